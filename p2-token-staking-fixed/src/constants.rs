@@ -1,10 +1,10 @@
 use near_sdk::{Balance, Gas};
 
 /// Amount of gas for fungible token transfers.
-pub const TGAS: Gas = 1_000_000_000_000;
-pub const GAS_FOR_FT_TRANSFER: Gas = 10 * TGAS;
-pub const GAS_FOR_MINT_CALLBACK: Gas = 20 * TGAS;
-pub const GAS_FOR_MINT_CALLBACK_FINALLY: Gas = 8 * TGAS;
+pub const TGAS: Gas = Gas::ONE_TERA;
+pub const GAS_FOR_FT_TRANSFER: Gas = Gas(10 * TGAS.0);
+pub const GAS_FOR_MINT_CALLBACK: Gas = Gas(20 * TGAS.0);
+pub const GAS_FOR_MINT_CALLBACK_FINALLY: Gas = Gas(8 * TGAS.0);
 
 pub const ONE_YOCTO: Balance = 1;
 
@@ -12,6 +12,10 @@ pub const ONE_YOCTO: Balance = 1;
 pub const SECOND: u64 = 1_000_000_000;
 /// round duration in seconds
 pub const ROUND: u64 = 60; // 1 minute
+/// year duration in seconds
+pub const YEAR: u64 = 365 * 24 * 60 * 60;
+/// 100% in basis points
+pub const BASIS_P: u128 = 10_000;
 
 const MILLI_NEAR: Balance = 1000_000000_000000_000000; // 1e21
 pub const STORAGE_COST: Balance = MILLI_NEAR * 50; // 0.05 NEAR
@@ -6,6 +6,8 @@ pub const ERR03_STORAGE_DEP: &str = "E03: min storage deposit is 0.05 NEAR";
 // Token registration
 
 pub const ERR10_NO_ACCOUNT: &str = "E10: account not found. Register the account.";
+pub const ERR11_REGISTER_FIRST: &str =
+    "E11: account not registered. Call storage_deposit before staking";
 
 // Token Deposit errors //
 
@@ -40,6 +40,8 @@ pub struct ContractParams {
     pub farming_token: AccountId,
     pub staked_token: AccountId,
     pub farming_rate: U128,
+    /// round duration in seconds: `farming_rate` is emitted every round.
+    pub round_seconds: u64,
     pub is_active: bool,
     pub farming_start: u64,
     pub farming_end: u64,
@@ -50,4 +52,6 @@ pub struct ContractParams {
     pub fee_rate: U128,
     /// Number of accounts currently registered.
     pub accounts_registered: u64,
+    /// min amount an account can have staked
+    pub min_stake_amount: U128,
 }
@@ -1,8 +1,8 @@
 use std::convert::TryInto;
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupMap;
-use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::collections::{LookupMap, LookupSet};
+use near_sdk::json_types::U128;
 use near_sdk::{
     assert_one_yocto, env, log, near_bindgen, AccountId, PanicOnDefault, Promise, PromiseResult,
 };
@@ -10,14 +10,13 @@ use near_sdk::{
 pub mod constants;
 pub mod errors;
 pub mod interfaces;
+pub mod migration;
 // pub mod util;
 pub mod vault;
 
 use crate::interfaces::*;
 use crate::{constants::*, errors::*, vault::*};
 
-near_sdk::setup_alloc!();
-
 /// P2 rewards distribution contract implementing the "Scalable Reward Distribution on the Ethereum Blockchain"
 /// algorithm:
 /// https://uploads-ssl.webflow.com/5ad71ffeb79acc67c8bcdaba/5ad8d1193a40977462982470_scalable-reward-distribution-paper.pdf
@@ -59,6 +58,12 @@ pub struct Contract {
     pub fee_collected: u128,
     /// Treasury address - a destination for the collected fees.
     pub treasury: AccountId,
+    /// min amount an account can have staked. Deposits which would leave a smaller stake
+    /// are refunded. 0 disables the check.
+    pub min_stake_amount: u128,
+    /// accounts which storage deposit was already returned: their vault was recreated by a
+    /// state recovery after the account was closed, so closing it again must not refund it.
+    pub storage_refunded: LookupSet<AccountId>,
 }
 
 #[near_bindgen]
@@ -70,20 +75,20 @@ impl Contract {
     /// * `fee_rate`: the Contract.fee parameter (in basis points)
     #[init]
     pub fn new(
-        owner_id: ValidAccountId,
-        cheddar: ValidAccountId,
-        staked_token: ValidAccountId,
+        owner_id: AccountId,
+        cheddar: AccountId,
+        staked_token: AccountId,
         farming_start: u64,
         farming_end: u64,
         reward_rate: U128,
         fee_rate: u32,
-        treasury: ValidAccountId,
+        treasury: AccountId,
     ) -> Self {
         assert!(farming_end > farming_start, "End must be after start");
         Self {
-            owner_id: owner_id.into(),
-            cheddar: cheddar.into(),
-            staking_token: staked_token.into(),
+            owner_id,
+            cheddar,
+            staking_token: staked_token,
             is_active: true,
             vaults: LookupMap::new(b"v".to_vec()),
             rate: reward_rate.0,
@@ -96,7 +101,9 @@ impl Contract {
             accounts_registered: 0,
             fee_rate: fee_rate.into(),
             fee_collected: 0,
-            treasury: treasury.into(),
+            treasury,
+            min_stake_amount: 0,
+            storage_refunded: LookupSet::new(b"s".to_vec()),
         }
     }
 
@@ -111,6 +118,7 @@ impl Contract {
             farming_token: self.cheddar.clone(),
             staked_token: self.staking_token.clone(),
             farming_rate: self.rate.into(),
+            round_seconds: ROUND,
             is_active: self.is_active,
             farming_start: self.farming_start,
             farming_end: self.farming_end,
@@ -118,6 +126,7 @@ impl Contract {
             total_farmed: (u128::from(r) * self.rate).into(),
             fee_rate: self.fee_rate.into(),
             accounts_registered: self.accounts_registered,
+            min_stake_amount: self.min_stake_amount.into(),
         }
     }
 
@@ -138,6 +147,30 @@ impl Contract {
         };
     }
 
+    /// Returns true if the account is registered (has a vault).
+    pub fn is_registered(&self, account_id: AccountId) -> bool {
+        self.vaults.contains_key(&account_id)
+    }
+
+    /// Returns the annualized reward rate in basis points: yearly amount of farmed CHEDDAR
+    /// per staked token, assuming the current `rate` and `total_stake`.
+    /// NOTE: this is a reward-token-denominated APR (CHEDDAR per staked token unit),
+    /// not a USD APR - the contract doesn't know the token prices.
+    /// Returns 0 when nothing is staked.
+    pub fn get_apr(&self) -> U128 {
+        if self.total_stake == 0 {
+            return 0.into();
+        }
+        let rounds_per_year = u128::from(YEAR / ROUND);
+        (self.rate * rounds_per_year * BASIS_P / self.total_stake).into()
+    }
+
+    /// Returns the farming `rate` spread over a round: amount of yocto CHEDDAR emitted
+    /// per second. Useful to display live-accruing balances.
+    pub fn get_emission_per_second(&self) -> U128 {
+        (self.rate / u128::from(ROUND)).into()
+    }
+
     // ******************* //
     // transaction methods //
 
@@ -187,7 +220,7 @@ impl Contract {
         // and remove the account and return storage deposit.
         if v.staked == 0 && v.farmed == 0 {
             self.vaults.remove(&a);
-            Promise::new(a.clone()).transfer(STORAGE_COST);
+            self.refund_storage(&a);
             return;
         }
 
@@ -230,14 +263,10 @@ impl Contract {
         log!("Withdrawing collected fee: {} tokens", self.fee_collected);
         let fee = U128::from(self.fee_collected);
         self.fee_collected = 0;
-        return ext_ft::ft_transfer(
-            self.treasury.clone(),
-            fee,
-            Some("fee withdraw".to_string()),
-            &self.staking_token,
-            1,
-            GAS_FOR_FT_TRANSFER,
-        );
+        return ext_ft::ext(self.staking_token.clone())
+            .with_attached_deposit(ONE_YOCTO)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(self.treasury.clone(), fee, Some("fee withdraw".to_string()));
     }
 
     // ******************* //
@@ -250,6 +279,13 @@ impl Contract {
         self.is_active = is_open;
     }
 
+    /// Sets the min amount an account can have staked. Already staked vaults are not
+    /// affected: the check only applies to new deposits.
+    pub fn set_min_stake_amount(&mut self, amount: U128) {
+        self.assert_owner();
+        self.min_stake_amount = amount.0;
+    }
+
     /// start and end are unix timestamps (in seconds)
     pub fn set_end(&mut self, end: u64) {
         self.assert_owner();
@@ -287,21 +323,19 @@ impl Contract {
     fn return_tokens(&mut self, user: AccountId, amount: U128) -> Promise {
         let fee = amount.0 * self.fee_rate / 10_000;
         self.fee_collected += fee;
-        return ext_ft::ft_transfer(
-            user.clone(),
-            (amount.0 - fee).into(),
-            Some("unstaking".to_string()),
-            &self.staking_token,
-            1,
-            GAS_FOR_FT_TRANSFER,
-        )
-        .then(ext_self::return_tokens_callback(
-            user,
-            amount,
-            &env::current_account_id(),
-            0,
-            GAS_FOR_MINT_CALLBACK,
-        ));
+        return ext_ft::ext(self.staking_token.clone())
+            .with_attached_deposit(ONE_YOCTO)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(
+                user.clone(),
+                (amount.0 - fee).into(),
+                Some("unstaking".to_string()),
+            )
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_MINT_CALLBACK)
+                    .return_tokens_callback(user, amount),
+            );
     }
 
     #[private]
@@ -340,21 +374,15 @@ impl Contract {
         let mut p: Option<Promise> = None;
         if cheddar_amount.0 != 0 {
             p = Some(
-                ext_ft::ft_mint(
-                    a.clone(),
-                    cheddar_amount,
-                    Some("farming".to_string()),
-                    &self.cheddar,
-                    ONE_YOCTO,
-                    GAS_FOR_FT_TRANSFER,
-                )
-                .then(ext_self::mint_callback(
-                    a.clone(),
-                    cheddar_amount,
-                    &env::current_account_id(),
-                    0,
-                    GAS_FOR_MINT_CALLBACK,
-                )),
+                ext_ft::ext(self.cheddar.clone())
+                    .with_attached_deposit(ONE_YOCTO)
+                    .with_static_gas(GAS_FOR_FT_TRANSFER)
+                    .ft_mint(a.clone(), cheddar_amount, Some("farming".to_string()))
+                    .then(
+                        ext_self::ext(env::current_account_id())
+                            .with_static_gas(GAS_FOR_MINT_CALLBACK)
+                            .mint_callback(a.clone(), cheddar_amount),
+                    ),
             );
         }
         if tokens.0 != 0 {
@@ -366,12 +394,11 @@ impl Contract {
             }
         }
         if close {
-            p.unwrap().then(ext_self::close_account(
-                a.clone(),
-                &env::current_account_id(),
-                0,
-                GAS_FOR_MINT_CALLBACK,
-            ));
+            p.unwrap().then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_MINT_CALLBACK)
+                    .close_account(a.clone()),
+            );
         }
     }
 
@@ -409,10 +436,9 @@ impl Contract {
         }
         if let Some(v) = self.vaults.get(&user) {
             if v.staked == 0 && v.farmed == 0 {
-                log!("returning storage deposit");
                 self.vaults.remove(&user);
                 self.accounts_registered -= 1;
-                Promise::new(user).transfer(STORAGE_COST);
+                self.refund_storage(&user);
             }
         }
     }
@@ -424,8 +450,10 @@ impl Contract {
             v.staked += staked;
             v.farmed += cheddar;
         } else {
-            // If the vault was closed before by another TX, then we must recover the state
+            // If the vault was closed before by another TX, then we must recover the state.
+            // The storage deposit was already returned when closing.
             self.accounts_registered += 1;
+            self.storage_refunded.insert(user);
             v = Vault {
                 reward_acc: self.reward_acc,
                 staked,
@@ -436,6 +464,16 @@ impl Contract {
         self.vaults.insert(user, &v);
     }
 
+    /// Returns the storage deposit of a removed vault, unless it was already returned.
+    fn refund_storage(&mut self, user: &AccountId) {
+        if self.storage_refunded.remove(user) {
+            log!("storage deposit already returned");
+            return;
+        }
+        log!("returning storage deposit");
+        Promise::new(user.clone()).transfer(STORAGE_COST);
+    }
+
     /// Returns the round number since `start`.
     /// If now < start  return 0.
     /// If now == start return 0.
@@ -486,29 +524,28 @@ mod tests {
     use near_contract_standards::storage_management::StorageManagement;
     use near_sdk::test_utils::{accounts, VMContextBuilder};
     use near_sdk::{testing_env, Balance};
-    use near_sdk::{MockedBlockchain, ValidatorId};
     use std::convert::TryInto;
 
     use super::*;
 
-    fn acc_cheddar() -> ValidAccountId {
-        "cheddar".to_string().try_into().unwrap()
+    fn acc_cheddar() -> AccountId {
+        "cheddar".parse().unwrap()
     }
 
-    fn acc_staking() -> ValidAccountId {
-        "atom".try_into().unwrap()
+    fn acc_staking() -> AccountId {
+        "atom".parse().unwrap()
     }
 
-    fn acc_user1() -> ValidAccountId {
-        "user1".try_into().unwrap()
+    fn acc_user1() -> AccountId {
+        "user1".parse().unwrap()
     }
 
-    fn acc_user2() -> ValidAccountId {
-        "user2".try_into().unwrap()
+    fn acc_user2() -> AccountId {
+        "user2".parse().unwrap()
     }
 
-    fn acc_user3() -> ValidAccountId {
-        "user3".try_into().unwrap()
+    fn acc_user3() -> AccountId {
+        "user3".parse().unwrap()
     }
 
     /// block round length in nanoseconds.
@@ -519,7 +556,7 @@ mod tests {
 
     /// deposit_dec = size of deposit in e24 to set for the next transacton
     fn setup_contract(
-        predecessor: ValidAccountId,
+        predecessor: AccountId,
         deposit_dec: u128,
         round: u64,
         fee_rate: u32,
@@ -548,7 +585,7 @@ mod tests {
     fn stake(
         ctx: &mut VMContextBuilder,
         ctr: &mut Contract,
-        a: &ValidAccountId,
+        a: &AccountId,
         amount: u128,
         epoch: u64,
     ) {
@@ -575,6 +612,26 @@ mod tests {
         ctr.set_active(false);
     }
 
+    #[test]
+    fn test_get_apr() {
+        let (_, mut ctr) = setup_contract(acc_user1(), 0, 1, 0);
+        assert_eq!(ctr.get_apr().0, 0, "nothing staked");
+
+        // 525_600 rounds in a year
+        let yearly_reward = RATE * 525_600;
+        ctr.total_stake = yearly_reward * 2;
+        assert_eq!(ctr.get_apr().0, 5_000);
+        ctr.total_stake = yearly_reward / 4;
+        assert_eq!(ctr.get_apr().0, 40_000);
+    }
+
+    #[test]
+    fn test_get_emission_per_second() {
+        let (_, ctr) = setup_contract(acc_user1(), 0, 1, 0);
+        assert_eq!(ctr.get_emission_per_second().0, RATE / 60);
+        assert_eq!(ctr.get_contract_params().round_seconds, ROUND);
+    }
+
     #[test]
     #[should_panic(
         expected = "The attached deposit is less than the minimum storage balance (50000000000000000000000)"
@@ -609,10 +666,124 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_is_registered() {
+        let user = acc_user1();
+        let user_id: AccountId = user.clone();
+        let (mut ctx, mut ctr) = setup_contract(user, 0, 1, 0);
+        assert!(!ctr.is_registered(user_id.clone()));
+
+        testing_env!(ctx.attached_deposit(STORAGE_COST).build());
+        ctr.storage_deposit(None, None);
+        assert!(ctr.is_registered(user_id.clone()));
+
+        testing_env!(ctx.attached_deposit(1).build());
+        ctr.close();
+        assert!(!ctr.is_registered(user_id));
+    }
+
+    #[test]
+    fn test_close_refunds_storage_once() {
+        let user = acc_user1();
+        let user_id: AccountId = user.clone();
+        let (mut ctx, mut ctr) = setup_contract(user.clone(), 0, 1, 0);
+        testing_env!(ctx.attached_deposit(STORAGE_COST).build());
+        ctr.storage_deposit(None, None);
+        stake(&mut ctx, &mut ctr, &user, E24, 1);
+
+        // close: the vault is emptied and removed in the `close_account` callback
+        testing_env!(ctx
+            .predecessor_account_id(user.clone())
+            .attached_deposit(1)
+            .block_timestamp(12 * B_ROUND)
+            .build());
+        ctr.close();
+        // the user closes the empty vault in another transaction: storage is returned
+        let balance = env::account_balance();
+        ctr.close();
+        assert_eq!(balance - env::account_balance(), STORAGE_COST);
+        assert!(!ctr.is_registered(user_id.clone()));
+
+        // the mint fails: the vault is recovered without a storage deposit
+        testing_env!(
+            ctx.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        ctr.mint_callback(user_id.clone(), RATE.into());
+        ctr.close_account(user_id.clone());
+        assert!(ctr.storage_refunded.contains(&user_id));
+
+        // closing the recovered vault doesn't return the storage deposit again
+        testing_env!(ctx
+            .predecessor_account_id(user.clone())
+            .attached_deposit(1)
+            .build());
+        ctr.close();
+        testing_env!(
+            ctx.predecessor_account_id(accounts(0)).build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        let balance = env::account_balance();
+        ctr.close_account(user_id.clone());
+        assert_eq!(balance, env::account_balance());
+        assert!(!ctr.is_registered(user_id.clone()));
+        assert!(!ctr.storage_refunded.contains(&user_id));
+    }
+
+    #[test]
+    #[should_panic(expected = "E11: account not registered. Call storage_deposit before staking")]
+    fn test_stake_not_registered() {
+        let (mut ctx, mut ctr) = setup_contract(acc_user1(), 0, 1, 0);
+        stake(&mut ctx, &mut ctr, &acc_user1(), E24, 1);
+    }
+
+    #[test]
+    fn test_min_stake_amount() {
+        let user = acc_user1();
+        let user_a: AccountId = user.clone();
+        let (mut ctx, mut ctr) = setup_contract(accounts(0), 0, 1, 0);
+        ctr.set_min_stake_amount(E24.into());
+        assert_eq!(ctr.get_contract_params().min_stake_amount.0, E24);
+        testing_env!(ctx
+            .predecessor_account_id(user.clone())
+            .attached_deposit(STORAGE_COST)
+            .build());
+        ctr.storage_deposit(None, None);
+
+        // below the minimum: refunded
+        testing_env!(ctx
+            .attached_deposit(0)
+            .predecessor_account_id(acc_staking())
+            .build());
+        match ctr.ft_on_transfer(user.clone(), (E24 / 2).into(), "".to_string()) {
+            near_sdk::PromiseOrValue::Value(refund) => assert_eq!(refund.0, E24 / 2),
+            _ => panic!("expected a refund"),
+        }
+        assert_eq!(ctr.total_stake, 0);
+        assert_eq!(ctr.get_vault(&user_a).staked, 0);
+
+        // at the minimum: staked
+        match ctr.ft_on_transfer(user.clone(), E24.into(), "".to_string()) {
+            near_sdk::PromiseOrValue::Value(refund) => assert_eq!(refund.0, 0),
+            _ => panic!("expected no refund"),
+        }
+        assert_eq!(ctr.total_stake, E24);
+
+        // the vault is above the minimum, so it can be topped up with any amount
+        stake(&mut ctx, &mut ctr, &user, E24 / 10, 1);
+        assert_eq!(ctr.get_vault(&user_a).staked, E24 + E24 / 10);
+    }
+
     #[test]
     fn test_staking() {
         let user = acc_user1();
-        let user_a: AccountId = user.clone().into();
+        let user_a: AccountId = user.clone();
         let (mut ctx, mut ctr) = setup_contract(user.clone(), 0, 1, 0);
         assert_eq!(
             ctr.total_stake, 0,
@@ -701,7 +872,7 @@ mod tests {
         // ------------------------------------------------
         // User who didn't stake should have zero rewards
         let user2 = acc_user2();
-        let user2_a: AccountId = user2.clone().into();
+        let user2_a: AccountId = user2.clone();
         let (a2_s, a2_r, _) = ctr.status(user2_a.clone());
         assert_eq!(a2_s.0, 0, "account2 stake should be zero");
         assert_eq!(a2_r.0, 0, "account2 rewards should be zero");
@@ -782,7 +953,7 @@ mod tests {
     #[test]
     fn test_staking_late() {
         let user = acc_user1();
-        let user_a: AccountId = user.clone().into();
+        let user_a: AccountId = user.clone();
         let (mut ctx, mut ctr) = setup_contract(user.clone(), 0, 1, 0);
         assert_eq!(
             ctr.total_stake, 0,
@@ -823,7 +994,7 @@ mod tests {
     #[test]
     fn test_staking_late_join() {
         let user = acc_user1();
-        let user_a: AccountId = user.clone().into();
+        let user_a: AccountId = user.clone();
         let (mut ctx, mut ctr) =
             setup_contract(user.clone(), STORAGE_COST.try_into().unwrap(), 14, 0);
 
@@ -858,11 +1029,11 @@ mod tests {
     #[test]
     fn test_staking_few_users() {
         let user = acc_user1();
-        let user_a: AccountId = user.clone().into();
+        let user_a: AccountId = user.clone();
         let user2 = acc_user2();
-        let user2_a: AccountId = user2.clone().into();
+        let user2_a: AccountId = user2.clone();
         let user3 = acc_user3();
-        let user3_a: AccountId = user3.clone().into();
+        let user3_a: AccountId = user3.clone();
 
         let (mut ctx, mut ctr) =
             setup_contract(user.clone(), STORAGE_COST.try_into().unwrap(), 9, 0);
@@ -952,11 +1123,93 @@ mod tests {
     }
 
     fn get_acc(idx: usize) -> AccountId {
-        accounts(idx).as_ref().to_string()
+        accounts(idx)
     }
 
     fn assert_close(a: u128, b: u128, msg: &'static str) {
         assert!(a * 99999 / 100_000 < b, "{}, {} <> {}", msg, a, b);
         assert!(a * 100001 / 100_000 > b, "{}, {} <> {}", msg, a, b);
     }
+
+    /// Copy of the `Vault` layout deployed before the migration.
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct BaselineVault {
+        reward_acc: Balance,
+        staked: Balance,
+        farmed: Balance,
+    }
+
+    /// Copy of the `Contract` layout deployed before the migration. Kept apart from
+    /// `migration::LegacyContract`, so a drift of the legacy layout fails the test.
+    #[derive(BorshSerialize)]
+    struct BaselineContract {
+        owner_id: AccountId,
+        cheddar: AccountId,
+        staking_token: AccountId,
+        is_active: bool,
+        vaults: LookupMap<AccountId, BaselineVault>,
+        rate: u128,
+        farming_start: u64,
+        farming_end: u64,
+        total_harvested: u128,
+        reward_acc: u128,
+        reward_acc_round: u64,
+        total_stake: u128,
+        accounts_registered: u64,
+        fee_rate: u128,
+        fee_collected: u128,
+        treasury: AccountId,
+    }
+
+    #[test]
+    fn test_migrate() {
+        let (mut ctx, ctr) = setup_contract(acc_user1(), 0, 1, 0);
+        let mut vaults = LookupMap::new(b"v".to_vec());
+        vaults.insert(
+            &acc_user1(),
+            &BaselineVault {
+                reward_acc: 7,
+                staked: 5 * E24,
+                farmed: 2 * E24,
+            },
+        );
+        env::state_write(&BaselineContract {
+            owner_id: ctr.owner_id.clone(),
+            cheddar: ctr.cheddar.clone(),
+            staking_token: ctr.staking_token.clone(),
+            is_active: true,
+            vaults,
+            rate: RATE,
+            farming_start: ctr.farming_start,
+            farming_end: ctr.farming_end,
+            total_harvested: 3 * E24,
+            reward_acc: 7,
+            reward_acc_round: 2,
+            total_stake: 5 * E24,
+            accounts_registered: 2,
+            fee_rate: 10,
+            fee_collected: E24,
+            treasury: ctr.treasury.clone(),
+        });
+
+        testing_env!(ctx
+            .predecessor_account_id(env::current_account_id())
+            .build());
+        let ctr = Contract::migrate();
+        assert_eq!(ctr.min_stake_amount, 0);
+        assert_eq!(ctr.rate, RATE);
+        assert_eq!(ctr.total_harvested, 3 * E24);
+        assert_eq!(ctr.reward_acc, 7);
+        assert_eq!(ctr.reward_acc_round, 2);
+        assert_eq!(ctr.total_stake, 5 * E24);
+        assert_eq!(ctr.accounts_registered, 2);
+        assert_eq!(ctr.fee_rate, 10);
+        assert_eq!(ctr.fee_collected, E24);
+        assert_eq!(ctr.treasury, accounts(1));
+        // the stored vaults are read with the current layout
+        let v = ctr.get_vault(&acc_user1());
+        assert_eq!((v.reward_acc, v.staked, v.farmed), (7, 5 * E24, 2 * E24));
+        assert!(!ctr.storage_refunded.contains(&acc_user1()));
+    }
+
 }
@@ -0,0 +1,57 @@
+//! State migration from the contract deployed before `min_stake_amount` and
+//! `storage_refunded` were added. The vaults layout didn't change.
+//!
+//! Deploy procedure: deploy the new code and call `migrate` in the same batch transaction.
+use crate::*;
+
+/// `Contract` layout of the previous contract version.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct LegacyContract {
+    pub owner_id: AccountId,
+    pub cheddar: AccountId,
+    pub staking_token: AccountId,
+    pub is_active: bool,
+    pub vaults: LookupMap<AccountId, Vault>,
+    pub rate: u128,
+    pub farming_start: u64,
+    pub farming_end: u64,
+    pub total_harvested: u128,
+    pub reward_acc: u128,
+    pub reward_acc_round: u64,
+    pub total_stake: u128,
+    pub accounts_registered: u64,
+    pub fee_rate: u128,
+    pub fee_collected: u128,
+    pub treasury: AccountId,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Migrates the contract state from the previous version. The min stake amount check
+    /// is disabled: use `set_min_stake_amount` to enable it.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: LegacyContract = env::state_read().expect("no contract state to migrate");
+        Self {
+            owner_id: old.owner_id,
+            cheddar: old.cheddar,
+            staking_token: old.staking_token,
+            is_active: old.is_active,
+            vaults: old.vaults,
+            rate: old.rate,
+            farming_start: old.farming_start,
+            farming_end: old.farming_end,
+            total_harvested: old.total_harvested,
+            reward_acc: old.reward_acc,
+            reward_acc_round: old.reward_acc_round,
+            total_stake: old.total_stake,
+            accounts_registered: old.accounts_registered,
+            fee_rate: old.fee_rate,
+            fee_collected: old.fee_collected,
+            treasury: old.treasury,
+            min_stake_amount: 0,
+            storage_refunded: LookupSet::new(b"s".to_vec()),
+        }
+    }
+}
@@ -1,7 +1,7 @@
 //! Vault is information per user about their balances in the exchange.
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::json_types::U128;
 use near_sdk::{env, log, AccountId, Balance, PromiseOrValue};
 
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
@@ -66,6 +66,16 @@ impl Contract {
         self.vaults.get(account_id).expect(ERR10_NO_ACCOUNT)
     }
 
+    /// Panics with `ERR11_REGISTER_FIRST` if the account is not registered.
+    pub(crate) fn assert_registered(&self, account_id: &AccountId) {
+        assert!(
+            self.is_registered(account_id.clone()),
+            "{} ({})",
+            ERR11_REGISTER_FIRST,
+            account_id
+        );
+    }
+
     pub(crate) fn ping_all(&mut self, v: &mut Vault) -> u128 {
         let r = self.current_round();
         self.update_reward_acc(r);
@@ -110,7 +120,7 @@ impl FungibleTokenReceiver for Contract {
     #[allow(unused_variables)]
     fn ft_on_transfer(
         &mut self,
-        sender_id: ValidAccountId,
+        sender_id: AccountId,
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
@@ -122,8 +132,19 @@ impl FungibleTokenReceiver for Contract {
             self.staking_token
         );
         assert!(amount.0 > 0, "staked amount must be positive");
-        let sender_id: &AccountId = sender_id.as_ref();
+        let sender_id: &AccountId = &sender_id;
+        self.assert_registered(sender_id);
         let mut v = self.get_vault(sender_id);
+        // refund dust stakes, before any state change
+        if v.staked + amount.0 < self.min_stake_amount {
+            log!(
+                "Stake below the min stake amount {}, refunding {} {}",
+                self.min_stake_amount,
+                amount.0,
+                token
+            );
+            return PromiseOrValue::Value(amount);
+        }
 
         // firstly update the past rewards
         self.ping_all(&mut v);
@@ -144,14 +165,12 @@ impl StorageManagement for Contract {
     #[payable]
     fn storage_deposit(
         &mut self,
-        account_id: Option<ValidAccountId>,
+        account_id: Option<AccountId>,
         registration_only: Option<bool>,
     ) -> StorageBalance {
         let amount: Balance = env::attached_deposit();
-        let account_id = account_id
-            .map(|a| a.into())
-            .unwrap_or_else(|| env::predecessor_account_id());
-        if self.vaults.contains_key(&account_id) {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        if self.is_registered(account_id.clone()) {
             log!("The account is already registered, refunding the deposit");
             if amount > 0 {
                 Promise::new(env::predecessor_account_id()).transfer(amount);
@@ -201,9 +220,8 @@ impl StorageManagement for Contract {
 
     /// If the account is registered the total and available balance is always MIN_BALANCE.
     /// Otherwise None.
-    fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
-        let account_id: AccountId = account_id.into();
-        if self.vaults.contains_key(&account_id) {
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        if self.is_registered(account_id) {
             return Some(storage_balance());
         }
         None
@@ -0,0 +1,51 @@
+//! NEP-297 events, logged as `EVENT_JSON:{...}`.
+//! https://nomicon.io/Standards/EventsFormat
+
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::serde_json::json;
+use near_sdk::{log, AccountId};
+
+pub const EVENT_STANDARD: &str = "cheddar-farm";
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/// Fee charged from an unstake, emitted once the unstaked tokens are transferred.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeCollected<'a> {
+    pub token: &'a AccountId,
+    pub amount: U128,
+}
+
+/// Collected fee transferred to a fee recipient, emitted once the transfer succeeded.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeWithdrawn<'a> {
+    pub token: &'a AccountId,
+    pub amount: U128,
+    pub treasury: &'a AccountId,
+}
+
+impl FeeCollected<'_> {
+    pub fn emit(&self) {
+        emit_event("fee_collected", &[self]);
+    }
+}
+
+impl FeeWithdrawn<'_> {
+    pub fn emit(&self) {
+        emit_event("fee_withdrawn", &[self]);
+    }
+}
+
+fn emit_event<T: Serialize>(event: &str, data: &T) {
+    log!(
+        "EVENT_JSON:{}",
+        json!({
+            "standard": EVENT_STANDARD,
+            "version": EVENT_STANDARD_VERSION,
+            "event": event,
+            "data": data,
+        })
+    );
+}
@@ -1,6 +1,6 @@
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{ext_contract, AccountId};
+use near_sdk::{ext_contract, AccountId, PromiseOrValue};
 
 // #[ext_contract(ext_staking_pool)]
 pub trait StakingPool {
@@ -31,7 +31,9 @@ pub trait ExtSelf {
     );
     fn transfer_farmed_callback(&mut self, user: AccountId, token_i: usize, amount: U128);
     fn withdraw_nft_callback(&mut self, user: AccountId, cheddy: String);
-    fn withdraw_fees_callback(&mut self, token_i: usize, amount: U128);
+    fn withdraw_fees_callback(&mut self, token_i: usize, amount: U128, recipient: AccountId);
+    fn compound_callback(&mut self, user: AccountId, token_i: usize, amount: U128);
+    fn sweep_unclaimed_callback(&mut self, token_i: usize, amount: U128);
     fn mint_callback(&mut self, user: AccountId, amount: U128);
     fn mint_callback_finally(&mut self);
 }
@@ -40,6 +42,13 @@ pub trait ExtSelf {
 pub trait FungibleToken {
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
     fn ft_mint(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
 }
 
 #[ext_contract(ext_nft)]
@@ -53,12 +62,27 @@ pub trait NonFungibleToken {
     );
 }
 
+/// Farm operation status.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum FarmStatus {
+    /// all operations are open.
+    Active,
+    /// the farm is winding down: new stakes are rejected, while harvests, unstakes and
+    /// closing accounts work normally.
+    Draining,
+    /// all user operations are blocked.
+    Paused,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct ContractParams {
     pub is_active: bool,
+    pub status: FarmStatus,
     pub owner_id: AccountId,
     pub stake_tokens: Vec<AccountId>,
     pub stake_rates: Vec<U128>,
+    /// decimals of the stake tokens
+    pub stake_decimals: Vec<u8>,
     pub farm_unit_emission: U128,
     pub farm_tokens: Vec<AccountId>,
     pub farm_token_rates: Vec<U128>,
@@ -1,19 +1,42 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupMap;
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, TreeMap, Vector};
+use near_sdk::json_types::{I128, U128};
 use near_sdk::{
-    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, PanicOnDefault, Promise,
+    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, Promise,
     PromiseOrValue, PromiseResult,
 };
 
 pub mod errors;
+pub mod events;
 pub mod interfaces;
 // pub mod util;
 pub mod vault;
 
 use p3_lib::{constants::*, helpers::*};
 
-use crate::{errors::*, interfaces::*, vault::*};
+use crate::{errors::*, events::*, interfaces::*, vault::*};
+
+/// Min harvest deadline after the farming end (in rounds): 30 days.
+pub const MIN_HARVEST_DEADLINE_ROUNDS: u64 = 30 * 24 * 60 * 60 / ROUND;
+/// Max number of kept rewards accumulator checkpoints (at least one day of rounds).
+pub const MAX_REWARD_ACC_CHECKPOINTS: u64 = 24 * 60 * 60 / ROUND;
+
+/// `ft_transfer_call` msg prefix to stake on behalf of another account: "stake for <account_id>".
+pub const STAKE_FOR_MSG_PREFIX: &str = "stake for ";
+
+/// Gas for `ft_transfer_call` of compounded rewards, including the target farm
+/// `ft_on_transfer` and the token `ft_resolve_transfer`.
+pub const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas(60 * TGAS.0);
+
+/// Result of a farm setup deposit.
+#[derive(Debug, PartialEq)]
+pub enum SetupDepositResult {
+    /// the deposit was recorded.
+    Accepted,
+    /// the deposit for the token was already done: the new one is not recorded and must be
+    /// refunded. Makes retrying a partially failed setup safe.
+    AlreadyDone,
+}
 
 /// P2 rewards distribution contract implementing the "Scalable Reward Distribution on the Ethereum Blockchain"
 /// algorithm:
@@ -23,10 +46,15 @@ use crate::{errors::*, interfaces::*, vault::*};
 pub struct Contract {
     /// if farming is opened
     pub is_active: bool,
+    /// if the farm is draining: new stakes are rejected. Only relevant when `is_active`.
+    pub draining: bool,
     pub setup_finalized: bool,
     pub owner_id: AccountId,
-    /// Treasury address - a destination for the collected fees.
+    /// Treasury address - a destination for the swept unclaimed farm deposits.
     pub treasury: AccountId,
+    /// Destinations for the collected fees with their shares in basis points (summing to
+    /// `BASIS_P`). The rounding dust goes to the first recipient.
+    pub fee_recipients: Vec<(AccountId, u32)>,
     /// user vaults
     pub vaults: LookupMap<AccountId, Vault>,
     pub stake_tokens: Vec<AccountId>,
@@ -36,7 +64,13 @@ pub struct Contract {
     /// When farming the `min(staking_token[i]*stake_rate[i]/1e24)` is taken
     /// to allocate farm_units.
     /// Cheddar should be the first stake token.
+    /// A zero rate excludes the token from the min: it can be staked, but it doesn't affect
+    /// farming. At least one rate must be positive.
     pub stake_rates: Vec<u128>,
+    /// Decimals of the stake tokens. Staked amounts are normalized to 24 decimals before
+    /// applying `stake_rates`. Operators must set the correct token decimals
+    /// (see `ft_metadata`) before the setup is finalized.
+    pub stake_decimals: Vec<u8>,
     pub farm_tokens: Vec<AccountId>,
     /// Ratios between the farm unit and all farm tokens when computing reward.
     /// When farming, for each token index i in `farm_tokens` we allocate to
@@ -48,6 +82,11 @@ pub struct Contract {
     pub farm_unit_emission: u128,
     /// received deposits for farming reward
     pub farm_deposits: Vec<u128>,
+    /// missing amount of the received farm deposits (when less than expected was received)
+    pub farm_deposit_shortfalls: Vec<u128>,
+    /// max accepted shortfall of a farm deposit, in basis points. Used to support tokens
+    /// which charge a fee on transfer.
+    pub deposit_tolerance_bp: u32,
     /// unix timestamp (seconds) when the farming starts.
     pub farming_start: u64,
     /// unix timestamp (seconds) when the farming ends (first time with no farming).
@@ -63,6 +102,12 @@ pub struct Contract {
     reward_acc: u128,
     /// round number when the s was previously updated.
     reward_acc_round: u64,
+    /// `(reward_acc_round, reward_acc)` checkpoints, one for each round the accumulator was
+    /// updated in. Used to compute the accumulator of the past rounds.
+    /// Ring buffer of the last `MAX_REWARD_ACC_CHECKPOINTS` checkpoints.
+    reward_acc_checkpoints: Vector<(u64, u128)>,
+    /// total number of checkpoints recorded, including the overwritten ones.
+    reward_acc_checkpoints_count: u64,
     /// total amount of currently staked tokens.
     total_stake: Vec<Balance>,
     /// total number of accounts currently registered.
@@ -73,6 +118,27 @@ pub struct Contract {
     pub fee_rate: u128,
     /// amount of fee collected (in staking token).
     pub fee_collected: Vec<Balance>,
+    /// number of rounds after `farming_end` when farmed tokens can still be harvested.
+    /// 0 means no deadline.
+    pub harvest_deadline_round: u64,
+    /// true when the unclaimed farm deposits were swept to the treasury.
+    pub unclaimed_swept: bool,
+    /// sum of the vaults `farmed_recovered`: harvested farm tokens which failed to transfer
+    /// and are still owed to the users.
+    pub total_farmed_recovered: Vec<Balance>,
+    /// max farm units a single vault can farm per round. Vaults above the cap farm exactly
+    /// the cap, and the excess emission goes to the other stakers. The capped vaults are
+    /// recomputed whenever the stake or the emission changes.
+    pub max_units_per_round_per_user: Option<u128>,
+    /// `(stake units, account)` of the vaults capped by `max_units_per_round_per_user`.
+    pub capped_stakes: TreeMap<(Balance, AccountId), ()>,
+    /// `(stake units, account)` of the other vaults, kept only when the cap is set.
+    pub uncapped_stakes: TreeMap<(Balance, AccountId), ()>,
+    /// sum of the stake units of the capped vaults. They don't take part in `reward_acc`.
+    pub capped_units: Balance,
+    /// farms allowed to stake on behalf of other accounts with the "stake for" msg,
+    /// eg. when compounding their rewards (see `compound_to_farm`).
+    pub trusted_farms: Vec<AccountId>,
 }
 
 #[near_bindgen]
@@ -109,17 +175,22 @@ impl Contract {
         let farm_len = farm_tokens.len();
         let c = Self {
             is_active: true,
+            draining: false,
             setup_finalized: false,
             owner_id: owner_id.into(),
+            fee_recipients: vec![(treasury.clone(), BASIS_P as u32)],
             treasury: treasury.into(),
             vaults: LookupMap::new(b"v".to_vec()),
             stake_tokens,
             staked_units: 0,
             stake_rates: stake_rates.iter().map(|x| x.0).collect(),
+            stake_decimals: vec![24; stake_len],
             farm_tokens,
             farm_token_rates: farm_token_rates.iter().map(|x| x.0).collect(),
             farm_unit_emission: farm_unit_emission.0,
             farm_deposits: vec![0; farm_len],
+            farm_deposit_shortfalls: vec![0; farm_len],
+            deposit_tolerance_bp: 0,
             farming_start,
             farming_end,
             cheddar_nft: cheddar_nft.into(),
@@ -127,10 +198,20 @@ impl Contract {
             total_harvested: vec![0; farm_len],
             reward_acc: 0,
             reward_acc_round: 0,
+            reward_acc_checkpoints: Vector::new(b"c".to_vec()),
+            reward_acc_checkpoints_count: 0,
             total_stake: vec![0; stake_len],
             accounts_registered: 0,
             fee_rate: fee_rate.into(),
             fee_collected: vec![0; stake_len],
+            harvest_deadline_round: 0,
+            unclaimed_swept: false,
+            total_farmed_recovered: vec![0; farm_len],
+            max_units_per_round_per_user: None,
+            capped_stakes: TreeMap::new(b"p".to_vec()),
+            uncapped_stakes: TreeMap::new(b"u".to_vec()),
+            capped_units: 0,
+            trusted_farms: Vec::new(),
         };
         c.check_vectors();
         c
@@ -142,15 +223,22 @@ impl Contract {
         assert!(
             fl == self.farm_token_rates.len()
                 && fl == self.total_harvested.len()
-                && fl == self.farm_deposits.len(),
+                && fl == self.farm_deposits.len()
+                && fl == self.farm_deposit_shortfalls.len()
+                && fl == self.total_farmed_recovered.len(),
             "farm token vector length is not correct"
         );
         assert!(
             sl == self.stake_rates.len()
+                && sl == self.stake_decimals.len()
                 && sl == self.total_stake.len()
                 && sl == self.fee_collected.len(),
             "stake token vector length is not correct"
         );
+        assert!(
+            self.stake_rates.iter().any(|r| *r > 0),
+            "at least one stake rate must be positive"
+        );
     }
 
     // ************ //
@@ -162,11 +250,13 @@ impl Contract {
             owner_id: self.owner_id.clone(),
             stake_tokens: self.stake_tokens.clone(),
             stake_rates: to_U128s(&self.stake_rates),
+            stake_decimals: self.stake_decimals.clone(),
             farm_unit_emission: self.farm_unit_emission.into(),
             farm_tokens: self.farm_tokens.clone(),
             farm_token_rates: to_U128s(&self.farm_token_rates),
             farm_deposits: to_U128s(&self.farm_deposits),
             is_active: self.is_active,
+            status: self.farm_status(),
             farming_start: self.farming_start,
             farming_end: self.farming_end,
             cheddar_nft: self.cheddar_nft.clone(),
@@ -177,11 +267,34 @@ impl Contract {
         }
     }
 
+    /// Returns a hex encoded sha256 hash of the config fields the owner can change: stake and
+    /// farm token rates, emission, farming start and end, fee rate and farm status.
+    /// Frontends compare it with the hash of their cached config to detect changes.
+    pub fn get_farm_config_hash(&self) -> String {
+        let config = (
+            &self.stake_rates,
+            &self.farm_token_rates,
+            self.farm_unit_emission,
+            self.farming_start,
+            self.farming_end,
+            self.fee_rate,
+            self.is_active,
+            self.draining,
+        );
+        env::sha256(&config.try_to_vec().unwrap())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
     pub fn status(&self, account_id: AccountId) -> Option<Status> {
         return match self.vaults.get(&account_id) {
             Some(mut v) => {
                 let r = self.current_round();
-                v.ping(self.compute_reward_acc(r), r);
+                match self.capped_acc(&account_id, &v, r) {
+                    Some(acc) => v.ping_capped(acc, r),
+                    None => v.ping(self.compute_reward_acc(r), r),
+                }
                 // round starts from 1 when now >= farming_start
                 let r0 = if r > 1 { r - 1 } else { 0 };
                 let farmed = self
@@ -202,6 +315,81 @@ impl Contract {
         };
     }
 
+    /// Returns the account farmed (not withdrawn) amounts paired with the farm tokens.
+    /// Returns an empty list if the account is not registered.
+    pub fn get_account_farm_breakdown(&self, account_id: AccountId) -> Vec<(AccountId, U128)> {
+        match self.status(account_id) {
+            Some(status) => self
+                .farm_tokens
+                .iter()
+                .cloned()
+                .zip(status.farmed_tokens)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the farm units accrued by the account between `from_round` and `to_round`,
+    /// assuming its stake doesn't change in the interval. Past rounds use the recorded rewards
+    /// accumulator, future rounds assume the current total stake. Useful to stream rewards
+    /// off-chain. Rounds are clamped to the farm rounds.
+    /// Returns 0 for an empty range or if the account is not registered.
+    /// Panics if `from_round` is older than the kept accumulator checkpoints (see
+    /// `MAX_REWARD_ACC_CHECKPOINTS`).
+    pub fn get_farmed_between(
+        &self,
+        account_id: AccountId,
+        from_round: u64,
+        to_round: u64,
+    ) -> U128 {
+        let v = match self.vaults.get(&account_id) {
+            Some(v) => v,
+            None => return 0.into(),
+        };
+        let total = round_number(self.farming_start, self.farming_end, self.farming_end);
+        let (from, to) = (from_round.min(total), to_round.min(total));
+        if from >= to {
+            return 0.into();
+        }
+        if let Some(cap) = self.max_units_per_round_per_user {
+            if self.capped_acc(&account_id, &v, to).is_some() {
+                return (cap * u128::from(to - from)).into();
+            }
+        }
+        let acc_delta = self.reward_acc_at(to) - self.reward_acc_at(from);
+        (v.min_stake * acc_delta / ACC_OVERFLOW).into()
+    }
+
+    /// Returns `(current_round, total_rounds, rounds_remaining)`.
+    /// Before the farm starts `current_round` is 0 and all rounds remain; after the farm
+    /// ends `current_round == total_rounds` and no rounds remain.
+    pub fn get_farm_progress(&self) -> (u64, u64, u64) {
+        let current = self.current_round();
+        let total = round_number(self.farming_start, self.farming_end, self.farming_end);
+        (current, total, total.saturating_sub(current))
+    }
+
+    /// Returns `(amount_returned, fee_charged)` for unstaking `amount` of `token` by
+    /// `account_id`, without changing the contract state.
+    /// Returns zeros if the account doesn't have `amount` of `token` staked.
+    pub fn simulate_unstake(
+        &self,
+        account_id: AccountId,
+        token: AccountId,
+        amount: U128,
+    ) -> (U128, U128) {
+        let token_i = find_acc_idx(&token, &self.stake_tokens);
+        let staked = match self.vaults.get(&account_id) {
+            Some(v) => v.staked[token_i],
+            None => 0,
+        };
+        if amount.0 > staked {
+            return (0.into(), 0.into());
+        }
+        let fee = self.unstake_fee(amount.0);
+        ((amount.0 - fee).into(), fee.into())
+    }
+
     // ******************* //
     // transaction methods //
 
@@ -231,10 +419,12 @@ impl Contract {
             return PromiseOrValue::Value(true);
         }
         log!("Staking Cheddy NFT - you will obtain a special farming boost");
-        self.ping_all(&mut v);
+        self.ping_all(&previous_owner_id, &mut v);
 
         v.cheddy = token_id;
-        self._recompute_stake(&mut v);
+        self._recompute_stake(&previous_owner_id, &mut v);
+        // A panic (eg in the stake recompute) reverts all the changes and the NFT contract
+        // returns the token in `nft_resolve_transfer`.
         self.vaults.insert(&previous_owner_id, &v);
         return PromiseOrValue::Value(false);
     }
@@ -251,44 +441,81 @@ impl Contract {
         self.vaults.insert(&user, &v);
     }
 
-    pub(crate) fn _setup_deposit(&mut self, token: &AccountId, amount: u128) {
+    pub(crate) fn _setup_deposit(&mut self, token: &AccountId, amount: u128) -> SetupDepositResult {
         assert!(
             !self.setup_finalized,
             "setup deposits must be done when contract setup is not finalized"
         );
         let token_i = find_acc_idx(token, &self.farm_tokens);
-        let total_rounds = round_number(self.farming_start, self.farming_end, self.farming_end);
-        let expected = safe_mul(
-            u128::from(total_rounds) * self.farm_unit_emission,
-            self.farm_token_rates[token_i],
-        );
-        assert_eq!(
-            self.farm_deposits[token_i], 0,
-            "deposit already done for the given token"
-        );
-        assert_eq!(
-            amount, expected,
+        if self.farm_deposits[token_i] != 0 {
+            log!("deposit already done for {}, refunding {}", token, amount);
+            return SetupDepositResult::AlreadyDone;
+        }
+        let expected = self.expected_deposit(token_i);
+        let min_expected = expected - expected * u128::from(self.deposit_tolerance_bp) / BASIS_P;
+        assert!(
+            amount >= min_expected && amount <= expected,
             "Expected deposit for token {} is {}, got {}",
-            self.farm_tokens[token_i], expected, amount
+            self.farm_tokens[token_i],
+            expected,
+            amount
         );
         self.farm_deposits[token_i] = amount;
+        if amount < expected {
+            // reduce the rewards proportionally, so we never pay out more than we received
+            let shortfall = expected - amount;
+            self.farm_deposit_shortfalls[token_i] = shortfall;
+            self.farm_token_rates[token_i] = (U256::from(self.farm_token_rates[token_i])
+                * U256::from(amount)
+                / U256::from(expected))
+            .as_u128();
+            log!(
+                "Deposit for token {} is {} short, reducing the farm token rate to {}",
+                self.farm_tokens[token_i],
+                shortfall,
+                self.farm_token_rates[token_i]
+            );
+        }
+        SetupDepositResult::Accepted
+    }
+
+    /// Returns true if the farm deposit for `token` was already done.
+    /// Panics if `token` is not a farm token.
+    pub fn is_deposit_done(&self, token: AccountId) -> bool {
+        self.farm_deposits[find_acc_idx(&token, &self.farm_tokens)] != 0
     }
 
     /// Deposit native near during the setup phase for farming rewards.
-    /// Panics when the deposit was already done or the setup is completed.
+    /// The deposit is refunded if it was already done.
+    /// Panics when the setup is completed.
     #[payable]
     pub fn setup_deposit_near(&mut self) {
-        self._setup_deposit(&near(), env::attached_deposit())
+        let amount = env::attached_deposit();
+        if self._setup_deposit(&near(), amount) == SetupDepositResult::AlreadyDone {
+            Promise::new(env::predecessor_account_id()).transfer(amount);
+        }
     }
 
     /// stakes native near.
     /// The transaction fails if near is not included in `self.stake_amount`.
+    /// If the caller is not registered, `STORAGE_COST` of the attached deposit is used to
+    /// register the account, and the rest is staked.
     /// This function is considered safe and will work when contract is paused.
     #[payable]
     pub fn stake_near(&mut self) {
-        self.assert_is_active();
+        self.assert_accepts_stakes();
         let a = env::predecessor_account_id();
-        self._stake(&a, &near(), env::attached_deposit());
+        let mut amount = env::attached_deposit();
+        if !self.vaults.contains_key(&a) {
+            assert!(
+                amount > STORAGE_COST,
+                "account is not registered: register it first with storage_deposit, or attach more than {} yNEAR to register and stake",
+                STORAGE_COST
+            );
+            self.create_account(&a);
+            amount -= STORAGE_COST;
+        }
+        self._stake(&a, &near(), amount);
     }
 
     // NEP-141 token staking is done via ft_transfer_call
@@ -317,10 +544,13 @@ impl Contract {
         assert_one_yocto();
         let a = env::predecessor_account_id();
         let mut v = self.get_vault(&a);
-        self.ping_all(&mut v);
+        self.ping_all(&a, &mut v);
         log!("Closing {} account, farmed: {:?}", &a, v.farmed);
         self.accounts_registered -= 1;
         self.vaults.remove(&a);
+        if self.max_units_per_round_per_user.is_some() {
+            self.remove_from_cap(&a, &mut v);
+        }
 
         // if user doesn't stake anything and has no rewards then we can make a shortcut
         // and remove the account and return storage deposit.
@@ -329,14 +559,23 @@ impl Contract {
             return;
         }
 
-        let s = min_stake(&v.staked, &self.stake_rates);
-        self.staked_units -= s;
+        // the vault stake units, including the Cheddy boost
+        self.staked_units -= v.min_stake;
+        self.apply_cap();
         for i in 0..self.total_stake.len() {
             self.transfer_staked_tokens(a.clone(), i, v.staked[i]);
         }
-        self._withdraw_crop(&a, v.farmed);
+        if self.harvest_closed() {
+            log!(
+                "Harvest deadline passed, {} farmed units are forfeited",
+                v.farmed
+            );
+        } else {
+            self._withdraw_crop(&a, v.farmed);
+        }
         if !v.cheddy.is_empty() {
-            self._withdraw_nft(&a, &mut v, a.clone());
+            // the vault is removed, its stake units were already subtracted
+            self.transfer_cheddy(&a, a.clone(), v.cheddy);
         }
 
         // NOTE: we don't return deposit because it will dramatically complicate logic
@@ -347,9 +586,35 @@ impl Contract {
     /// Panics if user has not staked anything.
     pub fn withdraw_crop(&mut self) {
         self.assert_is_active();
+        self.assert_harvest_open();
+        let a = env::predecessor_account_id();
+        let mut v = self.get_vault(&a);
+        self.ping_all(&a, &mut v);
+        let farmed_units = v.farmed;
+        v.farmed = 0;
+        self.vaults.insert(&a, &v);
+        self._withdraw_crop(&a, farmed_units);
+    }
+
+    /// Withdraws rewards farmed only up to `until_round` (capped at the current round).
+    /// Rewards of the later rounds stay in the vault and are picked up by the next ping.
+    /// Rewards already settled in the vault (eg when the stake changed after `until_round`)
+    /// are withdrawn as well.
+    /// Panics if `until_round` is older than the kept accumulator checkpoints (see
+    /// `MAX_REWARD_ACC_CHECKPOINTS`).
+    pub fn withdraw_crop_until(&mut self, until_round: u64) {
+        self.assert_is_active();
+        self.assert_harvest_open();
         let a = env::predecessor_account_id();
         let mut v = self.get_vault(&a);
-        self.ping_all(&mut v);
+        let r = std::cmp::min(until_round, self.current_round());
+        // the vault stake didn't change since the last ping, so the accumulator at round `r`
+        // gives the rewards farmed up to `r`. A vault capped (or uncapped) after `r` was
+        // pinged at that moment, so the ping is a no-op.
+        match self.capped_acc(&a, &v, r) {
+            Some(acc) => v.ping_capped(acc, r),
+            None => v.ping(self.reward_acc_at(r), r),
+        }
         let farmed_units = v.farmed;
         v.farmed = 0;
         self.vaults.insert(&a, &v);
@@ -372,11 +637,60 @@ impl Contract {
         }
     }
 
+    /// Harvests the caller farmed tokens and stakes them in the `target_farm` (a farm which
+    /// accepts the farm tokens as stake tokens) on behalf of the caller, using
+    /// `ft_transfer_call` with the "stake for <account_id>" msg. The caller must be registered
+    /// in the `target_farm`, and the `target_farm` must trust this contract (see
+    /// `set_trusted_farms`). Rewards rejected by the `target_farm` are recovered to the vault
+    /// and can be withdrawn with `withdraw_farmed_recovered`.
+    /// NEAR rewards can't be sent with `ft_transfer_call`, so they are transferred to the caller.
+    pub fn compound_to_farm(&mut self, target_farm: AccountId) {
+        self.assert_is_active();
+        self.assert_harvest_open();
+        assert!(
+            target_farm != env::current_account_id(),
+            "can't compound to the same farm"
+        );
+        let a = env::predecessor_account_id();
+        let mut v = self.get_vault(&a);
+        self.ping_all(&a, &mut v);
+        let farmed_units = v.farmed;
+        v.farmed = 0;
+        self.vaults.insert(&a, &v);
+        if farmed_units == 0 {
+            return;
+        }
+        for i in 0..self.farm_tokens.len() {
+            let amount = safe_mul(farmed_units, self.farm_token_rates[i]);
+            let token = self.farm_tokens[i].clone();
+            if token.as_ref() == NEAR_TOKEN {
+                self.transfer_farmed_tokens(&a, i, amount);
+                continue;
+            }
+            self.total_harvested[i] += amount;
+            ext_ft::ext(token)
+                .with_attached_deposit(ONE_YOCTO)
+                .with_static_gas(GAS_FOR_FT_TRANSFER_CALL)
+                .ft_transfer_call(
+                    target_farm.clone(),
+                    amount.into(),
+                    Some("compounding".to_string()),
+                    format!("{}{}", STAKE_FOR_MSG_PREFIX, a),
+                )
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_CALLBACK)
+                        .compound_callback(a.clone(), i, amount.into()),
+                );
+        }
+    }
+
     /** Withdraws harvested {token} to the user, which faild to transfer in a past call,
      *  for example due to missing token registration (some tokens require registration
      *  prior to receiving transfers).
      *  This function doesn't call crop an it doesn't translate outstanding farmed units into
      *  harvested tokens.
+     *  Works also after the harvest deadline: the recovered tokens are not swept.
      */
     pub fn withdraw_farmed_recovered(&mut self, token: &AccountId) {
         self.assert_is_active();
@@ -386,6 +700,7 @@ impl Contract {
         let amount = v.farmed_recovered[token_i];
         assert!(amount > 0, "user {} balance is zero", token);
         v.farmed_recovered[token_i] = 0;
+        self.total_farmed_recovered[token_i] -= amount;
         self.transfer_farmed_tokens(&a, token_i, amount);
     }
 
@@ -394,31 +709,36 @@ impl Contract {
         to_U128s(&self.fee_collected)
     }
 
-    /// Withdraws all collected fee to the treasury.
-    /// Must make sure treasury is registered
+    /// Withdraws all collected fee to the fee recipients.
+    /// Must make sure all fee recipients are registered
     /// Panics if the collected fees == 0.
     pub fn withdraw_fees(&mut self) {
         log!("Withdrawing collected fee: {:?} tokens", self.fee_collected);
         for i in 0..self.stake_tokens.len() {
             if self.fee_collected[i] != 0 {
-                ext_ft::ext(self.stake_tokens[i].clone())
-                    .with_attached_deposit(ONE_YOCTO)
-                    .with_static_gas(GAS_FOR_FT_TRANSFER)
-                    .ft_transfer(
-                        self.treasury.clone(),
-                        self.fee_collected[i].into(),
-                        Some("fee withdraw".to_string()),
-                    )
-                    .then(
-                        ext_self::ext(env::current_account_id())
-                            .with_static_gas(GAS_FOR_MINT_CALLBACK)
-                            .withdraw_fees_callback(i, self.fee_collected[i].into()),
-                    );
-                self.fee_collected[i] = 0;
+                self.transfer_fee(i);
             }
         }
     }
 
+    /// Withdraws the collected fee of a single stake `token` to the fee recipients. Useful to retry
+    /// a fee withdrawal which failed for one token only.
+    /// Panics if `token` is not a stake token or its collected fee == 0.
+    pub fn withdraw_fee_token(&mut self, token: AccountId) {
+        let token_i = find_acc_idx(&token, &self.stake_tokens);
+        assert!(
+            self.fee_collected[token_i] != 0,
+            "no fee collected for {}",
+            token
+        );
+        log!(
+            "Withdrawing collected fee: {} {}",
+            self.fee_collected[token_i],
+            token
+        );
+        self.transfer_fee(token_i);
+    }
+
     // ******************* //
     // management          //
 
@@ -427,6 +747,138 @@ impl Contract {
     pub fn set_active(&mut self, is_open: bool) {
         self.assert_owner();
         self.is_active = is_open;
+        self.draining = false;
+    }
+
+    /// Sets the farm status. Use `Draining` to wind down the farm: new stakes are rejected,
+    /// but users can still harvest, unstake and close their accounts.
+    pub fn set_status(&mut self, status: FarmStatus) {
+        self.assert_owner();
+        self.is_active = status != FarmStatus::Paused;
+        self.draining = status == FarmStatus::Draining;
+    }
+
+    /// Sets the max farm units a single vault can farm per round (`None` removes the cap).
+    /// The capped vaults are tracked from the first stake, so the cap can only be changed
+    /// when nothing is staked.
+    pub fn set_max_units_per_round_per_user(&mut self, cap: Option<U128>) {
+        self.assert_owner();
+        if let Some(cap) = cap {
+            assert!(cap.0 > 0, "cap must be positive");
+        }
+        assert!(
+            self.staked_units == 0,
+            "the cap can only be changed when nothing is staked"
+        );
+        self.max_units_per_round_per_user = cap.map(|c| c.0);
+    }
+
+    /// Sets the farms allowed to stake on behalf of other accounts with the
+    /// "stake for <account_id>" msg.
+    pub fn set_trusted_farms(&mut self, farms: Vec<AccountId>) {
+        self.assert_owner();
+        log!("Setting trusted farms: {:?}", farms);
+        self.trusted_farms = farms;
+    }
+
+    /// Sets the max accepted shortfall of farm deposits, in basis points.
+    pub fn set_deposit_tolerance(&mut self, tolerance_bp: u32) {
+        self.assert_owner();
+        assert!(
+            u128::from(tolerance_bp) <= BASIS_P,
+            "tolerance can't be bigger than 100%"
+        );
+        self.deposit_tolerance_bp = tolerance_bp;
+    }
+
+    /// Sets the harvest deadline: number of rounds after `farming_end` when farmed tokens
+    /// can still be harvested. After the deadline, the owner can sweep the unclaimed farm
+    /// deposits with `sweep_unclaimed`.
+    /// NOTE: the swept deposits include rewards earned but not claimed by users, so the
+    /// deadline must be well past the farming end: at least `MIN_HARVEST_DEADLINE_ROUNDS`.
+    pub fn set_harvest_deadline(&mut self, rounds_after_end: u64) {
+        self.assert_owner();
+        assert!(!self.unclaimed_swept, "unclaimed deposits already swept");
+        assert!(
+            rounds_after_end >= MIN_HARVEST_DEADLINE_ROUNDS,
+            "harvest deadline must be at least {} rounds after the farming end",
+            MIN_HARVEST_DEADLINE_ROUNDS
+        );
+        self.harvest_deadline_round = rounds_after_end;
+    }
+
+    /// Transfers the farm deposits not harvested before the harvest deadline to the treasury.
+    /// Recovered farm tokens (see `withdraw_farmed_recovered`) are still owed to the users,
+    /// so they are not swept. The swept amounts are removed from the farm deposits.
+    /// Can be called only once, after the deadline. If a transfer fails, its amount is
+    /// restored and the sweep can be called again.
+    pub fn sweep_unclaimed(&mut self) {
+        self.assert_owner();
+        assert!(
+            self.harvest_deadline_round > 0,
+            "harvest deadline is not set"
+        );
+        assert!(self.harvest_closed(), "harvest deadline not passed yet");
+        assert!(!self.unclaimed_swept, "unclaimed deposits already swept");
+        self.unclaimed_swept = true;
+        for i in 0..self.farm_tokens.len() {
+            let amount = self.farm_deposits[i]
+                .saturating_sub(self.total_harvested[i] + self.total_farmed_recovered[i]);
+            if amount == 0 {
+                continue;
+            }
+            self.farm_deposits[i] -= amount;
+            let token = self.farm_tokens[i].clone();
+            log!("Sweeping {} unclaimed {} to the treasury", amount, token);
+            if token.as_ref() == NEAR_TOKEN {
+                Promise::new(self.treasury.clone()).transfer(amount);
+                continue;
+            }
+            ext_ft::ext(token)
+                .with_attached_deposit(ONE_YOCTO)
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .ft_transfer(
+                    self.treasury.clone(),
+                    amount.into(),
+                    Some("unclaimed sweep".to_string()),
+                )
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_MINT_CALLBACK)
+                        .sweep_unclaimed_callback(i, amount.into()),
+                );
+        }
+    }
+
+    /// Sets a new treasury - a destination for the swept farm deposits and the only recipient
+    /// of the collected fees (use `set_fee_recipients` to split them afterwards).
+    /// Fees which are being withdrawn at the moment are not affected: on failure the
+    /// callback re-adds them to the collected fees, which will go to the new treasury.
+    pub fn set_treasury(&mut self, new_treasury: AccountId) {
+        self.assert_owner();
+        log!(
+            "Changing treasury from {} to {}",
+            self.treasury,
+            new_treasury
+        );
+        self.fee_recipients = vec![(new_treasury.clone(), BASIS_P as u32)];
+        self.treasury = new_treasury;
+    }
+
+    /// Sets the collected fee recipients with their shares in basis points. Shares must sum
+    /// to 100% (`BASIS_P`). The rounding dust goes to the first recipient.
+    pub fn set_fee_recipients(&mut self, recipients: Vec<(AccountId, u32)>) {
+        self.assert_owner();
+        assert!(!recipients.is_empty(), "fee recipients can't be empty");
+        let total: u128 = recipients.iter().map(|(_, bp)| u128::from(*bp)).sum();
+        assert!(
+            total == BASIS_P,
+            "fee recipient shares must sum to {}, got {}",
+            BASIS_P,
+            total
+        );
+        log!("Setting fee recipients: {:?}", recipients);
+        self.fee_recipients = recipients;
     }
 
     /// start and end are unix timestamps (in seconds)
@@ -461,6 +913,56 @@ impl Contract {
     pub fn admin_set_rates(&mut self, stake_rates: Vec<U128>) {
         self.assert_owner();
         self.stake_rates = stake_rates.iter().map(|x| x.0).collect();
+        self.check_vectors();
+    }
+
+    /// Changes the farm unit emission for the remaining rounds. Rewards of the past rounds
+    /// are settled with the old emission.
+    /// Returns, for each farm token, the change of the deposit required to cover the remaining
+    /// rounds: positive values must be transferred to the contract by the owner, negative
+    /// values are not needed anymore.
+    pub fn set_emission(&mut self, new_emission: U128) -> Vec<I128> {
+        self.assert_owner();
+        let r = self.current_round();
+        let total_rounds = round_number(self.farming_start, self.farming_end, self.farming_end);
+        assert!(r < total_rounds, "farm already finished");
+        // settle the accumulator, so the past rounds use the old emission
+        self.set_reward_acc(self.compute_reward_acc(r), r);
+
+        let remaining = u128::from(total_rounds - r);
+        let old_emission = self.farm_unit_emission;
+        self.farm_unit_emission = new_emission.0;
+        self.apply_cap();
+        log!(
+            "Changing farm unit emission from {} to {} at round {}",
+            old_emission,
+            new_emission.0,
+            r
+        );
+        self.farm_token_rates
+            .iter()
+            .map(|rate| {
+                let old = safe_mul(remaining * old_emission, *rate) as i128;
+                let new = safe_mul(remaining * new_emission.0, *rate) as i128;
+                I128(new - old)
+            })
+            .collect()
+    }
+
+    /// Sets decimals of the stake tokens (in the `stake_tokens` order). Must be called
+    /// before the setup is finalized, so no tokens are staked yet.
+    pub fn admin_set_decimals(&mut self, stake_decimals: Vec<u8>) {
+        self.assert_owner();
+        assert!(
+            !self.setup_finalized,
+            "decimals must be set before the setup is finalized"
+        );
+        assert!(
+            stake_decimals.iter().all(|d| *d <= 24),
+            "decimals can't be bigger than 24"
+        );
+        self.stake_decimals = stake_decimals;
+        self.check_vectors();
     }
 
     pub fn finalize_setup(&mut self) {
@@ -485,19 +987,24 @@ impl Contract {
 
     /// Returns expected and received deposits for farmed tokens
     pub fn finalize_setup_expected(&self) -> (Vec<U128>, Vec<U128>) {
-        let total_rounds = u128::from(round_number(
-            self.farming_start,
-            self.farming_end,
-            self.farming_end,
-        ));
-        let out = self
-            .farm_token_rates
-            .iter()
-            .map(|rate| safe_mul(total_rounds * self.farm_unit_emission, *rate))
+        let out = (0..self.farm_tokens.len())
+            .map(|i| self.expected_deposit(i))
             .collect();
         (to_U128s(&out), to_U128s(&self.farm_deposits))
     }
 
+    /// Deposit of the farm token `token_i` covering the whole farm emission.
+    /// Harvests convert farm units to tokens rounding down (per vault and per harvest), so
+    /// their sum never exceeds the total emission converted to tokens. The deposit rounds
+    /// that conversion up, so it always covers all the harvests up to the last round.
+    fn expected_deposit(&self, token_i: usize) -> Balance {
+        let total_rounds = round_number(self.farming_start, self.farming_end, self.farming_end);
+        safe_mul_ceil(
+            u128::from(total_rounds) * self.farm_unit_emission,
+            self.farm_token_rates[token_i],
+        )
+    }
+
     /*****************
      * internal methods */
 
@@ -506,6 +1013,36 @@ impl Contract {
         assert!(self.is_active, "contract is not active");
     }
 
+    /// Like `assert_is_active`, but also rejects a draining farm.
+    fn assert_accepts_stakes(&self) {
+        self.assert_is_active();
+        assert!(
+            !self.draining,
+            "farm is draining, new stakes are not accepted"
+        );
+    }
+
+    fn farm_status(&self) -> FarmStatus {
+        if !self.is_active {
+            FarmStatus::Paused
+        } else if self.draining {
+            FarmStatus::Draining
+        } else {
+            FarmStatus::Active
+        }
+    }
+
+    /// Returns true when the harvest deadline is set and passed.
+    fn harvest_closed(&self) -> bool {
+        self.harvest_deadline_round > 0
+            && env::block_timestamp() / SECOND
+                >= self.farming_end + self.harvest_deadline_round * ROUND
+    }
+
+    fn assert_harvest_open(&self) {
+        assert!(!self.harvest_closed(), "harvest deadline passed");
+    }
+
     /// transfers staked tokens (token identified by an index in
     /// self.stake_tokens) back to the user.
     /// `self.staked_units` must be adjusted in the caller. The callback will fix the
@@ -514,7 +1051,7 @@ impl Contract {
         if amount == 0 {
             return Promise::new(user);
         }
-        let fee = amount * self.fee_rate / 10_000;
+        let fee = self.unstake_fee(amount);
         let amount = amount - fee;
         let token = self.stake_tokens[token_i].clone();
         self.total_stake[token_i] -= amount;
@@ -534,6 +1071,12 @@ impl Contract {
             );
     }
 
+    /// fee charged from the unstaked `amount`
+    #[inline]
+    fn unstake_fee(&self, amount: Balance) -> Balance {
+        amount * self.fee_rate / 10_000
+    }
+
     #[inline]
     fn transfer_farmed_tokens(&mut self, u: &AccountId, token_i: usize, amount: u128) -> Promise {
         let token = self.farm_tokens[token_i].clone();
@@ -568,6 +1111,13 @@ impl Contract {
             PromiseResult::Successful(_) => {
                 self.fee_collected[token_i] += fee.0;
                 log!("tokens withdrawn {}", amount.0);
+                if fee.0 > 0 {
+                    FeeCollected {
+                        token: &self.stake_tokens[token_i],
+                        amount: fee,
+                    }
+                    .emit();
+                }
                 // we can't remove the vault here, because we don't know if `mint` succeded
                 //  if it didn't succeed, the the mint_callback will try to recover the vault
                 //  and recreate it - so potentially we will send back to the user NEAR deposit
@@ -600,13 +1150,36 @@ impl Contract {
                 log!(
                     "harvesting {} {} token failed. recovering account state",
                     amount.0,
-                    self.stake_tokens[token_i],
+                    self.farm_tokens[token_i],
                 );
                 self.recover_state(&user, false, token_i, amount.0);
             }
         }
     }
 
+    /// Recovers the compounded rewards which were not staked in the target farm.
+    #[private]
+    pub fn compound_callback(&mut self, user: AccountId, token_i: usize, amount: U128) {
+        let used = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            // `ft_transfer_call` returns the amount used by the receiver. An unexpected
+            // result is handled as nothing used: the amount is recovered to the vault.
+            PromiseResult::Successful(v) => near_sdk::serde_json::from_slice::<U128>(&v)
+                .map(|used| std::cmp::min(used.0, amount.0))
+                .unwrap_or(0),
+            PromiseResult::Failed => 0,
+        };
+        let unused = amount.0 - used;
+        if unused > 0 {
+            log!(
+                "compounding {} {} token failed. recovering account state",
+                unused,
+                self.farm_tokens[token_i],
+            );
+            self.recover_state(&user, false, token_i, unused);
+        }
+    }
+
     #[private]
     pub fn withdraw_nft_callback(&mut self, user: AccountId, cheddy: String) {
         match env::promise_result(0) {
@@ -626,18 +1199,24 @@ impl Contract {
                     self.accounts_registered += 1;
                     v = self.new_vault();
                 }
+                self.ping_all(&user, &mut v);
                 v.cheddy = cheddy;
-                self._recompute_stake(&mut v);
+                self._recompute_stake(&user, &mut v);
                 self.vaults.insert(&user, &v);
             }
         }
     }
 
     #[private]
-    pub fn withdraw_fees_callback(&mut self, token_i: usize, amount: U128) {
+    pub fn withdraw_fees_callback(&mut self, token_i: usize, amount: U128, recipient: AccountId) {
         match env::promise_result(0) {
             PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Successful(_) => {}
+            PromiseResult::Successful(_) => FeeWithdrawn {
+                token: &self.stake_tokens[token_i],
+                amount,
+                treasury: &recipient,
+            }
+            .emit(),
 
             PromiseResult::Failed => {
                 log!(
@@ -650,6 +1229,51 @@ impl Contract {
         }
     }
 
+    /// Restores the farm deposit when sweeping it to the treasury failed, and allows to
+    /// sweep again.
+    #[private]
+    pub fn sweep_unclaimed_callback(&mut self, token_i: usize, amount: U128) {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {}
+            PromiseResult::Failed => {
+                log!(
+                    "sweeping {} {} failed. Recovering contract state",
+                    amount.0,
+                    self.farm_tokens[token_i],
+                );
+                self.farm_deposits[token_i] += amount.0;
+                self.unclaimed_swept = false;
+            }
+        }
+    }
+
+    /// Splits the collected fee of the stake token `token_i` between the fee recipients.
+    /// Each transfer has its own callback, which recovers its part if the transfer fails.
+    fn transfer_fee(&mut self, token_i: usize) {
+        let amount = self.fee_collected[token_i];
+        self.fee_collected[token_i] = 0;
+        let shares = fee_shares(amount, &self.fee_recipients);
+        for ((recipient, _), share) in self.fee_recipients.iter().zip(shares) {
+            if share == 0 {
+                continue;
+            }
+            ext_ft::ext(self.stake_tokens[token_i].clone())
+                .with_attached_deposit(ONE_YOCTO)
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .ft_transfer(
+                    recipient.clone(),
+                    share.into(),
+                    Some("fee withdraw".to_string()),
+                )
+                .then(
+                    ext_self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_MINT_CALLBACK)
+                        .withdraw_fees_callback(token_i, share.into(), recipient.clone()),
+                );
+        }
+    }
+
     fn recover_state(&mut self, user: &AccountId, is_staked: bool, token_i: usize, amount: u128) {
         let mut v = match self.vaults.get(&user) {
             Some(v2) => v2,
@@ -660,10 +1284,12 @@ impl Contract {
             }
         };
         if is_staked {
+            self.ping_all(user, &mut v);
             v.staked[token_i] += amount;
-            self._recompute_stake(&mut v);
+            self._recompute_stake(user, &mut v);
         } else {
             self.total_harvested[token_i] -= amount;
+            self.total_farmed_recovered[token_i] += amount;
             v.farmed_recovered[token_i] += amount;
         }
 
@@ -704,6 +1330,18 @@ impl Contract {
     }
 }
 
+/// Splits `amount` between the `recipients` proportionally to their basis point shares.
+/// The rounding dust goes to the first recipient.
+fn fee_shares(amount: Balance, recipients: &[(AccountId, u32)]) -> Vec<Balance> {
+    let mut shares: Vec<Balance> = recipients
+        .iter()
+        .map(|(_, bp)| amount * u128::from(*bp) / BASIS_P)
+        .collect();
+    let distributed: Balance = shares.iter().sum();
+    shares[0] += amount - distributed;
+    shares
+}
+
 #[cfg(all(test, not(target_arch = "wasm32")))]
 #[allow(unused_imports)]
 mod tests {
@@ -727,32 +1365,32 @@ mod tests {
     }
 
     fn acc_staking1() -> AccountId {
-        "atom1".try_into().unwrap()
+        "atom1".parse().unwrap()
     }
 
     fn acc_staking2() -> AccountId {
-        "atom2".try_into().unwrap()
+        "atom2".parse().unwrap()
     }
 
     fn acc_nft_cheddy() -> AccountId {
-        "nft_cheddy".try_into().unwrap()
+        "nft_cheddy".parse().unwrap()
     }
 
     fn acc_u1() -> AccountId {
-        "user1".try_into().unwrap()
+        "user1".parse().unwrap()
     }
 
     fn acc_u2() -> AccountId {
-        "user2".try_into().unwrap()
+        "user2".parse().unwrap()
     }
 
     #[allow(dead_code)]
     fn acc_u3() -> AccountId {
-        "user3".try_into().unwrap()
+        "user3".parse().unwrap()
     }
 
     fn acc_owner() -> AccountId {
-        "user_owner".try_into().unwrap()
+        "user_owner".parse().unwrap()
     }
 
     /// half of the block round
@@ -877,63 +1515,1233 @@ mod tests {
         ctr.set_active(false);
     }
 
-    fn finalize(ctr: &mut Contract) {
-        ctr._setup_deposit(&acc_cheddar().into(), 20 * E24);
-        ctr._setup_deposit(&acc_farming2().into(), 10 * E24);
-        ctr.finalize_setup();
+    #[test]
+    fn test_farm_config_hash() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        let hash = ctr.get_farm_config_hash();
+        assert_eq!(hash.len(), 64);
+        assert_eq!(ctr.get_farm_config_hash(), hash);
+
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_start_end(round(1) / SECOND, round(END + 1) / SECOND);
+        let new_hash = ctr.get_farm_config_hash();
+        assert_ne!(new_hash, hash);
+        assert_eq!(ctr.get_farm_config_hash(), new_hash);
     }
 
     #[test]
-    fn test_finalize_setup() {
-        let (_, mut ctr) = setup_contract(accounts(1), 0, 0);
-        assert_eq!(
-            ctr.setup_finalized, false,
-            "at the beginning setup mut not be finalized"
-        );
-        finalize(&mut ctr);
-        assert_eq!(ctr.setup_finalized, true)
+    fn test_set_status() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        assert_eq!(ctr.get_contract_params().status, FarmStatus::Active);
+        ctr.set_status(FarmStatus::Draining);
+        assert_eq!(ctr.get_contract_params().status, FarmStatus::Draining);
+        assert!(ctr.is_active);
+        ctr.set_status(FarmStatus::Paused);
+        assert_eq!(ctr.get_contract_params().status, FarmStatus::Paused);
+        assert!(!ctr.is_active);
+        // set_active opens the farm fully
+        ctr.set_status(FarmStatus::Draining);
+        ctr.set_active(true);
+        assert_eq!(ctr.get_contract_params().status, FarmStatus::Active);
     }
 
     #[test]
-    #[should_panic(expected = "must be finalized at last before farm start")]
-    fn test_finalize_setup_too_late() {
-        let (mut ctx, mut ctr) = setup_contract(accounts(1), 0, 0);
-        ctr._setup_deposit(&acc_cheddar().into(), 20 * E24);
-        ctr._setup_deposit(&acc_farming2().into(), 10 * E24);
-        testing_env!(ctx.block_timestamp(10 * ROUND_NS).build());
-        ctr.finalize_setup();
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_set_status_not_owner() {
+        let (_, mut ctr) = setup_contract(accounts(0), 0, 0);
+        ctr.set_status(FarmStatus::Draining);
     }
 
     #[test]
-    #[should_panic(expected = "Expected deposit for token cheddar1 is 20000000000000000000000000")]
-    fn test_finalize_setup_wrong_deposit() {
-        let (_, mut ctr) = setup_contract(accounts(1), 0, 0);
-        ctr._setup_deposit(&acc_cheddar().into(), 10 * E24);
+    #[should_panic(expected = "farm is draining, new stakes are not accepted")]
+    fn test_draining_rejects_stake() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_status(FarmStatus::Draining);
+        stake(&mut ctx, &mut ctr, &user_1, &acc_staking1(), E24);
     }
 
     #[test]
-    #[should_panic(expected = "Deposit for token cheddar2 not done")]
-    fn test_finalize_setup_not_enough_deposit() {
-        let (_, mut ctr) = setup_contract(accounts(1), 0, 0);
-        ctr._setup_deposit(&acc_cheddar().into(), 20 * E24);
-        ctr.finalize_setup();
+    fn test_draining_allows_harvest_and_unstake() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_status(FarmStatus::Draining);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(5))
+            .build());
+        ctr.withdraw_crop();
+        assert!(ctr.total_harvested[0] > 0);
+        unstake(&mut ctx, &mut ctr, &user_1, &acc_staking1(), E24 / 2);
+        assert_eq!(ctr.get_vault(&user_1).staked, vec![E24 / 2, 10 * E24]);
+        close(&mut ctx, &mut ctr, &user_1);
+        assert!(ctr.vaults.get(&user_1).is_none());
     }
 
     #[test]
-    fn test_round_number() {
-        let (mut ctx, ctr) = setup_contract(acc_u1(), 0, 0);
-        assert_eq!(ctr.current_round(), 0);
-
-        assert_eq!(round(-9), ROUND_NS);
-        assert_eq!(ctr.farming_start, 10 * ROUND);
+    #[should_panic(expected = "contract is not active")]
+    fn test_paused_blocks_unstake() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_status(FarmStatus::Paused);
+        unstake(&mut ctx, &mut ctr, &user_1, &acc_staking1(), E24 / 2);
+    }
 
-        testing_env!(ctx.block_timestamp(round(-2)).build());
-        assert_eq!(ctr.current_round(), 0);
+    #[test]
+    fn test_harvest_keeps_total_stake() {
+        let u1 = acc_u1();
+        let mut ctx = VMContextBuilder::new();
+        testing_env!(ctx.build());
+        // one stake token and two farm tokens - index spaces don't align
+        let mut ctr = Contract::new(
+            acc_owner(),
+            vec![acc_staking1()],
+            to_U128s(&vec![E24]),
+            RATE.into(),
+            vec![acc_cheddar(), acc_farming2()],
+            to_U128s(&vec![E24, E24 / 2]),
+            round(0) / SECOND,
+            round(END) / SECOND,
+            acc_nft_cheddy(),
+            BOOST,
+            0,
+            accounts(1),
+        );
+        testing_env!(ctx.block_timestamp(round(-10)).build());
+        finalize(&mut ctr);
 
-        testing_env!(ctx.block_timestamp(round(0)).build());
-        assert_eq!(ctr.current_round(), 0);
+        let a1_stake = vec![5 * E24];
+        register_user_and_stake(&mut ctx, &mut ctr, &u1, &a1_stake, -2);
+        assert_eq!(ctr.total_stake, a1_stake);
 
-        assert_eq!(round(1), 11 * ROUND_NS);
+        testing_env!(ctx
+            .attached_deposit(0)
+            .predecessor_account_id(u1.clone())
+            .block_timestamp(round(4))
+            .build());
+        ctr.withdraw_crop();
+        assert_eq!(ctr.total_harvested, vec![4 * RATE, 4 * RATE / 2]);
+        assert_eq!(
+            ctr.total_stake, a1_stake,
+            "harvest must not change the stake"
+        );
+
+        // failed harvest of the second farm token is recovered without touching the stake
+        testing_env!(
+            ctx.predecessor_account_id(env::current_account_id())
+                .build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        ctr.transfer_farmed_callback(u1.clone(), 1, (4 * RATE / 2).into());
+        assert_eq!(ctr.total_harvested, vec![4 * RATE, 0]);
+        assert_eq!(
+            ctr.total_stake, a1_stake,
+            "harvest must not change the stake"
+        );
+        assert_eq!(ctr.get_vault(&u1).farmed_recovered, vec![0, 4 * RATE / 2]);
+    }
+
+    #[test]
+    fn test_stake_decimals() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        // second stake token has 6 decimals
+        ctr.admin_set_decimals(vec![24, 6]);
+        finalize(&mut ctr);
+        let e6: u128 = 1_000_000;
+
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![10 * E24, 100 * e6], -1);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &vec![20 * E24, 200 * e6], -1);
+        // 100 tokens with rate 0.1 give the same stake as 10 tokens with rate 1
+        assert_eq!(ctr.get_vault(&user_1).min_stake, 10 * E24);
+        assert_eq!(ctr.get_vault(&user_2).min_stake, 20 * E24);
+
+        testing_env!(ctx.block_timestamp(round(5)).build());
+        let farmed_1 = ctr.status(user_1).unwrap().farmed_units.0;
+        let farmed_2 = ctr.status(user_2).unwrap().farmed_units.0;
+        assert!(farmed_1 > 0);
+        assert!(farmed_1.abs_diff(farmed_2 / 2) <= 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "decimals must be set before the setup is finalized")]
+    fn test_set_decimals_after_finalize() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        ctr.admin_set_decimals(vec![24, 6]);
+    }
+
+    #[test]
+    fn test_withdraw_crop_until() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+
+        // claim the first 5 rounds in the middle of the farm
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(7))
+            .build());
+        ctr.withdraw_crop_until(5);
+        let harvested_r5 = ctr.total_harvested[0];
+        assert!(harvested_r5.abs_diff(5 * RATE) < E24 / 1_000_000);
+        // rounds 6 and 7 stay in the vault
+        let farmed = ctr.status(user_1.clone()).unwrap().farmed_units.0;
+        assert!(farmed.abs_diff(2 * RATE) < E24 / 1_000_000);
+
+        // claim the rest at the end of the farm
+        testing_env!(ctx.block_timestamp(round(END)).build());
+        ctr.withdraw_crop();
+        assert_eq!(ctr.get_vault(&user_1).farmed, 0);
+        assert!(ctr.total_harvested[0].abs_diff(10 * RATE) < E24 / 1_000_000);
+    }
+
+    #[test]
+    fn test_compound_to_farm() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        let target: AccountId = "farm2".parse().unwrap();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        ctr.compound_to_farm(target.clone());
+        assert_eq!(ctr.get_vault(&user_1).farmed, 0);
+        let harvested = ctr.total_harvested.clone();
+        assert!(harvested[0].abs_diff(3 * RATE) < E24 / 1_000_000);
+
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let transfer = receipts
+            .iter()
+            .find(|r| r.receiver_id == acc_cheddar())
+            .expect("ft_transfer_call receipt not created");
+        match &transfer.actions[0] {
+            near_sdk::mock::VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } => {
+                assert_eq!(function_name, "ft_transfer_call");
+                let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                assert_eq!(args["receiver_id"], "farm2");
+                assert_eq!(args["amount"], harvested[0].to_string());
+                assert_eq!(args["msg"], "stake for user1");
+            }
+            _ => panic!("expected a function call"),
+        }
+
+        let resolve = |ctx: &mut VMContextBuilder, result: PromiseResult| {
+            testing_env!(
+                ctx.predecessor_account_id(env::current_account_id())
+                    .build(),
+                near_sdk::VMConfig::test(),
+                near_sdk::RuntimeFeesConfig::test(),
+                Default::default(),
+                vec![result],
+            );
+        };
+        // the target farm staked everything
+        let used = serde_json::to_vec(&U128(harvested[0])).unwrap();
+        resolve(&mut ctx, PromiseResult::Successful(used));
+        ctr.compound_callback(user_1.clone(), 0, harvested[0].into());
+        // the target farm staked only a part
+        let used = serde_json::to_vec(&U128(harvested[1] - 5)).unwrap();
+        resolve(&mut ctx, PromiseResult::Successful(used));
+        ctr.compound_callback(user_1.clone(), 1, harvested[1].into());
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered, vec![0, 5]);
+        assert_eq!(ctr.total_harvested, vec![harvested[0], harvested[1] - 5]);
+
+        // the target farm rejected the transfer: everything is recovered locally
+        resolve(&mut ctx, PromiseResult::Failed);
+        ctr.compound_callback(user_1.clone(), 0, harvested[0].into());
+        assert_eq!(
+            ctr.get_vault(&user_1).farmed_recovered,
+            vec![harvested[0], 5]
+        );
+        assert_eq!(ctr.total_harvested, vec![0, harvested[1] - 5]);
+
+        // an unexpected result is handled as nothing used
+        resolve(&mut ctx, PromiseResult::Successful(b"\"invalid\"".to_vec()));
+        ctr.compound_callback(user_1.clone(), 1, 10.into());
+        assert_eq!(
+            ctr.get_vault(&user_1).farmed_recovered,
+            vec![harvested[0], 15]
+        );
+        assert_eq!(ctr.total_harvested, vec![0, harvested[1] - 15]);
+    }
+
+    #[test]
+    fn test_stake_unknown_token() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        let total_stake = ctr.total_stake.clone();
+
+        testing_env!(ctx
+            .predecessor_account_id("unknown.token".parse().unwrap())
+            .build());
+        let refund = ctr.ft_on_transfer(user_1.clone(), E24.into(), "".to_string());
+        assert!(matches!(refund, PromiseOrValue::Value(U128(E24))));
+        assert_eq!(ctr.get_vault(&user_1).staked, vec![E24, 10 * E24]);
+        assert_eq!(ctr.total_stake, total_stake);
+    }
+
+    #[test]
+    #[should_panic(expected = "account is not registered: register it first with storage_deposit")]
+    fn test_stake_near_not_registered() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        testing_env!(ctx
+            .attached_deposit(STORAGE_COST)
+            .predecessor_account_id(acc_u1())
+            .build());
+        ctr.stake_near();
+    }
+
+    #[test]
+    fn test_stake_near_registers() {
+        let mut ctx = VMContextBuilder::new();
+        testing_env!(ctx.build());
+        let mut ctr = Contract::new(
+            acc_owner(),
+            vec![near(), acc_staking2()],
+            to_U128s(&vec![E24, E24 / 10]),
+            RATE.into(),
+            vec![acc_cheddar(), acc_farming2()],
+            to_U128s(&vec![E24, E24 / 2]),
+            round(0) / SECOND,
+            round(END) / SECOND,
+            acc_nft_cheddy(),
+            BOOST,
+            0,
+            accounts(1),
+        );
+        finalize(&mut ctr);
+        let u1 = acc_u1();
+        testing_env!(ctx
+            .attached_deposit(STORAGE_COST + 5 * E24)
+            .predecessor_account_id(u1.clone())
+            .block_timestamp(round(-1))
+            .build());
+        ctr.stake_near();
+        assert_eq!(ctr.accounts_registered, 1);
+        assert_eq!(ctr.get_vault(&u1).staked, vec![5 * E24, 0]);
+        assert_eq!(ctr.total_stake, vec![5 * E24, 0]);
+
+        // registered accounts stake the whole deposit
+        testing_env!(ctx.attached_deposit(E24).build());
+        ctr.stake_near();
+        assert_eq!(ctr.accounts_registered, 1);
+        assert_eq!(ctr.get_vault(&u1).staked, vec![6 * E24, 0]);
+    }
+
+    #[test]
+    fn test_stake_for() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let source_farm: AccountId = "source_farm".parse().unwrap();
+        ctr.set_trusted_farms(vec![source_farm.clone()]);
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &vec![E24, 10 * E24], -1);
+
+        // a trusted farm compounding its rewards stakes on behalf of user_2
+        testing_env!(ctx.predecessor_account_id(acc_staking1()).build());
+        let res = ctr.ft_on_transfer(source_farm, E24.into(), "stake for user2".to_string());
+        assert!(matches!(res, PromiseOrValue::Value(U128(0))));
+        assert_eq!(ctr.get_vault(&user_2).staked, vec![2 * E24, 10 * E24]);
+        assert_eq!(ctr.get_vault(&user_1).staked, vec![E24, 10 * E24]);
+    }
+
+    #[test]
+    fn test_stake_for_untrusted_sender() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        ctr.set_trusted_farms(vec!["source_farm".parse().unwrap()]);
+        let user_2 = acc_u2();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &vec![E24, 10 * E24], -1);
+
+        // neither a random account nor the farm itself can stake on behalf of user_2
+        testing_env!(ctx.predecessor_account_id(acc_staking1()).build());
+        for sender in [acc_u1(), env::current_account_id()] {
+            let res = ctr.ft_on_transfer(sender, E24.into(), "stake for user2".to_string());
+            assert!(matches!(res, PromiseOrValue::Value(U128(E24))));
+        }
+        assert_eq!(ctr.get_vault(&user_2).staked, vec![E24, 10 * E24]);
+        assert_eq!(ctr.total_stake, vec![E24, 10 * E24]);
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_set_trusted_farms_not_owner() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        testing_env!(ctx.predecessor_account_id(acc_u1()).build());
+        ctr.set_trusted_farms(vec![acc_u1()]);
+    }
+
+    #[test]
+    fn test_withdraw_crop_until_settled_round() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &vec![E24, 10 * E24], 3);
+
+        // user_2 settles the rewards accumulator up to round 7
+        testing_env!(ctx
+            .predecessor_account_id(user_2.clone())
+            .block_timestamp(round(7))
+            .build());
+        ctr.withdraw_crop();
+        let harvested = ctr.total_harvested[0];
+
+        // user_1 farmed all the round 1-3 rewards and half of the round 4-5 rewards
+        testing_env!(ctx.predecessor_account_id(user_1.clone()).build());
+        ctr.withdraw_crop_until(5);
+        let harvested_1 = ctr.total_harvested[0] - harvested;
+        assert!(harvested_1.abs_diff(4 * RATE) < E24 / 1_000_000);
+        let farmed = ctr.status(user_1.clone()).unwrap().farmed_units.0;
+        assert!(farmed.abs_diff(RATE) < E24 / 1_000_000);
+    }
+
+    /// Records a checkpoint every 2 rounds, with the accumulator equal to `round * E24`,
+    /// until the oldest 10 checkpoints are overwritten.
+    fn fill_reward_acc_checkpoints(ctr: &mut Contract) {
+        for r in 1..=MAX_REWARD_ACC_CHECKPOINTS + 10 {
+            ctr.set_reward_acc(u128::from(2 * r) * E24, 2 * r);
+        }
+    }
+
+    #[test]
+    fn test_reward_acc_checkpoints_bounded() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        fill_reward_acc_checkpoints(&mut ctr);
+        let n = MAX_REWARD_ACC_CHECKPOINTS;
+        assert_eq!(ctr.reward_acc_checkpoints.len(), n);
+        // the accumulator is still interpolated between the kept checkpoints
+        assert_eq!(ctr.reward_acc_at(22), 22 * E24);
+        assert_eq!(ctr.reward_acc_at(23), 23 * E24);
+        assert_eq!(ctr.reward_acc_at(2 * n + 5), u128::from(2 * n + 5) * E24);
+    }
+
+    #[test]
+    #[should_panic(expected = "rewards accumulator is not known before round 22")]
+    fn test_reward_acc_checkpoints_overwritten() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        fill_reward_acc_checkpoints(&mut ctr);
+        ctr.reward_acc_at(21);
+    }
+
+    #[test]
+    fn test_harvest_deadline() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        ctr.set_harvest_deadline(MIN_HARVEST_DEADLINE_ROUNDS);
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &vec![E24, 10 * E24], -1);
+
+        // harvest before the deadline
+        let deadline = round(END) + MIN_HARVEST_DEADLINE_ROUNDS * ROUND_NS;
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(deadline - 1)
+            .build());
+        ctr.withdraw_crop();
+        assert!(ctr.total_harvested[0].abs_diff(5 * RATE) < E24 / 1_000_000);
+
+        // sweep after the deadline: user_2 rewards were not claimed
+        testing_env!(ctx
+            .predecessor_account_id(acc_owner())
+            .block_timestamp(deadline)
+            .build());
+        ctr.sweep_unclaimed();
+        assert!(ctr.unclaimed_swept);
+        let transfers: Vec<AccountId> = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .map(|r| r.receiver_id)
+            .filter(|a| *a != env::current_account_id())
+            .collect();
+        assert_eq!(
+            transfers,
+            vec![acc_cheddar(), acc_farming2()],
+            "both farm tokens are swept"
+        );
+    }
+
+    #[test]
+    fn test_sweep_unclaimed_recovered_and_failed() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        ctr.set_harvest_deadline(MIN_HARVEST_DEADLINE_ROUNDS);
+        let user_1 = acc_u1();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        let failed_ctx = |ctx: &mut VMContextBuilder| {
+            testing_env!(
+                ctx.predecessor_account_id(env::current_account_id())
+                    .build(),
+                near_sdk::VMConfig::test(),
+                near_sdk::RuntimeFeesConfig::test(),
+                Default::default(),
+                vec![PromiseResult::Failed],
+            );
+        };
+
+        // the harvest of the second farm token fails and is recovered
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(4))
+            .build());
+        ctr.withdraw_crop();
+        let recovered = ctr.total_harvested[1];
+        failed_ctx(&mut ctx);
+        ctr.transfer_farmed_callback(user_1.clone(), 1, recovered.into());
+        assert_eq!(ctr.total_farmed_recovered, vec![0, recovered]);
+
+        // the recovered tokens are not swept
+        let deadline = round(END) + MIN_HARVEST_DEADLINE_ROUNDS * ROUND_NS;
+        testing_env!(ctx
+            .predecessor_account_id(acc_owner())
+            .block_timestamp(deadline)
+            .build());
+        let harvested = ctr.total_harvested.clone();
+        ctr.sweep_unclaimed();
+        assert_eq!(ctr.farm_deposits, vec![harvested[0], recovered]);
+
+        // a failed sweep transfer restores the deposit and allows to sweep again
+        failed_ctx(&mut ctx);
+        ctr.sweep_unclaimed_callback(0, (20 * E24 - harvested[0]).into());
+        assert!(!ctr.unclaimed_swept);
+        assert_eq!(ctr.farm_deposits[0], 20 * E24);
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.sweep_unclaimed();
+        let transfers: Vec<AccountId> = near_sdk::test_utils::get_created_receipts()
+            .into_iter()
+            .map(|r| r.receiver_id)
+            .filter(|a| *a != env::current_account_id())
+            .collect();
+        assert_eq!(
+            transfers,
+            vec![acc_cheddar()],
+            "only the first farm token is swept again"
+        );
+        assert_eq!(ctr.farm_deposits, vec![harvested[0], recovered]);
+
+        // recovered tokens can be withdrawn after the deadline
+        testing_env!(ctx.predecessor_account_id(user_1.clone()).build());
+        ctr.withdraw_farmed_recovered(&acc_farming2());
+        assert_eq!(ctr.total_farmed_recovered, vec![0, 0]);
+        assert_eq!(ctr.total_harvested[1], recovered);
+    }
+
+    #[test]
+    #[should_panic(expected = "harvest deadline passed")]
+    fn test_harvest_after_sweep() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        ctr.set_harvest_deadline(MIN_HARVEST_DEADLINE_ROUNDS);
+        let user_1 = acc_u1();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+
+        let deadline = round(END) + MIN_HARVEST_DEADLINE_ROUNDS * ROUND_NS;
+        testing_env!(ctx
+            .predecessor_account_id(acc_owner())
+            .block_timestamp(deadline)
+            .build());
+        ctr.sweep_unclaimed();
+        testing_env!(ctx.predecessor_account_id(user_1.clone()).build());
+        ctr.withdraw_crop();
+    }
+
+    #[test]
+    #[should_panic(expected = "harvest deadline not passed yet")]
+    fn test_sweep_before_deadline() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        ctr.set_harvest_deadline(MIN_HARVEST_DEADLINE_ROUNDS);
+        testing_env!(ctx.block_timestamp(round(END + 1)).build());
+        ctr.sweep_unclaimed();
+    }
+
+    #[test]
+    fn test_account_farm_breakdown() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        assert!(ctr.get_account_farm_breakdown(user_1.clone()).is_empty());
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+
+        testing_env!(ctx.block_timestamp(round(4)).build());
+        let farmed = ctr.status(user_1.clone()).unwrap().farmed_units.0;
+        assert_eq!(
+            ctr.get_account_farm_breakdown(user_1),
+            vec![
+                (acc_cheddar(), U128(farmed)),
+                (acc_farming2(), U128(farmed / 2))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_farmed_between() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        assert_eq!(ctr.get_farmed_between(user_1.clone(), 0, END as u64).0, 0);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+
+        let farmed_at = |ctx: &mut VMContextBuilder, ctr: &Contract, r: i64| {
+            testing_env!(ctx.block_timestamp(round(r)).build());
+            ctr.status(user_1.clone()).unwrap().farmed_units.0
+        };
+        let f3 = farmed_at(&mut ctx, &ctr, 3);
+        let f7 = farmed_at(&mut ctx, &ctr, 7);
+        assert_eq!(f7 - f3, 4 * RATE);
+        assert_eq!(ctr.get_farmed_between(user_1.clone(), 3, 7).0, f7 - f3);
+
+        // clamped to the farm rounds
+        assert_eq!(
+            ctr.get_farmed_between(user_1.clone(), 7, 100).0,
+            farmed_at(&mut ctx, &ctr, END + 5) - f7
+        );
+        assert_eq!(ctr.get_farmed_between(user_1.clone(), 7, 3).0, 0);
+        assert_eq!(ctr.get_farmed_between(user_1, 50, 100).0, 0);
+    }
+
+    #[test]
+    fn test_get_farmed_between_stake_changed() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        // user_2 doubles the total stake at round 4
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &vec![E24, 10 * E24], 4);
+
+        testing_env!(ctx.block_timestamp(round(8)).build());
+        // 2 rounds alone and 2 rounds with user_2
+        assert_eq!(ctr.get_farmed_between(user_1.clone(), 2, 6).0, 2 * RATE + RATE);
+        assert_eq!(ctr.get_farmed_between(user_1.clone(), 0, 4).0, 4 * RATE);
+        assert_eq!(ctr.get_farmed_between(user_2.clone(), 4, 8).0, 2 * RATE);
+        assert_eq!(
+            ctr.get_farmed_between(user_1.clone(), 0, 8).0,
+            ctr.status(user_1).unwrap().farmed_units.0
+        );
+    }
+
+    #[test]
+    fn test_max_units_per_round_per_user() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let cap = RATE / 2;
+        ctr.set_max_units_per_round_per_user(Some(cap.into()));
+        let user_1 = acc_u1();
+        let whale = acc_u2();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        register_user_and_stake(&mut ctx, &mut ctr, &whale, &vec![10 * E24, 100 * E24], -1);
+        // without the cap the whale would farm 10/11 of the emission
+        assert_eq!(ctr.get_vault(&whale).min_stake, 10 * E24);
+        assert_eq!(ctr.staked_units, 11 * E24);
+        assert_eq!(ctr.capped_units, 10 * E24);
+
+        testing_env!(ctx.block_timestamp(round(4)).build());
+        let farmed = |a: &AccountId| ctr.status(a.clone()).unwrap().farmed_units.0;
+        assert_eq!(farmed(&whale), 4 * cap);
+        assert_eq!(farmed(&user_1), 4 * (RATE - cap));
+        assert_eq!(ctr.get_farmed_between(whale.clone(), 1, 4).0, 3 * cap);
+        assert_eq!(ctr.get_farmed_between(user_1.clone(), 1, 4).0, 3 * (RATE - cap));
+    }
+
+    #[test]
+    fn test_max_units_per_round_per_user_others_unstake() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let cap = RATE / 2;
+        ctr.set_max_units_per_round_per_user(Some(cap.into()));
+        let user_1 = acc_u1();
+        let user_3 = acc_u3();
+        let whale = acc_u2();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_3, &vec![E24, 10 * E24], -1);
+        register_user_and_stake(&mut ctx, &mut ctr, &whale, &vec![10 * E24, 100 * E24], -1);
+        let farmed = |ctr: &Contract, a: &AccountId| ctr.status(a.clone()).unwrap().farmed_units.0;
+
+        // user_3 unstakes at round 2: the whale still farms the cap, and user_1 farms the rest
+        testing_env!(ctx.block_timestamp(round(2)).build());
+        unstake(&mut ctx, &mut ctr, &user_3, &acc_staking1(), E24);
+        assert_eq!(ctr.staked_units, 11 * E24);
+        testing_env!(ctx.block_timestamp(round(4)).build());
+        assert_eq!(farmed(&ctr, &whale), 4 * cap);
+        assert_eq!(farmed(&ctr, &user_1), 2 * (RATE - cap) / 2 + 2 * (RATE - cap));
+
+        // the whale stake update doesn't change its farm
+        stake(&mut ctx, &mut ctr, &whale, &acc_staking1(), E24);
+        testing_env!(ctx.block_timestamp(round(6)).build());
+        assert_eq!(farmed(&ctr, &whale), 6 * cap);
+
+        // alone, the whale is not capped: the excess emission is not burned
+        unstake(&mut ctx, &mut ctr, &user_1, &acc_staking1(), E24);
+        assert_eq!(ctr.capped_units, 0);
+        testing_env!(ctx.block_timestamp(round(8)).build());
+        assert_eq!(farmed(&ctr, &whale), 6 * cap + 2 * RATE);
+    }
+
+    #[test]
+    fn test_max_units_per_round_per_user_close() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        ctr.set_max_units_per_round_per_user(Some((RATE / 2).into()));
+        let user_1 = acc_u1();
+        let whale = acc_u2();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+        register_user_and_stake(&mut ctx, &mut ctr, &whale, &vec![10 * E24, 100 * E24], -1);
+        testing_env!(ctx.predecessor_account_id(acc_nft_cheddy()).build());
+        ctr.nft_on_transfer(whale.clone(), whale.clone(), "1".into(), "".into());
+        let boosted = 10 * E24 + 10 * E24 * u128::from(BOOST) / BASIS_P;
+        assert_eq!(ctr.get_vault(&whale).min_stake, boosted);
+        assert_eq!(ctr.capped_units, boosted);
+
+        // the capped whale removes its boosted stake units once
+        testing_env!(ctx.block_timestamp(round(2)).build());
+        close(&mut ctx, &mut ctr, &whale);
+        assert_eq!(ctr.staked_units, E24);
+        assert_eq!(ctr.capped_units, 0);
+        assert!(ctr.capped_stakes.is_empty());
+        testing_env!(ctx.block_timestamp(round(4)).build());
+        let farmed = ctr.status(user_1.clone()).unwrap().farmed_units.0;
+        assert_eq!(farmed, 2 * RATE / 2 + 2 * RATE);
+    }
+
+    #[test]
+    fn test_max_units_per_round_per_user_alone() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        ctr.set_max_units_per_round_per_user(Some((RATE / 2).into()));
+        let whale = acc_u2();
+        register_user_and_stake(&mut ctx, &mut ctr, &whale, &vec![10 * E24, 100 * E24], -1);
+        // no other stakers to redistribute the excess to
+        assert_eq!(ctr.capped_units, 0);
+        testing_env!(ctx.block_timestamp(round(2)).build());
+        assert_eq!(ctr.status(whale.clone()).unwrap().farmed_units.0, 2 * RATE);
+    }
+
+    #[test]
+    #[should_panic(expected = "the cap can only be changed when nothing is staked")]
+    fn test_max_units_per_round_per_user_staked() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        register_user_and_stake(&mut ctx, &mut ctr, &acc_u1(), &vec![E24, 10 * E24], -1);
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_max_units_per_round_per_user(Some((RATE / 2).into()));
+    }
+
+    #[test]
+    #[should_panic(expected = "start must be in the future")]
+    fn test_new_with_past_start() {
+        let context = VMContextBuilder::new();
+        testing_env!(context.clone().block_timestamp(round(1)).build());
+        Contract::new(
+            acc_owner(),
+            vec![acc_staking1(), acc_staking2()],
+            to_U128s(&vec![E24, E24 / 10]),
+            RATE.into(),
+            vec![acc_cheddar(), acc_farming2()],
+            to_U128s(&vec![E24, E24 / 2]),
+            round(0) / SECOND,
+            round(END) / SECOND,
+            acc_nft_cheddy(),
+            BOOST,
+            0,
+            accounts(1),
+        );
+    }
+
+    #[test]
+    fn test_set_emission() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        finalize(&mut ctr);
+        let user_1 = acc_u1();
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &vec![E24, 10 * E24], -1);
+
+        // double the emission after 5 rounds
+        testing_env!(ctx
+            .predecessor_account_id(acc_owner())
+            .block_timestamp(round(5))
+            .build());
+        let farmed_r5 = ctr.status(user_1.clone()).unwrap().farmed_units.0;
+        let delta = ctr.set_emission((2 * RATE).into());
+        assert_eq!(ctr.farm_unit_emission, 2 * RATE);
+        // remaining 5 rounds need the same extra deposit as originally
+        assert_eq!(delta, vec![I128(10 * E24 as i128), I128(5 * E24 as i128)]);
+        // rewards farmed in the past rounds don't change
+        assert_eq!(
+            ctr.status(user_1.clone()).unwrap().farmed_units.0,
+            farmed_r5
+        );
+
+        testing_env!(ctx.block_timestamp(round(END)).build());
+        let farmed = ctr.status(user_1.clone()).unwrap().farmed_units.0;
+        let expected = 5 * RATE + 5 * 2 * RATE;
+        assert!(farmed_r5.abs_diff(5 * RATE) < E24 / 1_000_000);
+        assert!(farmed.abs_diff(expected) < E24 / 1_000_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_set_emission_not_owner() {
+        let (_, mut ctr) = setup_contract(acc_u1(), 0, 0);
+        ctr.set_emission(E24.into());
+    }
+
+    #[test]
+    fn test_simulate_unstake() {
+        let u1 = acc_u1();
+        let t_s1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(u1.clone(), 0, 100); // 1% fee
+        finalize(&mut ctr);
+        register_user_and_stake(&mut ctx, &mut ctr, &u1, &vec![10 * E24, 10 * E24], -2);
+
+        let (returned, fee) = ctr.simulate_unstake(u1.clone(), t_s1.clone(), (4 * E24).into());
+        assert_eq!(returned.0, 4 * E24 * 99 / 100);
+        assert_eq!(fee.0, 4 * E24 / 100);
+        // not enough stake or not registered
+        let zero: (U128, U128) = (0.into(), 0.into());
+        assert_eq!(
+            ctr.simulate_unstake(u1.clone(), t_s1.clone(), (11 * E24).into()),
+            zero
+        );
+        assert_eq!(
+            ctr.simulate_unstake(acc_u2(), t_s1.clone(), E24.into()),
+            zero
+        );
+
+        // the simulated split matches the actual unstake
+        unstake(&mut ctx, &mut ctr, &u1, &t_s1, 4 * E24);
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let transfer = receipts
+            .iter()
+            .rev()
+            .find(|r| r.receiver_id == t_s1)
+            .expect("ft_transfer receipt not created");
+        match &transfer.actions[0] {
+            near_sdk::mock::VmAction::FunctionCall { args, .. } => {
+                let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                assert_eq!(args["amount"], returned.0.to_string());
+            }
+            _ => panic!("expected a function call"),
+        }
+        let callback = receipts
+            .iter()
+            .rev()
+            .find(|r| r.receiver_id == env::current_account_id())
+            .expect("callback receipt not created");
+        match &callback.actions[0] {
+            near_sdk::mock::VmAction::FunctionCall { args, .. } => {
+                let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                assert_eq!(args["fee"], fee.0.to_string());
+            }
+            _ => panic!("expected a function call"),
+        }
+    }
+
+    #[test]
+    fn test_fee_events() {
+        let u1 = acc_u1();
+        let t_s1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(u1.clone(), 0, 100); // 1% fee
+        finalize(&mut ctr);
+        register_user_and_stake(&mut ctx, &mut ctr, &u1, &vec![10 * E24, 10 * E24], -2);
+        let events = || -> Vec<serde_json::Value> {
+            near_sdk::test_utils::get_logs()
+                .iter()
+                .filter_map(|l| l.strip_prefix("EVENT_JSON:"))
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect()
+        };
+        let callback_env = |ctx: &mut VMContextBuilder| {
+            testing_env!(
+                ctx.predecessor_account_id(env::current_account_id())
+                    .build(),
+                near_sdk::VMConfig::test(),
+                near_sdk::RuntimeFeesConfig::test(),
+                Default::default(),
+                vec![PromiseResult::Successful(vec![])],
+            );
+        };
+
+        // the fee is collected only when the unstaked tokens are transferred
+        unstake(&mut ctx, &mut ctr, &u1, &t_s1, 4 * E24);
+        assert!(events().is_empty());
+        let fee = 4 * E24 / 100;
+        callback_env(&mut ctx);
+        ctr.transfer_staked_callback(u1.clone(), 0, (4 * E24 - fee).into(), fee.into());
+        assert_eq!(
+            events(),
+            vec![serde_json::json!({
+                "standard": EVENT_STANDARD,
+                "version": EVENT_STANDARD_VERSION,
+                "event": "fee_collected",
+                "data": [{"token": t_s1.to_string(), "amount": fee.to_string()}],
+            })]
+        );
+
+        testing_env!(ctx.predecessor_account_id(u1.clone()).build());
+        ctr.withdraw_fees();
+        assert!(events().is_empty());
+        callback_env(&mut ctx);
+        ctr.withdraw_fees_callback(0, fee.into(), accounts(1));
+        assert_eq!(
+            events(),
+            vec![serde_json::json!({
+                "standard": EVENT_STANDARD,
+                "version": EVENT_STANDARD_VERSION,
+                "event": "fee_withdrawn",
+                "data": [{
+                    "token": t_s1.to_string(),
+                    "amount": fee.to_string(),
+                    "treasury": accounts(1).to_string(),
+                }],
+            })]
+        );
+    }
+
+    #[test]
+    fn test_set_treasury() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        let new_treasury: AccountId = "treasury2".parse().unwrap();
+        ctr.set_treasury(new_treasury.clone());
+        assert_eq!(ctr.treasury, new_treasury);
+
+        ctr.fee_collected[0] = 10 * E24;
+        ctr.withdraw_fees();
+        assert_eq!(ctr.fee_collected[0], 0);
+
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let transfer = receipts
+            .iter()
+            .find(|r| r.receiver_id == acc_staking1())
+            .expect("ft_transfer receipt not created");
+        match &transfer.actions[0] {
+            near_sdk::mock::VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } => {
+                assert_eq!(function_name, "ft_transfer");
+                let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                assert_eq!(args["receiver_id"], "treasury2");
+            }
+            _ => panic!("expected a function call"),
+        }
+    }
+
+    #[test]
+    fn test_withdraw_fee_token() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        ctr.fee_collected = vec![10 * E24, 3 * E24];
+        ctr.withdraw_fee_token(acc_staking2());
+        assert_eq!(ctr.fee_collected, vec![10 * E24, 0]);
+
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert!(receipts.iter().all(|r| r.receiver_id != acc_staking1()));
+        let transfer = receipts
+            .iter()
+            .find(|r| r.receiver_id == acc_staking2())
+            .expect("ft_transfer receipt not created");
+        match &transfer.actions[0] {
+            near_sdk::mock::VmAction::FunctionCall { args, .. } => {
+                let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                assert_eq!(args["amount"], (3 * E24).to_string());
+            }
+            _ => panic!("expected a function call"),
+        }
+
+        // failed transfer: the fee is recovered and can be retried
+        testing_env!(
+            ctx.predecessor_account_id(env::current_account_id())
+                .build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        ctr.withdraw_fees_callback(1, (3 * E24).into(), accounts(1));
+        assert_eq!(ctr.fee_collected, vec![10 * E24, 3 * E24]);
+        ctr.withdraw_fee_token(acc_staking2());
+        assert_eq!(ctr.fee_collected, vec![10 * E24, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "no fee collected for atom1")]
+    fn test_withdraw_fee_token_zero() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        ctr.withdraw_fee_token(acc_staking1());
+    }
+
+    #[test]
+    fn test_fee_recipients_split() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        let burn: AccountId = "burn".parse().unwrap();
+        ctr.set_fee_recipients(vec![(accounts(1), 7_000), (burn.clone(), 3_000)]);
+
+        // 1001 doesn't split evenly: 700.7 and 300.3, the dust goes to the first recipient
+        ctr.fee_collected = vec![10 * E24, 1001];
+        ctr.withdraw_fees();
+        assert_eq!(ctr.fee_collected, vec![0, 0]);
+
+        let mut transfers: Vec<(AccountId, String, String)> = vec![];
+        for r in near_sdk::test_utils::get_created_receipts() {
+            if let near_sdk::mock::VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } = &r.actions[0]
+            {
+                if function_name == "ft_transfer" {
+                    let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                    transfers.push((
+                        r.receiver_id.clone(),
+                        args["receiver_id"].as_str().unwrap().to_string(),
+                        args["amount"].as_str().unwrap().to_string(),
+                    ));
+                }
+            }
+        }
+        let expected = vec![
+            (
+                acc_staking1(),
+                accounts(1).to_string(),
+                (7 * E24).to_string(),
+            ),
+            (acc_staking1(), burn.to_string(), (3 * E24).to_string()),
+            (acc_staking2(), accounts(1).to_string(), "701".to_string()),
+            (acc_staking2(), burn.to_string(), "300".to_string()),
+        ];
+        assert_eq!(transfers, expected);
+    }
+
+    #[test]
+    fn test_fee_recipients_failed_transfer() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        ctr.set_fee_recipients(vec![(accounts(1), 7_000), ("burn".parse().unwrap(), 3_000)]);
+        ctr.fee_collected = vec![10 * E24, 0];
+        ctr.withdraw_fees();
+
+        // only the failed part is recovered
+        testing_env!(
+            ctx.predecessor_account_id(env::current_account_id())
+                .build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        ctr.withdraw_fees_callback(0, (3 * E24).into(), "burn".parse().unwrap());
+        assert_eq!(ctr.fee_collected, vec![3 * E24, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "fee recipient shares must sum to 10000, got 9000")]
+    fn test_fee_recipients_wrong_total() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        ctr.set_fee_recipients(vec![(accounts(1), 6_000), ("burn".parse().unwrap(), 3_000)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_set_treasury_not_owner() {
+        let (_, mut ctr) = setup_contract(acc_u1(), 0, 0);
+        ctr.set_treasury(acc_u1());
+    }
+
+    fn finalize(ctr: &mut Contract) {
+        ctr._setup_deposit(&acc_cheddar().into(), 20 * E24);
+        ctr._setup_deposit(&acc_farming2().into(), 10 * E24);
+        ctr.finalize_setup();
+    }
+
+    #[test]
+    fn test_expected_deposit_rounds_up() {
+        let mut ctx = VMContextBuilder::new();
+        testing_env!(ctx.build());
+        let mut ctr = Contract::new(
+            acc_owner(),
+            vec![acc_staking1(), acc_staking2()],
+            to_U128s(&vec![E24, E24 / 10]),
+            (RATE + 1).into(),
+            vec![acc_cheddar(), acc_farming2()],
+            to_U128s(&vec![E24, E24 / 3]),
+            round(0) / SECOND,
+            round(END) / SECOND,
+            acc_nft_cheddy(),
+            BOOST,
+            0,
+            accounts(1),
+        );
+        // (20e24 + 10) * (1e24 / 3) / 1e24 = 20 * (1e24 / 3) + 3.33 farming2 tokens are emitted
+        let expected = 20 * (E24 / 3) + 4;
+        assert_eq!(
+            ctr.finalize_setup_expected().0,
+            vec![U128(20 * E24 + 10), U128(expected)]
+        );
+        ctr._setup_deposit(&acc_cheddar(), 20 * E24 + 10);
+        ctr._setup_deposit(&acc_farming2(), expected);
+        ctr.finalize_setup();
+
+        let users = [acc_u1(), acc_u2(), acc_u3()];
+        for (i, u) in users.iter().enumerate() {
+            let stake = (i as u128 + 1) * E24;
+            register_user_and_stake(&mut ctx, &mut ctr, u, &vec![stake, 10 * stake], -1);
+        }
+        for r in [3, 7, END] {
+            for u in users.iter() {
+                testing_env!(ctx
+                    .predecessor_account_id(u.clone())
+                    .block_timestamp(round(r))
+                    .build());
+                ctr.withdraw_crop();
+            }
+        }
+        assert!(ctr.total_harvested[0] <= ctr.farm_deposits[0]);
+        assert!(ctr.total_harvested[1] <= ctr.farm_deposits[1]);
+    }
+
+    #[test]
+    fn test_finalize_setup() {
+        let (_, mut ctr) = setup_contract(accounts(1), 0, 0);
+        assert_eq!(
+            ctr.setup_finalized, false,
+            "at the beginning setup mut not be finalized"
+        );
+        finalize(&mut ctr);
+        assert_eq!(ctr.setup_finalized, true)
+    }
+
+    #[test]
+    #[should_panic(expected = "must be finalized at last before farm start")]
+    fn test_finalize_setup_too_late() {
+        let (mut ctx, mut ctr) = setup_contract(accounts(1), 0, 0);
+        ctr._setup_deposit(&acc_cheddar().into(), 20 * E24);
+        ctr._setup_deposit(&acc_farming2().into(), 10 * E24);
+        testing_env!(ctx.block_timestamp(10 * ROUND_NS).build());
+        ctr.finalize_setup();
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected deposit for token cheddar1 is 20000000000000000000000000")]
+    fn test_finalize_setup_wrong_deposit() {
+        let (_, mut ctr) = setup_contract(accounts(1), 0, 0);
+        ctr._setup_deposit(&acc_cheddar().into(), 10 * E24);
+    }
+
+    #[test]
+    fn test_setup_deposit_exact_without_tolerance() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        assert_eq!(ctr.deposit_tolerance_bp, 0);
+        finalize(&mut ctr);
+        assert_eq!(ctr.farm_deposit_shortfalls, vec![0, 0]);
+        assert_eq!(ctr.farm_token_rates, vec![E24, E24 / 2]);
+    }
+
+    #[test]
+    fn test_setup_deposit_within_tolerance() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        ctr.set_deposit_tolerance(100); // 1%
+        // 0.5% fee charged on the cheddar transfer
+        ctr._setup_deposit(&acc_cheddar(), 20 * E24 * 995 / 1000);
+        ctr._setup_deposit(&acc_farming2(), 10 * E24);
+        ctr.finalize_setup();
+        assert_eq!(ctr.farm_deposit_shortfalls, vec![20 * E24 / 200, 0]);
+        assert_eq!(ctr.farm_token_rates, vec![E24 * 995 / 1000, E24 / 2]);
+
+        // all rounds farmed by one user: the payout doesn't exceed the deposit
+        let u1 = acc_u1();
+        register_user_and_stake(&mut ctx, &mut ctr, &u1, &vec![E24, E24], -2);
+        testing_env!(ctx
+            .attached_deposit(0)
+            .predecessor_account_id(u1.clone())
+            .block_timestamp(round(END + 1))
+            .build());
+        ctr.withdraw_crop();
+        assert_eq!(ctr.total_harvested, ctr.farm_deposits);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected deposit for token cheddar1 is 20000000000000000000000000")]
+    fn test_setup_deposit_exceeding_tolerance() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        ctr.set_deposit_tolerance(100);
+        ctr._setup_deposit(&acc_cheddar(), 20 * E24 * 98 / 100);
+    }
+
+    #[test]
+    fn test_setup_deposit_already_done() {
+        let (mut ctx, mut ctr) = setup_contract(accounts(1), 0, 0);
+        assert!(!ctr.is_deposit_done(acc_cheddar()));
+
+        testing_env!(ctx.predecessor_account_id(acc_cheddar()).build());
+        let msg = "setup reward deposit".to_string();
+        let res = ctr.ft_on_transfer(acc_owner(), (20 * E24).into(), msg.clone());
+        assert!(matches!(res, PromiseOrValue::Value(U128(0))));
+        assert!(ctr.is_deposit_done(acc_cheddar()));
+        assert!(!ctr.is_deposit_done(acc_farming2()));
+
+        // a retried deposit is refunded and doesn't change the recorded one
+        let res = ctr.ft_on_transfer(acc_owner(), (19 * E24).into(), msg);
+        assert!(matches!(res, PromiseOrValue::Value(U128(a)) if a == 19 * E24));
+        assert_eq!(ctr.farm_deposits, vec![20 * E24, 0]);
+        assert_eq!(
+            ctr._setup_deposit(&acc_cheddar(), 20 * E24),
+            SetupDepositResult::AlreadyDone
+        );
+        assert!(ctr.is_deposit_done(acc_cheddar()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Deposit for token cheddar2 not done")]
+    fn test_finalize_setup_not_enough_deposit() {
+        let (_, mut ctr) = setup_contract(accounts(1), 0, 0);
+        ctr._setup_deposit(&acc_cheddar().into(), 20 * E24);
+        ctr.finalize_setup();
+    }
+
+    #[test]
+    fn test_round_number() {
+        let (mut ctx, ctr) = setup_contract(acc_u1(), 0, 0);
+        assert_eq!(ctr.current_round(), 0);
+
+        assert_eq!(round(-9), ROUND_NS);
+        assert_eq!(ctr.farming_start, 10 * ROUND);
+
+        testing_env!(ctx.block_timestamp(round(-2)).build());
+        assert_eq!(ctr.current_round(), 0);
+
+        testing_env!(ctx.block_timestamp(round(0)).build());
+        assert_eq!(ctr.current_round(), 0);
+
+        assert_eq!(round(1), 11 * ROUND_NS);
 
         testing_env!(ctx.block_timestamp(round(1)).build());
         assert_eq!(ctr.current_round(), 1);
@@ -947,6 +2755,27 @@ mod tests {
         assert_eq!(total_rounds, 10);
     }
 
+    #[test]
+    fn test_get_farm_progress() {
+        let (mut ctx, ctr) = setup_contract(acc_u1(), 0, 0);
+        // pre-start
+        assert_eq!(ctr.get_farm_progress(), (0, 10, 10));
+        testing_env!(ctx.block_timestamp(round(0)).build());
+        assert_eq!(ctr.get_farm_progress(), (0, 10, 10));
+
+        // mid-farm
+        testing_env!(ctx.block_timestamp(round(1)).build());
+        assert_eq!(ctr.get_farm_progress(), (1, 10, 9));
+        testing_env!(ctx.block_timestamp(round(4) + ROUND_NS / 2).build());
+        assert_eq!(ctr.get_farm_progress(), (4, 10, 6));
+
+        // post-end
+        testing_env!(ctx.block_timestamp(round(10)).build());
+        assert_eq!(ctr.get_farm_progress(), (10, 10, 0));
+        testing_env!(ctx.block_timestamp(round(25)).build());
+        assert_eq!(ctr.get_farm_progress(), (10, 10, 0));
+    }
+
     #[test]
     #[should_panic(
         expected = "The attached deposit is less than the minimum storage balance (60000000000000000000000)"
@@ -995,6 +2824,34 @@ mod tests {
         assert_eq!(a1.stake.0, 2 * E24 / 10, "u1 should have staked units!");
     }
 
+    #[test]
+    fn test_min_stake_zero_rate() {
+        let staked = vec![E24, 5 * E24];
+        let decimals = [24, 24];
+        assert_eq!(min_stake(&staked, &vec![E24, E24], &decimals), E24);
+        // a zero rate token doesn't limit the stake
+        assert_eq!(min_stake(&staked, &vec![0, E24], &decimals), 5 * E24);
+        assert_eq!(
+            min_stake(&vec![0, 5 * E24], &vec![0, E24], &decimals),
+            5 * E24
+        );
+        assert_eq!(min_stake(&staked, &vec![0, 0], &decimals), 0);
+
+        let u1 = acc_u1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        ctr.admin_set_rates(to_U128s(&vec![E24, 0]));
+        finalize(&mut ctr);
+        register_user_and_stake(&mut ctx, &mut ctr, &u1, &vec![2 * E24, E24], 2);
+        assert_eq!(ctr.status(u1).unwrap().stake.0, 2 * E24);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one stake rate must be positive")]
+    fn test_set_zero_stake_rates() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, 0);
+        ctr.admin_set_rates(to_U128s(&vec![0, 0]));
+    }
+
     #[test]
     fn test_alone_staking() {
         let u1 = acc_u1();
@@ -1432,6 +3289,56 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_nft_on_transfer_results() {
+        let u1 = acc_u1();
+        let (mut ctx, mut ctr) = setup_contract(u1.clone(), 0, 0);
+        finalize(&mut ctr);
+        let returned = |r: PromiseOrValue<bool>| matches!(r, PromiseOrValue::Value(true));
+
+        // not a Cheddy NFT
+        testing_env!(ctx.predecessor_account_id(acc_staking1()).build());
+        assert!(returned(ctr.nft_on_transfer(
+            u1.clone(),
+            u1.clone(),
+            "1".into(),
+            "".into()
+        )));
+        // not registered
+        testing_env!(ctx.predecessor_account_id(acc_nft_cheddy()).build());
+        assert!(returned(ctr.nft_on_transfer(
+            u1.clone(),
+            u1.clone(),
+            "1".into(),
+            "".into()
+        )));
+
+        // boosted stake
+        register_user_and_stake(&mut ctx, &mut ctr, &u1, &vec![E24, 10 * E24], -2);
+        let stake = ctr.get_vault(&u1).min_stake;
+        testing_env!(ctx.predecessor_account_id(acc_nft_cheddy()).build());
+        assert!(!returned(ctr.nft_on_transfer(
+            u1.clone(),
+            u1.clone(),
+            "1".into(),
+            "".into()
+        )));
+        let v = ctr.get_vault(&u1);
+        assert_eq!(v.cheddy, "1");
+        assert_eq!(v.min_stake, stake + stake * u128::from(BOOST) / BASIS_P);
+        assert_eq!(ctr.staked_units, v.min_stake);
+
+        // second Cheddy
+        assert!(returned(ctr.nft_on_transfer(
+            u1.clone(),
+            u1.clone(),
+            "2".into(),
+            "".into()
+        )));
+        assert_eq!(ctr.get_vault(&u1).cheddy, "1");
+        assert_eq!(ctr.staked_units, v.min_stake);
+    }
+
     /*
     #[test]
     fn test_staking_few_users() {
@@ -62,6 +62,19 @@ impl Vault {
         self.reward_acc = reward_acc;
     }
 
+    /// Same as `ping` for a vault capped by `Contract.max_units_per_round_per_user`: the
+    /// vault farms the cap in each round, so `capped_acc` is the cap times the `round`.
+    pub fn ping_capped(&mut self, capped_acc: Balance, round: u64) {
+        if round == 0 {
+            return;
+        }
+        if self.reward_acc >= capped_acc {
+            return;
+        }
+        self.farmed += capped_acc - self.reward_acc;
+        self.reward_acc = capped_acc;
+    }
+
     #[inline]
     pub fn is_empty(&self) -> bool {
         check_all_zeros(&self.staked) && self.farmed == 0 && self.cheddy.is_empty()
@@ -76,10 +89,24 @@ impl Contract {
         self.vaults.get(account_id).expect(ERR10_NO_ACCOUNT)
     }
 
-    pub(crate) fn ping_all(&mut self, v: &mut Vault) {
+    pub(crate) fn ping_all(&mut self, a: &AccountId, v: &mut Vault) {
         let r = self.current_round();
         self.update_reward_acc(r);
-        v.ping(self.reward_acc, r);
+        match self.capped_acc(a, v, r) {
+            Some(acc) => v.ping_capped(acc, r),
+            None => v.ping(self.reward_acc, r),
+        }
+    }
+
+    /// Returns the farm units farmed by a capped vault up to `round`, or `None` if the vault
+    /// of `a` is not capped by `max_units_per_round_per_user`.
+    pub(crate) fn capped_acc(&self, a: &AccountId, v: &Vault, round: u64) -> Option<u128> {
+        let cap = self.max_units_per_round_per_user?;
+        if self.capped_stakes.contains_key(&(v.min_stake, a.clone())) {
+            Some(cap * u128::from(round))
+        } else {
+            None
+        }
     }
 
     /// updates the rewards accumulator
@@ -87,32 +114,194 @@ impl Contract {
         let new_acc = self.compute_reward_acc(round);
         // we should advance with rounds if self.t is zero, otherwise we have a jump and
         // don't compute properly the accumulator.
-        if self.staked_units == 0 || new_acc != self.reward_acc {
-            self.reward_acc = new_acc;
-            self.reward_acc_round = round;
+        if self.uncapped_units() == 0 || new_acc != self.reward_acc {
+            self.set_reward_acc(new_acc, round);
         }
     }
 
-    /// computes the rewards accumulator.
+    /// Sets the rewards accumulator and records a checkpoint when the round advanced.
+    /// When `MAX_REWARD_ACC_CHECKPOINTS` are kept, the oldest checkpoint is overwritten.
+    pub(crate) fn set_reward_acc(&mut self, reward_acc: u128, round: u64) {
+        if round != self.reward_acc_round {
+            let i = self.reward_acc_checkpoints_count % MAX_REWARD_ACC_CHECKPOINTS;
+            if i < self.reward_acc_checkpoints.len() {
+                self.reward_acc_checkpoints.replace(i, &(round, reward_acc));
+            } else {
+                self.reward_acc_checkpoints.push(&(round, reward_acc));
+            }
+            self.reward_acc_checkpoints_count += 1;
+        }
+        self.reward_acc = reward_acc;
+        self.reward_acc_round = round;
+    }
+
+    /// Returns the `i`-th kept checkpoint, starting from the oldest one.
+    fn reward_acc_checkpoint(&self, i: u64) -> (u64, u128) {
+        let oldest = self.reward_acc_checkpoints_count - self.reward_acc_checkpoints.len();
+        self.reward_acc_checkpoints
+            .get((oldest + i) % MAX_REWARD_ACC_CHECKPOINTS)
+            .unwrap()
+    }
+
+    /// Computes the rewards accumulator at `round`, also for the rounds before the last
+    /// accumulator update. Past rounds are interpolated between the checkpoints around them:
+    /// the stake didn't change in between.
+    pub(crate) fn reward_acc_at(&self, round: u64) -> u128 {
+        if round >= self.reward_acc_round {
+            return self.compute_reward_acc(round);
+        }
+        // index of the first checkpoint after `round`. It exists: the last checkpoint is
+        // at `reward_acc_round`.
+        let (mut lo, mut hi) = (0, self.reward_acc_checkpoints.len() - 1);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.reward_acc_checkpoint(mid).0 > round {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        let (next_round, next_acc) = self.reward_acc_checkpoint(lo);
+        let (prev_round, prev_acc) = if lo > 0 {
+            self.reward_acc_checkpoint(lo - 1)
+        } else {
+            assert!(
+                self.reward_acc_checkpoints_count == self.reward_acc_checkpoints.len(),
+                "rewards accumulator is not known before round {}",
+                next_round
+            );
+            (0, 0)
+        };
+        let acc = U256::from(next_acc - prev_acc) * U256::from(round - prev_round)
+            / U256::from(next_round - prev_round);
+        prev_acc + acc.as_u128()
+    }
+
+    /// computes the rewards accumulator of the vaults which are not capped (see
+    /// `max_units_per_round_per_user`).
     /// NOTE: the current, optimized algorithm will not farm anything if
     ///   `self.rate * ACC_OVERFLOW / self.t < 1`
     pub(crate) fn compute_reward_acc(&self, round: u64) -> u128 {
+        let uncapped = self.uncapped_units();
         // covers also when round == 0
-        if self.reward_acc_round == round || self.staked_units == 0 {
+        if self.reward_acc_round == round || uncapped == 0 {
             return self.reward_acc;
         }
 
         self.reward_acc
-            + u128::from(round - self.reward_acc_round) * self.farm_unit_emission * ACC_OVERFLOW
-                / self.staked_units
+            + u128::from(round - self.reward_acc_round)
+                * self.uncapped_emission()
+                * ACC_OVERFLOW
+                / uncapped
+    }
+
+    /// Stake units of the vaults which are not capped.
+    #[inline]
+    pub(crate) fn uncapped_units(&self) -> Balance {
+        self.staked_units - self.capped_units
+    }
+
+    /// Farm units emitted per round to the vaults which are not capped: the emission without
+    /// the cap farmed by each capped vault.
+    pub(crate) fn uncapped_emission(&self) -> u128 {
+        match self.max_units_per_round_per_user {
+            Some(cap) => self
+                .farm_unit_emission
+                .saturating_sub(cap * u128::from(self.capped_stakes.len())),
+            None => self.farm_unit_emission,
+        }
+    }
+
+    /// Caps (and uncaps) vaults until the capped vaults are exactly the ones which would farm
+    /// more than `cap` per round. Capped vaults farm the cap, and the rest of the emission is
+    /// distributed to the other vaults by stake. The excess emission is not burned: when the
+    /// vaults can't farm the whole emission within the cap, no vault is capped.
+    /// Must be called after the stakes or the emission change.
+    pub(crate) fn apply_cap(&mut self) {
+        let cap = match self.max_units_per_round_per_user {
+            Some(cap) => cap,
+            None => return,
+        };
+        let r = self.current_round();
+        // settle the accumulator, so the past rounds use the old capped vaults
+        self.set_reward_acc(self.compute_reward_acc(r), r);
+        let vaults = u128::from(self.capped_stakes.len() + self.uncapped_stakes.len());
+        let feasible = vaults * cap >= self.farm_unit_emission;
+        loop {
+            let uncapped = self.uncapped_units();
+            // a vault with more stake units than the threshold would farm more than the cap.
+            // `None` if the capped vaults already take the whole emission.
+            let emission = self.uncapped_emission();
+            let threshold = if emission == 0 {
+                None
+            } else {
+                Some((U256::from(cap) * U256::from(uncapped) / U256::from(emission)).as_u128())
+            };
+            if let (Some(key), Some(t)) = (self.uncapped_stakes.max(), threshold) {
+                // the last uncapped vault is not capped: its excess emission would be lost
+                if feasible && key.0 > t && key.0 < uncapped {
+                    self.set_capped(key, true, cap, r);
+                    continue;
+                }
+            }
+            if let Some(key) = self.capped_stakes.min() {
+                if !feasible || uncapped == 0 || !threshold.is_some_and(|t| key.0 > t) {
+                    self.set_capped(key, false, cap, r);
+                    continue;
+                }
+            }
+            return;
+        }
+    }
+
+    /// Moves the vault of the `(stake units, account)` key between the capped and the uncapped
+    /// vaults, settling its rewards up to the round `r` first.
+    fn set_capped(&mut self, key: (Balance, AccountId), capped: bool, cap: u128, r: u64) {
+        let mut v = self.get_vault(&key.1);
+        let capped_acc = cap * u128::from(r);
+        if capped {
+            v.ping(self.reward_acc, r);
+            v.reward_acc = capped_acc;
+            self.uncapped_stakes.remove(&key);
+            self.capped_stakes.insert(&key, &());
+            self.capped_units += key.0;
+        } else {
+            v.ping_capped(capped_acc, r);
+            v.reward_acc = self.reward_acc;
+            self.capped_stakes.remove(&key);
+            self.uncapped_stakes.insert(&key, &());
+            self.capped_units -= key.0;
+        }
+        self.vaults.insert(&key.1, &v);
+    }
+
+    /// Removes the vault of `a` from the capped or the uncapped vaults. The vault must be
+    /// pinged: a capped vault continues with the uncapped rewards accumulator.
+    pub(crate) fn remove_from_cap(&mut self, a: &AccountId, v: &mut Vault) {
+        let key = (v.min_stake, a.clone());
+        if self.capped_stakes.remove(&key).is_some() {
+            self.capped_units -= v.min_stake;
+            v.reward_acc = self.reward_acc;
+        } else {
+            self.uncapped_stakes.remove(&key);
+        }
     }
 
     /// Recomputes stake aggreagator. Must be called after ping_s!
-    pub(crate) fn _recompute_stake(&mut self, v: &mut Vault) {
-        let mut s = min_stake(&v.staked, &self.stake_rates);
+    /// With `max_units_per_round_per_user` set, the vault is saved and the cap is applied to
+    /// all vaults.
+    pub(crate) fn _recompute_stake(&mut self, a: &AccountId, v: &mut Vault) {
+        let mut s = min_stake(&v.staked, &self.stake_rates, &self.stake_decimals);
         if !v.cheddy.is_empty() {
             s += s * u128::from(self.cheddar_nft_boost) / BASIS_P;
         }
+        let capped = self.max_units_per_round_per_user.is_some();
+        if capped {
+            self.remove_from_cap(a, v);
+            if s > 0 {
+                self.uncapped_stakes.insert(&(s, a.clone()), &());
+            }
+        }
         if s > v.min_stake {
             let diff = s - v.min_stake;
             self.staked_units += diff;
@@ -121,6 +310,11 @@ impl Contract {
             self.staked_units -= diff;
         }
         v.min_stake = s;
+        if capped {
+            self.vaults.insert(a, v);
+            self.apply_cap();
+            *v = self.get_vault(a);
+        }
     }
 
     /// Returns new stake units
@@ -135,11 +329,11 @@ impl Contract {
         let mut v = self.get_vault(user);
 
         // firstly update the past rewards
-        self.ping_all(&mut v);
+        self.ping_all(user, &mut v);
 
         v.staked[token_i] += amount;
         self.total_stake[token_i] += amount;
-        self._recompute_stake(&mut v);
+        self._recompute_stake(user, &mut v);
         self.vaults.insert(user, &v);
         log!("Staked {} {}, stake_units: {}", amount, token, v.min_stake);
         return v.min_stake;
@@ -162,11 +356,11 @@ impl Contract {
             return 0;
         }
 
-        self.ping_all(&mut v);
+        self.ping_all(user, &mut v);
         // self.total_stake is updated in transfer_staked_tokens
         let remaining = v.staked[token_i] - amount;
         v.staked[token_i] = remaining;
-        self._recompute_stake(&mut v);
+        self._recompute_stake(user, &mut v);
         self.vaults.insert(user, &v);
         self.transfer_staked_tokens(user.clone(), token_i, amount);
         return remaining;
@@ -174,24 +368,29 @@ impl Contract {
 
     pub(crate) fn _withdraw_nft(&mut self, user: &AccountId, v: &mut Vault, receiver: AccountId) {
         assert!(!v.cheddy.is_empty(), "Sender has no NFT deposit");
-        self.ping_all(v);
+        self.ping_all(user, v);
+        self.transfer_cheddy(user, receiver, v.cheddy.clone());
+        v.cheddy = "".into();
+        self._recompute_stake(user, v);
+    }
+
+    /// Transfers the Cheddy NFT of the user to the receiver. The vault must be updated by
+    /// the caller. The callback will recover the NFT to the user vault if the transfer fails.
+    pub(crate) fn transfer_cheddy(&mut self, user: &AccountId, receiver: AccountId, cheddy: String) {
         ext_nft::ext(self.cheddar_nft.clone())
             .with_attached_deposit(ONE_YOCTO)
             .with_static_gas(GAS_FOR_NFT_TRANSFER)
             .nft_transfer(
                 receiver,
-                v.cheddy.clone(),
+                cheddy.clone(),
                 None,
                 Some("Cheddy withdraw".to_string()),
             )
             .then(
                 ext_self::ext(env::current_account_id())
                     .with_static_gas(GAS_FOR_MINT_CALLBACK)
-                    .withdraw_nft_callback(user.clone(), v.cheddy.clone()),
+                    .withdraw_nft_callback(user.clone(), cheddy),
             );
-
-        v.cheddy = "".into();
-        self._recompute_stake(v);
     }
 }
 
@@ -202,11 +401,14 @@ impl FungibleTokenReceiver for Contract {
     FungibleTokenReceiver implementation Callback on receiving tokens by this contract.
     Handles both farm deposits and stake deposits. For farm deposit (sending tokens
     to setup the farm) you must set "setup reward deposit" msg.
-    Otherwise tokens will be staken.
-    Returns zero.
+    Otherwise tokens will be staken. With "stake for <account_id>" msg the tokens are
+    staked on behalf of <account_id>, otherwise on behalf of the sender. The "stake for"
+    msg is only accepted from `Contract.trusted_farms`.
+    Returns zero. Tokens which are not stake tokens, and "stake for" deposits from an
+    untrusted sender, are refunded.
     Panics when:
     - account is not registered
-    - or receiving a wrong token
+    - or receiving a wrong farm deposit token
     - or making a farm deposit after farm is finalized
     - or staking before farm is finalized. */
     #[allow(unused_variables)]
@@ -225,10 +427,37 @@ impl FungibleTokenReceiver for Contract {
         );
         assert!(amount.0 > 0, "staked amount must be positive");
         if msg == "setup reward deposit" {
-            self._setup_deposit(&token, amount.0);
+            if self._setup_deposit(&token, amount.0) == SetupDepositResult::AlreadyDone {
+                return PromiseOrValue::Value(amount);
+            }
         } else {
-            self.assert_is_active();
-            self._stake(&sender_id, &token, amount.0);
+            self.assert_accepts_stakes();
+            if !self.stake_tokens.contains(&token) {
+                log!(
+                    "{} is not a stake token, refunding {} to @{}",
+                    token,
+                    amount.0,
+                    sender_id
+                );
+                return PromiseOrValue::Value(amount);
+            }
+            // stake on behalf of another account, eg. when compounding from another farm
+            let user = match msg.strip_prefix(STAKE_FOR_MSG_PREFIX) {
+                Some(account_id) => {
+                    if !self.trusted_farms.contains(&sender_id) {
+                        log!(
+                            "stake for is not accepted from @{}, refunding {} {}",
+                            sender_id,
+                            amount.0,
+                            token
+                        );
+                        return PromiseOrValue::Value(amount);
+                    }
+                    account_id.parse().expect("invalid stake for account")
+                }
+                None => sender_id,
+            };
+            self._stake(&user, &token, amount.0);
         }
 
         return PromiseOrValue::Value(U128(0));
@@ -312,13 +541,28 @@ fn storage_balance() -> StorageBalance {
     }
 }
 
-pub fn min_stake(staked: &Vec<u128>, stake_rates: &Vec<u128>) -> Balance {
-    let mut min = std::u128::MAX;
+/// Converts `amount` of a token with `decimals` to 24 decimals.
+pub fn to_e24(amount: Balance, decimals: u8) -> Balance {
+    amount * 10u128.pow(24 - u32::from(decimals))
+}
+
+/// Returns the min of the staked amounts weighted by `stake_rates`.
+/// Tokens with a zero rate are skipped: they can be staked, but they don't limit farming.
+/// Returns 0 if no rate is positive.
+pub fn min_stake(staked: &Vec<u128>, stake_rates: &Vec<u128>, decimals: &[u8]) -> Balance {
+    let mut min = u128::MAX;
     for (i, rate) in stake_rates.iter().enumerate() {
-        let s = safe_mul(staked[i], *rate);
+        if *rate == 0 {
+            continue;
+        }
+        let s = safe_mul(to_e24(staked[i], decimals[i]), *rate);
         if s < min {
             min = s;
         }
     }
-    return min;
+    if min == u128::MAX {
+        0
+    } else {
+        min
+    }
 }
@@ -67,6 +67,12 @@ pub mod helpers {
         (U256::from(units) * U256::from(rate) / e24_big).as_u128()
     }
 
+    /// Same as `safe_mul`, but rounds up.
+    pub fn safe_mul_ceil(units: Balance, rate: Balance) -> Balance {
+        let e24_big: U256 = U256::from(E24);
+        ((U256::from(units) * U256::from(rate) + e24_big - 1) / e24_big).as_u128()
+    }
+
     #[allow(non_snake_case)]
     pub fn to_U128s(v: &Vec<Balance>) -> Vec<U128> {
         v.iter().map(|x| U128::from(*x)).collect()
@@ -86,7 +92,12 @@ pub mod helpers {
     }
 
     /// computes round number based on timestamp in seconds
-    pub fn round_number(start: u64, end: u64, mut now: u64) -> u64 {
+    pub fn round_number(start: u64, end: u64, now: u64) -> u64 {
+        round_number_with_len(start, end, now, ROUND)
+    }
+
+    /// computes round number based on timestamp in seconds, for rounds of `round_len` seconds
+    pub fn round_number_with_len(start: u64, end: u64, mut now: u64, round_len: u64) -> u64 {
         if now < start {
             return 0;
         }
@@ -95,13 +106,23 @@ pub mod helpers {
         if now >= end {
             now = end;
             // if at the end of farming we don't start a new round then we need to force a new round
-            if now % ROUND != 0 {
+            if now % round_len != 0 {
                 adjust = 1
             };
         }
-        let r: u64 = ((now - start) / ROUND).try_into().unwrap();
+        let r: u64 = ((now - start) / round_len).try_into().unwrap();
         r + adjust
     }
+
+    /// rounds up a timestamp in seconds to the next multiple of `round_len` seconds
+    pub fn align_to_round(ts: u64, round_len: u64) -> u64 {
+        let rem = ts % round_len;
+        if rem == 0 {
+            ts
+        } else {
+            ts + round_len - rem
+        }
+    }
 }
 
 pub mod interfaces {
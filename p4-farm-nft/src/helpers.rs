@@ -13,6 +13,31 @@ pub(crate) type NftContractId = AccountId;
 /// `contract_id@token_id` pair
 pub(crate) type ContractNftTokenId = String;
 
+/// Max round duration (in seconds) which can be set at init: 7 days.
+pub(crate) const MAX_ROUND_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+/// Max number of farm tokens. Every harvest (and `close`) fires a transfer with a callback
+/// for each farm token, so all of them must fit in a single transaction gas limit, along with
+/// the `NFT_UNITS_MAX_TRANSFER_NUM` NFT transfers done on close.
+pub(crate) const MAX_FARM_TOKENS: usize = 5;
+
+/// Gas used by a harvest call itself (vault ping and state updates), on top of the farmed
+/// token transfers and their callbacks (see `Contract::estimate_harvest_gas`).
+pub(crate) const GAS_FOR_HARVEST_BASE: Gas = Gas(20 * TGAS.0);
+
+/// Number of consecutive failed farmed token transfers after which the token is reported as
+/// stuck for the account (see `get_stuck_farm_tokens`).
+pub(crate) const MAX_FARMED_TRANSFER_FAILURES: u32 = 3;
+
+/// Version of the `P4ContractParams` shape. Must be bumped whenever the struct changes.
+pub(crate) const CONTRACT_PARAMS_VERSION: u8 = 4;
+
+/// Weight of an NFT which has no rarity weight set by the owner.
+pub(crate) const DEFAULT_NFT_WEIGHT: Balance = 1;
+/// Max rarity weight of an NFT. Keeps the summed weights (in e24 stake units) far from
+/// overflowing.
+pub(crate) const MAX_NFT_WEIGHT: Balance = 1_000;
+
 /// NFT Delimeter
 /// Using Paras-HQ standarts `NFT_DELIMETER` for `ContractNftTokenId` format
 /// https://github.com/ParasHQ/paras-nft-farming-contract/blob/f762be16bc68a9c0da2c0ba30fbf555d78162074/ref-farming/src/utils.rs#L20
@@ -29,11 +54,27 @@ pub fn expected_cheddar_stake(num_nfts: usize, cheddar_rate: Balance) -> Balance
     }
 }
 
-pub fn find_token_idx(token: &TokenId, token_v: &Vec<TokenId>) -> usize {
-    token_v
-        .iter()
-        .position(|x| x == token)
-        .expect("invalid token")
+/// Returns the number of farm units needed to harvest at least `amount` tokens of a farm
+/// token with the given `rate`. Inverse of `safe_mul`, rounded up.
+pub fn units_for_amount(amount: Balance, rate: Balance) -> Balance {
+    let rate_big = U256::from(rate);
+    ((U256::from(amount) * U256::from(E24) + rate_big - 1) / rate_big).as_u128()
+}
+
+/// Returns `a + b`. Panics on overflow, naming the updated `value`.
+pub fn add_or_panic(a: Balance, b: Balance, value: &str) -> Balance {
+    match a.checked_add(b) {
+        Some(balance) => balance,
+        None => panic!("Math overflow while increasing {}", value),
+    }
+}
+
+/// Returns `a - b`. Panics on underflow, naming the updated `value`.
+pub fn sub_or_panic(a: Balance, b: Balance, value: &str) -> Balance {
+    match a.checked_sub(b) {
+        Some(balance) => balance,
+        None => panic!("Math underflow while decreasing {}", value),
+    }
 }
 
 pub fn extract_contract_token_ids(
@@ -49,16 +90,33 @@ pub fn extract_contract_token_ids(
     (nft_contract_id, token_id)
 }
 
-pub fn min_stake(staked: &Vec<TokenIds>, stake_rates: &Vec<u128>) -> Balance {
-    let mut min = std::u128::MAX;
+/// Computes stake units based on the summed NFT weights staked for each NFT contract.
+/// A regular NFT has weight 1, so without rarity weights this is the number of staked tokens.
+/// NFT contracts with zero stake rate (disabled) are not taken into account.
+/// Returns 0 if all NFT contracts are disabled.
+pub fn min_stake(staked_weights: &Vec<Balance>, stake_rates: &Vec<u128>) -> Balance {
+    let mut min = u128::MAX;
     for (i, rate) in stake_rates.iter().enumerate() {
-        let staked_tokens: u128 = staked[i].len() as u128 * E24; // Number of NFT tokens for nft_contract[i] as e24
-        let s = safe_mul(staked_tokens, *rate);
+        if *rate == 0 {
+            continue;
+        }
+        let s = safe_mul(weighted_units(staked_weights[i]), *rate);
         if s < min {
             min = s;
         }
     }
-    return min;
+    if min == u128::MAX {
+        return 0;
+    }
+    min
+}
+
+/// Returns the summed NFT `weight` as e24 units. Panics on overflow.
+pub fn weighted_units(weight: Balance) -> Balance {
+    match weight.checked_mul(E24) {
+        Some(units) => units,
+        None => panic!("Math overflow while computing weighted stake units"),
+    }
 }
 
 pub fn check_all_empty(v: &Vec<TokenIds>) -> bool {
@@ -101,6 +159,30 @@ fn test_expected_cheddar() {
 }
 #[cfg(not(target_arch = "wasm32"))]
 #[test]
+fn test_checked_math() {
+    assert_eq!(add_or_panic(2, 3, "x"), 5);
+    assert_eq!(sub_or_panic(3, 3, "x"), 0);
+}
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+#[should_panic(expected = "Math overflow while increasing x")]
+fn test_add_or_panic_overflow() {
+    add_or_panic(Balance::MAX, 1, "x");
+}
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+#[should_panic(expected = "Math underflow while decreasing x")]
+fn test_sub_or_panic_underflow() {
+    sub_or_panic(1, 2, "x");
+}
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
+#[should_panic(expected = "Math overflow while computing weighted stake units")]
+fn test_min_stake_weight_overflow() {
+    min_stake(&vec![Balance::MAX / E24 + 1], &vec![E24]);
+}
+#[cfg(not(target_arch = "wasm32"))]
+#[test]
 fn test_extract_nft_contract_and_token_ids() {
     assert_eq!(
         extract_contract_token_ids(&("nft_contract.near@token_id123".into())),
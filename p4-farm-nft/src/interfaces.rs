@@ -4,7 +4,11 @@ use crate::*;
 #[serde(crate="near_sdk::serde")]
 #[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
 pub struct P4ContractParams {
+    /// version of this struct shape, bumped whenever fields change
+    pub version: u8,
     pub is_active: bool,
+    /// true when the farm deposits are done and the farm is ready
+    pub setup_finalized: bool,
     pub owner_id: AccountId,
     pub stake_tokens: Vec<NftContractId>,
     pub stake_rates: Vec<U128>,
@@ -14,8 +18,20 @@ pub struct P4ContractParams {
     pub farm_deposits: Vec<U128>,
     pub farming_start: u64,
     pub farming_end: u64,
+    /// round duration in seconds
+    pub round_seconds: u64,
+    /// true when rewards accrue pro-rata within a round, false for whole rounds only
+    pub pro_rata_accrual: bool,
+    /// max number of NFTs staked by a single account
+    pub max_staked_per_account: u64,
     /// NFT token used for boost
     pub boost_nft_contracts: Vec<NftContractId>,
+    /// Cheddy NFT contract (one of the `boost_nft_contracts`)
+    pub cheddy: NftContractId,
+    /// boost (in basis points) of a boost NFT
+    pub nft_boost: u32,
+    /// boost (in basis points) of a Cheddy NFT
+    pub cheddy_boost: u32,
     /// total staked is total amount of NFT tokens staked to farm
     pub total_staked: Vec<U128>,
     /// total farmed is total amount of tokens farmed (not necessary minted - which would be
@@ -23,6 +39,10 @@ pub struct P4ContractParams {
     pub total_farmed: Vec<U128>,
     /// total boost is total amount of NFT tokens staked as a boost
     pub total_boost: Vec<U128>,
+    /// total number of NFTs staked across all stake contracts (sum of `total_staked`)
+    pub total_nfts_staked: U128,
+    /// total number of boost NFTs staked across all boost contracts (sum of `total_boost`)
+    pub total_boost_nfts: U128,
     /// Number of accounts currently registered.
     pub accounts_registered: u64,
     /// Cheddar deposits
@@ -30,6 +50,31 @@ pub struct P4ContractParams {
     pub cheddar: AccountId
 }
 
+/// Init arguments of `Contract::new_single_reward`.
+#[derive(Deserialize, Serialize)]
+#[serde(crate="near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, Clone))]
+pub struct SingleRewardInitArgs {
+    pub owner_id: AccountId,
+    pub stake_nft_tokens: Vec<NftContractId>,
+    pub stake_rates: Vec<U128>,
+    /// the only farmed token, one farm unit is one token
+    pub farm_token: AccountId,
+    /// amount of `farm_token` emitted per round
+    pub emission: U128,
+    pub farming_start: u64,
+    pub farming_end: u64,
+    pub boost_nft_contracts: Vec<NftContractId>,
+    pub cheddy: NftContractId,
+    pub nft_boost: u32,
+    pub cheddy_boost: u32,
+    pub cheddar_rate: U128,
+    pub cheddar: AccountId,
+    pub treasury: AccountId,
+    pub align_start_to_round: bool,
+    pub round_seconds: u64,
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(crate="near_sdk::serde")]
 #[cfg_attr(not(target_arch = "wasm32"), derive(Debug, Clone))]
@@ -46,8 +91,89 @@ pub struct P4Status {
     pub farmed_tokens: Vec<U128>,
     /// token ID of a staked NFT boost. Empty if user doesn't stake any required boost NFT.
     pub boost_nfts: TokenId,
-    /// timestamp (in seconds) of the current round.
+    /// timestamp (in seconds) of the round boundary up to which `farmed_units` are computed.
     pub timestamp: u64,
     /// Cheddar stake
     pub total_cheddar_staked: U128
 }
+
+/// Transfers dispatched by `Contract::close`. Transfers are asynchronous - clients
+/// should follow the callbacks / logs to know when they are completed.
+#[derive(Deserialize, Serialize)]
+#[serde(crate="near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, PartialEq))]
+pub struct P4CloseReceipt {
+    /// number of staked NFT transfers
+    pub nft_transfers: u64,
+    /// number of farmed token transfers
+    pub farm_token_transfers: u64,
+    /// number of staked Cheddar transfers
+    pub cheddar_transfers: u64,
+    /// number of boost NFT transfers
+    pub boost_transfers: u64,
+    /// true if the vault was removed
+    pub vault_removed: bool,
+}
+
+/// Result of `Contract::unstake_v2`.
+#[derive(Deserialize, Serialize)]
+#[serde(crate="near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, PartialEq))]
+pub struct UnstakeResult {
+    /// tokens of the unstaked NFT contract still staked
+    pub remaining_tokens: Vec<TokenId>,
+    /// farmed units settled in the vault. When the account is closed, they are withdrawn.
+    pub farmed_units: U128,
+    /// staked Cheddar transferred back (only when the account is closed)
+    pub cheddar_refunded: U128,
+    /// true if the last token was unstaked and the account closed
+    pub account_closed: bool,
+}
+
+/// Raw vault snapshot, as stored (not pinged). See `Contract::get_vault_raw`.
+#[derive(Deserialize, Serialize)]
+#[serde(crate="near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, PartialEq))]
+pub struct VaultView {
+    pub reward_acc: U128,
+    pub staked: Vec<TokenIds>,
+    pub staked_weights: Vec<Vec<U128>>,
+    pub min_stake: U128,
+    pub farmed: U128,
+    pub farmed_recovered: Vec<U128>,
+    pub farmed_transfer_failures: Vec<u32>,
+    pub boost_nft: ContractNftTokenId,
+    pub boost_nft_recovered: Vec<ContractNftTokenId>,
+    pub staked_nft_recovered: Vec<(NftContractId, TokenId)>,
+    pub cheddar_staked: U128,
+    pub staked_since_round: u64,
+    pub last_action_round: u64,
+    pub boost_staked_round: u64,
+    pub stake_removals_applied: u64,
+    pub storage_deposit: U128,
+    pub storage_usage: u64,
+}
+
+impl From<Vault> for VaultView {
+    fn from(v: Vault) -> Self {
+        Self {
+            reward_acc: v.reward_acc.into(),
+            staked: v.staked,
+            staked_weights: v.staked_weights.iter().map(to_U128s).collect(),
+            min_stake: v.min_stake.into(),
+            farmed: v.farmed.into(),
+            farmed_recovered: to_U128s(&v.farmed_recovered),
+            farmed_transfer_failures: v.farmed_transfer_failures,
+            boost_nft: v.boost_nft,
+            boost_nft_recovered: v.boost_nft_recovered,
+            staked_nft_recovered: v.staked_nft_recovered,
+            cheddar_staked: v.cheddar_staked.into(),
+            staked_since_round: v.staked_since_round,
+            last_action_round: v.last_action_round,
+            boost_staked_round: v.boost_staked_round,
+            stake_removals_applied: v.stake_removals_applied,
+            storage_deposit: v.storage_deposit.into(),
+            storage_usage: v.storage_usage,
+        }
+    }
+}
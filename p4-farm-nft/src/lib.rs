@@ -3,11 +3,11 @@ use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::serde::{Deserialize, Serialize};
 
 use near_contract_standards::non_fungible_token::TokenId;
-use near_sdk::collections::LookupMap;
+use near_sdk::collections::{LookupMap, UnorderedSet};
 use near_sdk::json_types::U128;
 use near_sdk::{
-    assert_one_yocto, env, log, near_bindgen, require, AccountId, Balance, PanicOnDefault, Promise,
-    PromiseOrValue, PromiseResult, ONE_YOCTO,
+    assert_one_yocto, env, log, near_bindgen, require, AccountId, Balance, Gas, PanicOnDefault,
+    Promise, PromiseOrValue, PromiseResult, StorageUsage, ONE_YOCTO,
 };
 
 use p3_lib::constants::*;
@@ -17,12 +17,14 @@ use p3_lib::interfaces::*;
 
 pub mod helpers;
 pub mod interfaces;
+pub mod migration;
 pub mod storage_management;
 pub mod token_standards;
 pub mod vault;
 
 use crate::helpers::*;
 use crate::interfaces::*;
+use crate::storage_management::storage_cost;
 use crate::vault::*;
 
 /// Implementing the "Scalable Reward Distribution on the Ethereum Blockchain"
@@ -35,11 +37,16 @@ pub struct Contract {
     pub is_active: bool,
     pub setup_finalized: bool,
     pub owner_id: AccountId,
+    /// Account proposed by the owner to become the new owner. It must call
+    /// `accept_ownership` to complete the transfer.
+    pub pending_owner: Option<AccountId>,
     /// Treasury address - a destination for the collected fees.
     pub treasury: AccountId,
 
     /// user vaults
     pub vaults: LookupMap<AccountId, Vault>,
+    /// registered accounts (accounts with a vault), used to iterate over the vaults.
+    pub accounts: UnorderedSet<AccountId>,
 
     /// Nft contract ids allowed to stake in farm
     pub stake_nft_tokens: Vec<NftContractId>,
@@ -66,6 +73,8 @@ pub struct Contract {
     pub farming_start: u64,
     /// unix timestamp (seconds) when the farming ends (first time with no farming).
     pub farming_end: u64,
+    /// round duration in seconds
+    pub round_seconds: u64,
 
     /// NFT contract(s) used for boost
     pub boost_nft_contracts: Vec<NftContractId>,
@@ -84,8 +93,13 @@ pub struct Contract {
     reward_acc: u128,
     /// round number when the s was previously updated.
     reward_acc_round: u64,
+    /// seconds of `reward_acc_round` already accounted in `reward_acc` (pro-rata accrual).
+    reward_acc_offset: u64,
     /// total amount of currently staked tokens.
     total_stake: Vec<Balance>,
+    /// number of staked NFT transfers back to the users (`transfer_staked_nft`) not resolved
+    /// yet, per stake NFT contract.
+    pending_stake_transfers: Vec<u64>,
     /// total amount of currently staked Cheddar.
     total_cheddar_stake: Balance,
     /// total number of accounts currently registered.
@@ -94,6 +108,50 @@ pub struct Contract {
     pub cheddar_rate: Balance,
     /// Cheddar contract AccountId
     pub cheddar: AccountId,
+    /// Rarity weights of NFTs set by the owner. NFTs without a weight count as `DEFAULT_NFT_WEIGHT`.
+    pub token_weights: LookupMap<(NftContractId, TokenId), Balance>,
+    /// loyalty boost (in basis points) added for each round a vault holds its staked NFTs
+    pub loyalty_boost_bp_per_round: u32,
+    /// max loyalty boost in basis points
+    pub loyalty_boost_cap_bp: u32,
+    /// number of rounds a boost NFT must stay deposited before it can be withdrawn.
+    pub boost_lock_rounds: u64,
+    /// number of rounds after the last NFT stake before the account can unstake (or close).
+    /// 0 disables the cooldown.
+    pub unstake_cooldown_rounds: u64,
+    /// indexes (in `stake_nft_tokens`) of removed stake NFT contracts, in removal order.
+    /// Used to lazily migrate vaults created before a removal.
+    stake_nft_removals: Vec<usize>,
+    /// fee in Cheddar charged from the staked Cheddar on each `unstake` and `close`.
+    pub unstake_fee_cheddar: Balance,
+    /// amount of fee collected (in Cheddar).
+    pub fee_collected: Balance,
+    /// max number of NFTs staked by a single account. Can't be bigger than the number of
+    /// NFTs `close` can transfer in one transaction.
+    pub max_staked_per_account: u64,
+    /// reward multiplier (in basis points) applied to the farm unit emission during the
+    /// rounds `[reward_multiplier_start, reward_multiplier_end)`.
+    pub reward_multiplier_bp: u32,
+    pub reward_multiplier_start: u64,
+    pub reward_multiplier_end: u64,
+    /// partial sum of the vaults `cheddar_staked` of an ongoing
+    /// `recompute_total_cheddar_stake`, and the next account index it expects.
+    cheddar_stake_recompute: (Balance, u64),
+    /// min amount of each farm token transferred on a harvest. Smaller amounts are kept in
+    /// the vault (`farmed_recovered`) until they reach the threshold.
+    pub min_harvest_amount: Vec<u128>,
+    /// accounts allowed to unstake on behalf of users, without the 1 yNEAR deposit
+    /// (see `relayer_unstake`).
+    pub relayers: UnorderedSet<AccountId>,
+    /// round when the contract was paused with `set_active(false)`. Rounds (and so the
+    /// emission) are frozen at this round until the contract is resumed.
+    pub paused_at_round: Option<u64>,
+    /// timestamp (in seconds) of the last `set_active(false)`, valid while `paused_at_round`
+    /// is set.
+    pub paused_at: u64,
+    /// when true, rewards accrue with the seconds staked within a round (pro-rata accrual),
+    /// rather than with whole rounds only.
+    pub pro_rata_accrual: bool,
 }
 
 #[near_bindgen]
@@ -105,6 +163,9 @@ impl Contract {
     /// * `fee_rate`: the Contract.fee parameter (in basis points)
     /// * `cheddar_rate`: charge from stakers per 1 NFT token in Cheddar
     /// * `cheddar`     : Cheddar token account
+    /// * `align_start_to_round`: if true, `farming_start` is rounded up to the next multiple
+    ///   of the round duration. The effective start is returned by `get_contract_params`.
+    /// * `round_seconds`: round duration in seconds. `ROUND` is the default.
     /// The farm starts desactivated. To activate, you must send required farming deposits and
     /// call `self.finalize_setup()`.
     #[init]
@@ -124,13 +185,31 @@ impl Contract {
         cheddar_rate: U128,
         cheddar: AccountId,
         treasury: AccountId,
+        align_start_to_round: bool,
+        round_seconds: u64,
     ) -> Self {
+        assert!(
+            round_seconds > 0 && round_seconds <= MAX_ROUND_SECONDS,
+            "round_seconds must be between 1 and {}",
+            MAX_ROUND_SECONDS
+        );
+        let farming_start = if align_start_to_round {
+            align_to_round(farming_start, round_seconds)
+        } else {
+            farming_start
+        };
         assert!(
             farming_start > env::block_timestamp() / SECOND,
             "start must be in the future"
         );
         assert!(farming_end > farming_start, "End must be after start");
         assert!(cheddar_rate > U128(0), "cheddar_rate should be positive");
+        assert!(
+            farm_tokens.len() <= MAX_FARM_TOKENS,
+            "at most {} farm tokens are supported, got {}",
+            MAX_FARM_TOKENS,
+            farm_tokens.len()
+        );
 
         let stake_len = stake_nft_tokens.len();
         let farm_len = farm_tokens.len();
@@ -140,8 +219,10 @@ impl Contract {
             is_active: true,
             setup_finalized: false,
             owner_id,
+            pending_owner: None,
             treasury,
             vaults: LookupMap::new(b"v".to_vec()),
+            accounts: UnorderedSet::new(b"a".to_vec()),
             stake_nft_tokens,
             staked_units: 0,
             stake_rates: stake_rates.iter().map(|x| x.0).collect(),
@@ -151,6 +232,7 @@ impl Contract {
             farm_deposits: vec![0; farm_len],
             farming_start,
             farming_end,
+            round_seconds,
             boost_nft_contracts,
             cheddy,
             total_boost: vec![0; boost_len],
@@ -159,16 +241,61 @@ impl Contract {
             total_harvested: vec![0; farm_len],
             reward_acc: 0,
             reward_acc_round: 0,
+            reward_acc_offset: 0,
             total_stake: vec![0; stake_len],
+            pending_stake_transfers: vec![0; stake_len],
             total_cheddar_stake: 0,
             accounts_registered: 0,
             cheddar_rate: cheddar_rate.0,
             cheddar,
+            token_weights: LookupMap::new(b"w".to_vec()),
+            loyalty_boost_bp_per_round: 0,
+            loyalty_boost_cap_bp: 0,
+            boost_lock_rounds: 0,
+            unstake_cooldown_rounds: 0,
+            stake_nft_removals: Vec::new(),
+            unstake_fee_cheddar: 0,
+            fee_collected: 0,
+            max_staked_per_account: NFT_UNITS_MAX_TRANSFER_NUM as u64,
+            reward_multiplier_bp: BASIS_P as u32,
+            reward_multiplier_start: 0,
+            reward_multiplier_end: 0,
+            cheddar_stake_recompute: (0, 0),
+            min_harvest_amount: vec![0; farm_len],
+            relayers: UnorderedSet::new(b"r".to_vec()),
+            paused_at_round: None,
+            paused_at: 0,
+            pro_rata_accrual: false,
         };
         c.check_vectors();
         c
     }
 
+    /// Same as `new`, for a farm with a single reward token: `args.farm_token` is emitted at
+    /// `args.emission` tokens per round (one farm unit is one token).
+    #[init]
+    pub fn new_single_reward(args: SingleRewardInitArgs) -> Self {
+        Self::new(
+            args.owner_id,
+            args.stake_nft_tokens,
+            args.stake_rates,
+            args.emission,
+            vec![args.farm_token],
+            vec![U128(E24)],
+            args.farming_start,
+            args.farming_end,
+            args.boost_nft_contracts,
+            args.cheddy,
+            args.nft_boost,
+            args.cheddy_boost,
+            args.cheddar_rate,
+            args.cheddar,
+            args.treasury,
+            args.align_start_to_round,
+            args.round_seconds,
+        )
+    }
+
     fn check_vectors(&self) {
         let fl = self.farm_tokens.len();
         let sl = self.stake_nft_tokens.len();
@@ -176,11 +303,19 @@ impl Contract {
         assert!(
             fl == self.farm_token_rates.len()
                 && fl == self.total_harvested.len()
-                && fl == self.farm_deposits.len(),
+                && fl == self.farm_deposits.len()
+                && fl == self.min_harvest_amount.len(),
             "farm token vector length is not correct"
         );
+        // a zero rate farm token would never be farmed nor accept its setup deposit
+        assert!(
+            self.farm_token_rates.iter().all(|r| *r > 0),
+            "farm token rates must be positive"
+        );
         assert!(
-            sl == self.stake_rates.len() && sl == self.total_stake.len(),
+            sl == self.stake_rates.len()
+                && sl == self.total_stake.len()
+                && sl == self.pending_stake_transfers.len(),
             "stake token vector length is not correct"
         );
         assert!(
@@ -196,6 +331,7 @@ impl Contract {
     /// Returns amount of staked NEAR and farmed CHEDDAR of given account.
     pub fn get_contract_params(&self) -> P4ContractParams {
         P4ContractParams {
+            version: CONTRACT_PARAMS_VERSION,
             owner_id: self.owner_id.clone(),
             stake_tokens: self.stake_nft_tokens.clone(),
             stake_rates: to_U128s(&self.stake_rates),
@@ -204,25 +340,46 @@ impl Contract {
             farm_token_rates: to_U128s(&self.farm_token_rates),
             farm_deposits: to_U128s(&self.farm_deposits),
             is_active: self.is_active,
+            setup_finalized: self.setup_finalized,
             farming_start: self.farming_start,
             farming_end: self.farming_end,
+            round_seconds: self.round_seconds,
+            pro_rata_accrual: self.pro_rata_accrual,
+            max_staked_per_account: self.max_staked_per_account,
             boost_nft_contracts: self.boost_nft_contracts.clone(),
+            cheddy: self.cheddy.clone(),
+            nft_boost: self.nft_boost,
+            cheddy_boost: self.cheddy_boost,
             total_staked: to_U128s(&self.total_stake),
             total_farmed: to_U128s(&self.total_harvested),
             total_boost: to_U128s(&self.total_boost),
+            total_nfts_staked: U128(self.total_stake.iter().sum()),
+            total_boost_nfts: U128(self.total_boost.iter().sum()),
             accounts_registered: self.accounts_registered,
             cheddar_rate: U128(self.cheddar_rate),
             cheddar: self.cheddar.clone(),
         }
     }
 
+    /// Returns the contract crate version.
+    pub fn contract_version(&self) -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+
     pub fn status(&self, account_id: AccountId) -> Option<P4Status> {
         return match self.vaults.get(&account_id) {
             Some(mut v) => {
+                self.sync_vault(&mut v);
                 let r = self.current_round();
-                v.ping(self.compute_reward_acc(r), r);
-                // round starts from 1 when now >= farming_start
-                let r0 = if r > 1 { r - 1 } else { 0 };
+                let offset = self.current_round_offset();
+                v.ping(self.compute_reward_acc(r, offset));
+                // `farmed_units` are settled up to the end of the `r`-th round (plus the
+                // elapsed seconds with the pro-rata accrual), capped by the farming end (the
+                // last round can be shorter)
+                let timestamp = std::cmp::min(
+                    self.farming_start + r * self.round_seconds + offset,
+                    self.farming_end,
+                );
                 let farmed = self
                     .farm_token_rates
                     .iter()
@@ -234,7 +391,7 @@ impl Contract {
                     farmed_units: v.farmed.into(),
                     farmed_tokens: farmed,
                     boost_nfts: v.boost_nft,
-                    timestamp: self.farming_start + r0 * ROUND,
+                    timestamp,
                     total_cheddar_staked: v.cheddar_staked.into(),
                 });
             }
@@ -242,19 +399,403 @@ impl Contract {
         };
     }
 
+    /// Returns the stake units of the account at the current round, including the boost NFT
+    /// and the loyalty boost. This is the account weight in the farm emission, compared to the
+    /// contract `staked_units`.
+    /// Returns 0 if the account is not registered.
+    pub fn get_effective_stake_units(&self, account_id: AccountId) -> U128 {
+        match self.vaults.get(&account_id) {
+            Some(mut v) => {
+                self.sync_vault(&mut v);
+                self.effective_stake(&v).into()
+            }
+            None => U128(0),
+        }
+    }
+
+    /// Returns the farmed, not withdrawn amount of the single reward token of the account.
+    /// Returns `None` if the account is not registered.
+    /// Panics if the farm has more than one farm token.
+    pub fn get_single_reward_status(&self, account_id: AccountId) -> Option<U128> {
+        assert!(
+            self.farm_tokens.len() == 1,
+            "not a single reward farm: {} farm tokens",
+            self.farm_tokens.len()
+        );
+        self.status(account_id).map(|s| s.farmed_tokens[0])
+    }
+
+    /// Returns the estimated storage cost (in yoctoNEAR) of the `account_id` vault: the size of
+    /// the borsh serialized vault and its key times the storage byte cost.
+    /// Unlike `storage_balance_of`, which reports the storage measured when (un)staking.
+    /// Panics if the account is not registered.
+    pub fn get_account_storage_usage(&self, account_id: AccountId) -> U128 {
+        let vault = self.get_vault(&account_id);
+        let vault_len = vault.try_to_vec().unwrap().len();
+        // key: the map prefix and the borsh serialized account id (u32 length + bytes)
+        let key_len = 1 + 4 + account_id.as_str().len();
+        (env::storage_byte_cost() * (vault_len + key_len) as u128).into()
+    }
+
+    /// Self-check of the contract totals. Logs each failed invariant and returns false if any
+    /// of them doesn't hold. Vaults are checked in the `[from_index, from_index + limit)` page
+    /// of registered accounts. Totals are compared with the vault sums only when the page
+    /// covers all accounts.
+    /// NOTE: Cheddar stays in the vault when unstaking a single NFT, so the staked Cheddar is
+    /// only required to cover the staked NFTs.
+    pub fn verify_invariants(&self, from_index: u64, limit: u64) -> bool {
+        let mut ok = true;
+        let mut fail = |msg: String| {
+            log!("invariant failed: {}", msg);
+            ok = false;
+        };
+
+        let staked_nfts: u128 = self.total_stake.iter().sum();
+        if self.total_cheddar_stake < self.cheddar_rate * staked_nfts {
+            fail(format!(
+                "total_cheddar_stake {} doesn't cover {} staked NFTs",
+                self.total_cheddar_stake, staked_nfts
+            ));
+        }
+        if self.accounts.len() != self.accounts_registered {
+            fail(format!(
+                "{} accounts tracked, {} registered",
+                self.accounts.len(),
+                self.accounts_registered
+            ));
+        }
+
+        let mut staked_units = 0;
+        let mut total_stake = vec![0; self.total_stake.len()];
+        let mut total_boost = vec![0; self.total_boost.len()];
+        let end = std::cmp::min(from_index.saturating_add(limit), self.accounts.len());
+        for i in from_index..end {
+            let a = self.accounts.as_vector().get(i).unwrap();
+            let v = match self.vaults.get(&a) {
+                Some(mut v) => {
+                    self.sync_vault(&mut v);
+                    v
+                }
+                None => {
+                    fail(format!("account @{} has no vault", a));
+                    continue;
+                }
+            };
+            let nfts = v.get_number_of_staked_tokens() as u128;
+            if v.cheddar_staked < self.cheddar_rate * nfts {
+                fail(format!("@{} Cheddar doesn't cover {} staked NFTs", a, nfts));
+            }
+            staked_units += v.min_stake;
+            for (j, tokens) in v.staked.iter().enumerate() {
+                total_stake[j] += tokens.len() as u128;
+            }
+            for boost in std::iter::once(&v.boost_nft).chain(v.boost_nft_recovered.iter()) {
+                if !boost.is_empty() {
+                    let (contract, _) = extract_contract_token_ids(boost);
+                    total_boost[find_acc_idx(&contract, &self.boost_nft_contracts)] += 1;
+                }
+            }
+        }
+
+        if from_index == 0 && end == self.accounts.len() {
+            if staked_units != self.staked_units {
+                fail(format!(
+                    "staked_units {}, vaults sum {}",
+                    self.staked_units, staked_units
+                ));
+            }
+            if total_stake != self.total_stake {
+                fail(format!(
+                    "total_stake {:?}, vaults sum {:?}",
+                    self.total_stake, total_stake
+                ));
+            }
+            if total_boost != self.total_boost {
+                fail(format!(
+                    "total_boost {:?}, vaults sum {:?}",
+                    self.total_boost, total_boost
+                ));
+            }
+        }
+        ok
+    }
+
+    /// Returns the round number at the given unix timestamp (in seconds), as `current_round`
+    /// computes it: 0 before the farming start, and the total number of rounds after the
+    /// farming end.
+    pub fn round_at(&self, timestamp_seconds: u64) -> u64 {
+        round_number_with_len(
+            self.farming_start,
+            self.farming_end,
+            timestamp_seconds,
+            self.round_seconds,
+        )
+    }
+
+    /// Returns the stored rewards accumulator and the round when it was last updated.
+    pub fn get_reward_acc(&self) -> (U128, u64) {
+        (self.reward_acc.into(), self.reward_acc_round)
+    }
+
+    /// Returns the rewards accumulator at the start of the given `round`, without updating
+    /// the state. `round` can't be before the last accumulator update.
+    pub fn compute_reward_acc_view(&self, round: u64) -> U128 {
+        assert!(
+            round >= self.reward_acc_round,
+            "round can't be before the last reward_acc update ({})",
+            self.reward_acc_round
+        );
+        self.compute_reward_acc(round, 0).into()
+    }
+
+    /// Returns, per farm token: the token, its current deposit, the amount needed for the
+    /// remaining rounds (farm units emitted from the current round to the farming end)
+    /// and whether the deposit covers it.
+    pub fn get_deposits_health(&self) -> Vec<(AccountId, U128, U128, bool)> {
+        let remaining_units = self.emitted_units(self.current_round(), self.total_rounds());
+        self.farm_tokens
+            .iter()
+            .enumerate()
+            .map(|(i, token)| {
+                let needed = safe_mul(remaining_units, self.farm_token_rates[i]);
+                let deposit = self.farm_deposits[i];
+                (
+                    token.clone(),
+                    deposit.into(),
+                    needed.into(),
+                    deposit >= needed,
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the Cheddar the account must deposit (with the "cheddar stake" msg) to stake
+    /// `additional_nfts` more NFTs: each staked NFT requires `cheddar_rate` staked Cheddar,
+    /// and Cheddar already staked but not used by the staked NFTs is taken into account.
+    /// Returns `cheddar_rate * additional_nfts` for unregistered accounts.
+    pub fn get_cheddar_stake_requirement(
+        &self,
+        account_id: AccountId,
+        additional_nfts: u64,
+    ) -> U128 {
+        let (staked_nfts, cheddar_staked) = match self.vaults.get(&account_id) {
+            Some(v) => (v.get_number_of_staked_tokens() as u128, v.cheddar_staked),
+            None => (0, 0),
+        };
+        let required = (staked_nfts + u128::from(additional_nfts))
+            .checked_mul(self.cheddar_rate)
+            .expect("Math overflow while computing expected Cheddar stake");
+        required.saturating_sub(cheddar_staked).into()
+    }
+
+    /// Returns the gas to attach to a harvest (`withdraw_crop`, `harvest`,
+    /// `claim_on_behalf`): a base plus an `ft_transfer` with its callback for each farm
+    /// token. An upper bound for farms where NEAR is a farm token (plain transfer).
+    pub fn estimate_harvest_gas(&self) -> u64 {
+        let per_token = GAS_FOR_FT_TRANSFER.0 + GAS_FOR_CALLBACK.0;
+        GAS_FOR_HARVEST_BASE.0 + per_token * self.farm_tokens.len() as u64
+    }
+
+    /// Returns the farm units emitted since the farming start, up to the current round.
+    /// Stays at the total emission after the farming end.
+    pub fn total_farmed_units(&self) -> U128 {
+        self.emitted_units(0, self.current_round()).into()
+    }
+
+    /// Returns the sum of the farmed units not harvested yet, settled up to the current round,
+    /// of the vaults in the `[from_index, from_index + limit)` page of registered accounts.
+    /// Operators sum the pages to reconcile the farm token balances with the liabilities:
+    /// each farm token owes `safe_mul(units, farm_token_rate)`.
+    pub fn get_unharvested_global(&self, from_index: u64, limit: u64) -> U128 {
+        let r = self.current_round();
+        let reward_acc = self.compute_reward_acc(r, self.current_round_offset());
+        let end = std::cmp::min(from_index.saturating_add(limit), self.accounts.len());
+        let mut farmed = 0;
+        for i in from_index..end {
+            let a = self.accounts.as_vector().get(i).unwrap();
+            if let Some(mut v) = self.vaults.get(&a) {
+                self.sync_vault(&mut v);
+                v.ping(reward_acc);
+                farmed += v.farmed;
+            }
+        }
+        farmed.into()
+    }
+
+    /// Returns each stake NFT contract with its contribution to the account `min_stake`:
+    /// the staked weight (number of staked NFTs without rarity weights) multiplied by the
+    /// contract stake rate. The contract with the lowest contribution limits the farming.
+    /// Disabled contracts (zero stake rate) contribute 0 and are not limiting.
+    /// Returns an empty list if the account is not registered.
+    pub fn get_min_stake_breakdown(&self, account_id: AccountId) -> Vec<(AccountId, U128)> {
+        match self.vaults.get(&account_id) {
+            Some(mut v) => {
+                self.sync_vault(&mut v);
+                v.get_staked_weights()
+                    .iter()
+                    .zip(self.stake_rates.iter())
+                    .zip(self.stake_nft_tokens.iter())
+                    .map(|((weight, rate), c)| (c.clone(), safe_mul(weighted_units(*weight), *rate).into()))
+                    .collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Returns the top `limit` stakers with their `min_stake` (effective staked units),
+    /// sorted descending. Accounts with the same stake are sorted by account id.
+    /// Panics if `limit > 100`.
+    pub fn get_top_stakers(&self, limit: u64) -> Vec<(AccountId, U128)> {
+        assert!(limit <= 100, "limit can't be bigger than 100");
+        let limit = limit as usize;
+        if limit == 0 {
+            return vec![];
+        }
+        let by_stake =
+            |a: &(AccountId, u128), b: &(AccountId, u128)| b.1.cmp(&a.1).then(a.0.cmp(&b.0));
+        let mut stakers: Vec<(AccountId, u128)> = self
+            .accounts
+            .iter()
+            .map(|a| {
+                let min_stake = self.get_vault(&a).min_stake;
+                (a, min_stake)
+            })
+            .collect();
+        // keep only the top `limit` entries before sorting them
+        if stakers.len() > limit {
+            stakers.select_nth_unstable_by(limit - 1, by_stake);
+            stakers.truncate(limit);
+        }
+        stakers.sort_unstable_by(by_stake);
+        stakers.into_iter().map(|(a, s)| (a, s.into())).collect()
+    }
+
+    /// Returns a page of token ids of `nft_contract_id` staked by `account_id`.
+    /// Returns an empty list if the account is not registered or the page is out of range.
+    /// Panics if `nft_contract_id` is not a stake NFT contract.
+    pub fn get_staked_token_ids(
+        &self,
+        account_id: AccountId,
+        nft_contract_id: NftContractId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<TokenId> {
+        let nft_ctr_idx = find_acc_idx(&nft_contract_id, &self.stake_nft_tokens);
+        match self.vaults.get(&account_id) {
+            Some(mut v) => {
+                self.sync_vault(&mut v);
+                v.staked[nft_ctr_idx]
+                    .iter()
+                    .skip(from_index as usize)
+                    .take(limit as usize)
+                    .cloned()
+                    .collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Returns the farm tokens which failed to transfer to the account at least
+    /// `MAX_FARMED_TRANSFER_FAILURES` times in a row: withdrawing them is likely to fail again
+    /// until the token is fixed and the owner resets the failures.
+    /// Returns an empty list if the account is not registered.
+    pub fn get_stuck_farm_tokens(&self, account_id: AccountId) -> Vec<AccountId> {
+        match self.vaults.get(&account_id) {
+            Some(v) => self
+                .farm_tokens
+                .iter()
+                .zip(v.farmed_transfer_failures.iter())
+                .filter(|(_, failures)| **failures >= MAX_FARMED_TRANSFER_FAILURES)
+                .map(|(token, _)| token.clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Returns the account vault as stored, without pinging it nor migrating its stake
+    /// layout. Owner only: it's meant to diagnose the internal accounting.
+    pub fn get_vault_raw(&self, account_id: AccountId) -> Option<VaultView> {
+        self.assert_owner();
+        self.vaults.get(&account_id).map(VaultView::from)
+    }
+
+    /// Returns the accounts allowed to call `relayer_unstake`.
+    pub fn get_relayers(&self) -> Vec<AccountId> {
+        self.relayers.to_vec()
+    }
+
+    /// Returns the number of NFTs staked by the account per stake NFT contract, in the
+    /// `stake_nft_tokens` order. Cheaper than `status` when the token ids are not needed.
+    /// Returns zeros if the account is not registered.
+    pub fn get_staked_nft_count(&self, account_id: AccountId) -> Vec<u64> {
+        match self.vaults.get(&account_id) {
+            Some(mut v) => {
+                self.sync_vault(&mut v);
+                v.staked.iter().map(|ids| ids.len() as u64).collect()
+            }
+            None => vec![0; self.stake_nft_tokens.len()],
+        }
+    }
+
+    /// Returns the `token` index in `stake_nft_tokens` and in `farm_tokens` (`None` if it's
+    /// not a stake NFT contract or a farm token). Useful to debug farm configurations.
+    pub fn token_indexes(&self, token: AccountId) -> (Option<u64>, Option<u64>) {
+        let idx = |v: &Vec<AccountId>| v.iter().position(|a| *a == token).map(|i| i as u64);
+        (idx(&self.stake_nft_tokens), idx(&self.farm_tokens))
+    }
+
     // ******************* //
     // transaction methods //
     // ******************* //
 
     /// withdraw NFT to a destination account using the `nft_transfer` method.
+    /// `receiver_id` defaults to the caller. If the transfer fails, the boost NFT is
+    /// recovered to the caller vault.
     /// This function is considered safe and will work when contract is paused to allow user
     /// to withdraw his NFTs.
     #[payable]
-    pub fn withdraw_boost_nft(&mut self) {
+    pub fn withdraw_boost_nft(&mut self, receiver_id: Option<AccountId>) {
+        assert_one_yocto();
+        let user = env::predecessor_account_id();
+        let receiver = receiver_id.unwrap_or_else(|| user.clone());
+        let mut vault = self.get_vault(&user);
+        self.assert_boost_unlocked(&vault);
+        self._withdraw_boost_nft(&user, &receiver, &mut vault);
+    }
+
+    /// Withdraws boost NFTs which failed to return to the user when swapping boost NFTs.
+    #[payable]
+    pub fn withdraw_boost_nft_recovered(&mut self) {
+        assert_one_yocto();
+        let user = env::predecessor_account_id();
+        let mut vault = self.get_vault(&user);
+        assert!(
+            !vault.boost_nft_recovered.is_empty(),
+            "Sender has no recovered boost NFTs"
+        );
+        let recovered = std::mem::take(&mut vault.boost_nft_recovered);
+        self.vaults.insert(&user, &vault);
+        for contract_and_token_id in recovered {
+            self.transfer_boost_nft(&user, &user, contract_and_token_id);
+        }
+    }
+
+    /// Withdraws staked NFTs which failed to return to the user after their stake NFT
+    /// contract was removed.
+    #[payable]
+    pub fn withdraw_staked_nft_recovered(&mut self) {
         assert_one_yocto();
         let user = env::predecessor_account_id();
         let mut vault = self.get_vault(&user);
-        self._withdraw_boost_nft(&user, &mut vault);
+        assert!(
+            !vault.staked_nft_recovered.is_empty(),
+            "Sender has no recovered staked NFTs"
+        );
+        let recovered = std::mem::take(&mut vault.staked_nft_recovered);
+        self.vaults.insert(&user, &vault);
+        for (nft_contract_id, token_id) in recovered {
+            self.transfer_recovered_nft(&user, nft_contract_id, token_id);
+        }
     }
 
     /// Deposit native near during the setup phase for farming rewards.
@@ -271,9 +812,8 @@ impl Contract {
             "setup deposits must be done when contract setup is not finalized"
         );
         let token_i = find_acc_idx(token, &self.farm_tokens);
-        let total_rounds = round_number(self.farming_start, self.farming_end, self.farming_end);
         let expected = safe_mul(
-            u128::from(total_rounds) * self.farm_unit_emission,
+            self.emitted_units(0, self.total_rounds()),
             self.farm_token_rates[token_i],
         );
         assert_eq!(
@@ -288,35 +828,65 @@ impl Contract {
         self.farm_deposits[token_i] = amount;
     }
 
-    /// FT Receiver `cheddar stake` scenario
-    pub(crate) fn stake_cheddar(&mut self, sender_id: &AccountId, amount: u128) {
+    /// FT Receiver `reward topup` scenario: increases the farm deposit of a farm token
+    /// after the setup is finalized. Once the deposits cover the extra rounds, the owner
+    /// extends the farm with `set_start_end`.
+    pub(crate) fn _reward_topup(&mut self, token: &AccountId, amount: u128) {
+        assert!(
+            self.setup_finalized,
+            "reward topup is only possible after the setup is finalized"
+        );
+        let token_i = find_acc_idx(token, &self.farm_tokens);
+        self.farm_deposits[token_i] =
+            add_or_panic(self.farm_deposits[token_i], amount, "farm deposit");
+        log!(
+            "Farm deposit of {} topped up with {}, total: {}",
+            token,
+            amount,
+            self.farm_deposits[token_i]
+        );
+    }
+
+    /// FT Receiver `cheddar stake` scenario. Credits exactly `cheddar_rate` to the vault
+    /// and returns the excess Cheddar to refund.
+    /// Panics if the received token (the predecessor) is not `Contract.cheddar`.
+    pub(crate) fn stake_cheddar(&mut self, sender_id: &AccountId, amount: u128) -> u128 {
         self.assert_is_active();
+        assert!(
+            env::predecessor_account_id() == self.cheddar,
+            "only {} can be staked with the cheddar stake msg",
+            self.cheddar
+        );
         let user = sender_id.clone();
         let mut vault = self.get_vault(&user);
 
         // Expected cheddar for stake per one token
         let expected = self.cheddar_rate;
         assert!(
-            expected >= amount,
+            amount >= expected,
             "User need at least {} to stake one more token. Got {}",
             self.cheddar_rate,
             amount
         );
 
         // update vault
-        vault.cheddar_staked += amount;
+        vault.cheddar_staked = add_or_panic(vault.cheddar_staked, expected, "vault cheddar stake");
         // update total cheddar staked info
-        self.total_cheddar_stake += self.cheddar_rate;
+        self.total_cheddar_stake =
+            add_or_panic(self.total_cheddar_stake, expected, "total cheddar stake");
         log!(
             "User stake {} Cheddar, which is required rate for stake 1 NFT token more",
-            amount
+            expected
         );
         self.vaults.insert(&sender_id, &vault);
+        // the excess is refunded
+        amount - expected
     }
 
     /// Unstakes given token and transfers it back to the user.
     /// If there is last staked token in vault - unstake and close the account
-    /// NOTE: account once closed must re-register to stake again.
+    /// NOTE: account once closed must re-register to stake again. Use `unstake_keep_open`
+    /// to keep the account registered.
     /// Returns vector of staked tokens left (still staked) after the call.
     /// Panics if the caller doesn't stake anything or if he doesn't have enough staked tokens.
     /// Requires 1 yNEAR payment for wallet 2FA.
@@ -325,7 +895,58 @@ impl Contract {
         self.assert_is_active();
         assert_one_yocto();
         let user = env::predecessor_account_id();
-        self._nft_unstake(&user, nft_contract_id, token_id)
+        self._nft_unstake(&user, nft_contract_id, token_id, false)
+            .remaining_tokens
+    }
+
+    /// Same as `unstake`, but returns the details of the unstake: the remaining staked tokens,
+    /// the settled farmed units, the refunded Cheddar and whether the account was closed.
+    /// Requires 1 yNEAR payment for wallet 2FA.
+    #[payable]
+    pub fn unstake_v2(
+        &mut self,
+        nft_contract_id: &NftContractId,
+        token_id: TokenId,
+    ) -> UnstakeResult {
+        self.assert_is_active();
+        assert_one_yocto();
+        let user = env::predecessor_account_id();
+        self._nft_unstake(&user, nft_contract_id, token_id, false)
+    }
+
+    /// Same as `unstake`, called by a whitelisted relayer on behalf of `user` (eg a
+    /// meta-transaction relayer). The relayer can't attach the user 1 yNEAR deposit, so it's
+    /// not required. The token is transferred back to `user`.
+    pub fn relayer_unstake(
+        &mut self,
+        user: AccountId,
+        nft_contract_id: &NftContractId,
+        token_id: TokenId,
+    ) -> Vec<TokenId> {
+        self.assert_is_active();
+        assert!(
+            self.relayers.contains(&env::predecessor_account_id()),
+            "caller is not a whitelisted relayer"
+        );
+        self._nft_unstake(&user, nft_contract_id, token_id, false)
+            .remaining_tokens
+    }
+
+    /// Same as `unstake`, but the account is never closed: when the last token is unstaked
+    /// the account stays registered with an empty stake, keeping the staked Cheddar and the
+    /// farmed units (which can be withdrawn with `withdraw_crop`).
+    /// Requires 1 yNEAR payment for wallet 2FA.
+    #[payable]
+    pub fn unstake_keep_open(
+        &mut self,
+        nft_contract_id: &NftContractId,
+        token_id: TokenId,
+    ) -> Vec<TokenId> {
+        self.assert_is_active();
+        assert_one_yocto();
+        let user = env::predecessor_account_id();
+        self._nft_unstake(&user, nft_contract_id, token_id, true)
+            .remaining_tokens
     }
 
     /// Unstakes everything and close the account. Sends all farmed tokens using a ft_transfer
@@ -333,12 +954,16 @@ impl Contract {
     /// Panics if the caller doesn't stake anything.
     /// Requires 1 yNEAR payment for wallet validation.
     /// Max unstaking tokens per time limited - 5 tokens (greedy gas).
+    /// Returns number of dispatched transfers.
     #[payable]
-    pub fn close(&mut self) {
+    pub fn close(&mut self) -> P4CloseReceipt {
         self.assert_is_active();
         assert_one_yocto();
+        self._close(env::predecessor_account_id())
+    }
 
-        let user = env::predecessor_account_id();
+    /// Closes the `user` account, see `close`.
+    fn _close(&mut self, user: AccountId) -> P4CloseReceipt {
         let mut vault = self.get_vault(&user);
 
         assert!(
@@ -349,21 +974,47 @@ impl Contract {
             vault.get_number_of_staked_tokens(),
             NFT_UNITS_MAX_TRANSFER_NUM
         );
+        self.assert_boost_unlocked(&vault);
+        self.assert_unstake_cooldown(&vault);
 
         self.ping_all(&mut vault);
         log!("Closing {} account, farmed: {:?}", &user, vault.farmed);
 
+        let mut receipt = P4CloseReceipt {
+            nft_transfers: 0,
+            farm_token_transfers: 0,
+            cheddar_transfers: 0,
+            boost_transfers: 0,
+            vault_removed: true,
+        };
+
         // if user doesn't stake anything and has no rewards then we can make a shortcut
         // and remove the account and return storage deposit.
         if vault.is_empty() {
             self.accounts_registered -= 1;
             self.vaults.remove(&user);
-            Promise::new(user.clone()).transfer(STORAGE_COST);
-            return;
+            self.accounts.remove(&user);
+            if vault.storage_deposit > 0 {
+                Promise::new(user.clone()).transfer(vault.storage_deposit);
+            }
+            return receipt;
         }
 
-        let units = min_stake(&vault.staked, &self.stake_rates);
-        self.staked_units -= units;
+        // withdrawing the boost NFT removes the boost from the vault stake units
+        if !vault.boost_nft.is_empty() {
+            self._withdraw_boost_nft(&user, &user, &mut vault);
+            receipt.boost_transfers = 1;
+        }
+        for contract_and_token_id in std::mem::take(&mut vault.boost_nft_recovered) {
+            self.transfer_boost_nft(&user, &user, contract_and_token_id);
+            receipt.boost_transfers += 1;
+        }
+        for (nft_contract_id, token_id) in std::mem::take(&mut vault.staked_nft_recovered) {
+            self.transfer_recovered_nft(&user, nft_contract_id, token_id);
+            receipt.nft_transfers += 1;
+        }
+        // remaining stake units, including the loyalty boost
+        self.staked_units = sub_or_panic(self.staked_units, vault.min_stake, "staked units");
 
         // transfer all tokens to user
         for nft_ctr_idx in 0..self.total_stake.len() {
@@ -373,52 +1024,198 @@ impl Contract {
                     user.clone(),
                     nft_ctr_idx,
                     staked_tokens_ids[token_idx].clone(),
+                    vault.staked_weights[nft_ctr_idx][token_idx],
                 );
+                receipt.nft_transfers += 1;
             }
         }
-        // withdraw farmed to user
-        self._withdraw_crop(&user, vault.farmed);
-
-        if !vault.boost_nft.is_empty() {
-            self._withdraw_boost_nft(&user, &mut vault);
-        }
 
+        self.charge_unstake_fee(&mut vault);
         if vault.cheddar_staked > 0 {
             self.transfer_staked_cheddar(user.clone(), Some(vault.cheddar_staked));
+            receipt.cheddar_transfers = 1;
         }
 
-        // NOTE: we don't return deposit because it will dramatically complicate logic
-        // in case we need to recover an account.
+        // The cost of the vault storage is kept: a failed transfer recreates the vault to
+        // recover the tokens.
+        let refund = vault.storage_deposit.saturating_sub(storage_cost(&vault));
+        if refund > 0 {
+            Promise::new(user.clone()).transfer(refund);
+        }
         self.accounts_registered -= 1;
         self.vaults.remove(&user);
+        self.accounts.remove(&user);
+
+        // withdraw farmed and recovered tokens to user, ignoring `min_harvest_amount`: the
+        // vault is removed. Done after removing the vault, because rewards not covered by the
+        // farm deposits are recovered to the account.
+        for i in 0..self.farm_tokens.len() {
+            let amount = self.harvest_amount(i, vault.farmed) + vault.farmed_recovered[i];
+            if self
+                ._transfer_farmed_tokens(&user, &user, i, amount)
+                .is_some()
+            {
+                receipt.farm_token_transfers += 1;
+            }
+        }
+        receipt
+    }
+
+    /// Settles the `account_id` rewards up to the current round, without changing its stake.
+    /// Can be called by anyone (eg keeper bots before a rate change or the farm end).
+    /// Does nothing if the account is not registered.
+    pub fn ping(&mut self, account_id: AccountId) {
+        self.assert_is_active();
+        if let Some(mut v) = self.vaults.get(&account_id) {
+            self.sync_vault(&mut v);
+            self.ping_all(&mut v);
+            self.vaults.insert(&account_id, &v);
+        }
     }
 
     /// Withdraws all farmed tokens to the user. It doesn't close the account.
-    /// Panics if user has not staked anything.
+    /// Panics if user has not staked anything or has nothing to harvest.
     pub fn withdraw_crop(&mut self) {
+        self.withdraw_crop_to(env::predecessor_account_id());
+    }
+
+    /// Same as `withdraw_crop`, but the farmed tokens are sent to `receiver_id`.
+    /// Failed transfers are recovered to the caller vault.
+    /// NOTE: `receiver_id` must be registered in all farm tokens.
+    pub fn withdraw_crop_to(&mut self, receiver_id: AccountId) {
         self.assert_is_active();
         let a = env::predecessor_account_id();
         let mut v = self.get_vault(&a);
         self.ping_all(&mut v);
+        // recovered tokens are withdrawn with `withdraw_farmed_recovered`
+        assert!(v.farmed > 0, "nothing to harvest");
+        let farmed_units = v.farmed;
+        v.farmed = 0;
+        self.vaults.insert(&a, &v);
+        self._withdraw_crop(&a, &receiver_id, farmed_units);
+    }
+
+    /// Harvests the farmed tokens of `account_id` and transfers them to `account_id`.
+    /// Can be called by anyone (eg to gift the harvest gas), since only the vault owner
+    /// benefits from it. Failed transfers are recovered to the `account_id` vault.
+    /// Panics if the account is not registered or has nothing to harvest.
+    pub fn claim_on_behalf(&mut self, account_id: AccountId) {
+        self.assert_is_active();
+        let mut v = self.get_vault(&account_id);
+        self.ping_all(&mut v);
+        assert!(v.farmed > 0, "nothing to harvest");
         let farmed_units = v.farmed;
         v.farmed = 0;
+        self.vaults.insert(&account_id, &v);
+        log!(
+            "@{} claims the farmed tokens on behalf of @{}",
+            env::predecessor_account_id(),
+            account_id
+        );
+        self._withdraw_crop(&account_id, &account_id, farmed_units);
+    }
+
+    /// Same as `withdraw_crop`, but returns the account status after the harvest:
+    /// farmed units are zeroed (the farmed tokens transfers are in flight) and the stake
+    /// is intact.
+    pub fn harvest(&mut self) -> P4Status {
+        self.withdraw_crop();
+        self.status(env::predecessor_account_id())
+            .expect(ERR10_NO_ACCOUNT)
+    }
+
+    /// Uses farmed Cheddar as the Cheddar stake required to stake one more NFT. Moves the
+    /// caller's farmed Cheddar to the caller's Cheddar stake, topping it up to the next
+    /// multiple of `cheddar_rate`. Other farm tokens harvested by the consumed farm units are
+    /// transferred to the caller.
+    /// Panics if Cheddar is not a farm token or the caller farmed less than the top up.
+    pub fn use_farmed_cheddar_for_stake(&mut self) {
+        self.assert_is_active();
+        let a = env::predecessor_account_id();
+        let cheddar_i = self
+            .farm_tokens
+            .iter()
+            .position(|t| *t == self.cheddar)
+            .expect("Cheddar is not a farm token");
+        let mut v = self.get_vault(&a);
+        self.ping_all(&mut v);
+
+        let rate = self.farm_token_rates[cheddar_i];
+        let farmed = safe_mul(v.farmed, rate);
+        assert!(farmed > 0, "no farmed Cheddar");
+        // Cheddar missing to the next full NFT slot
+        let amount = self.cheddar_rate - v.cheddar_staked % self.cheddar_rate;
+        assert!(
+            farmed >= amount,
+            "not enough farmed Cheddar to stake one more NFT, {} required",
+            amount
+        );
+        let units = units_for_amount(amount, rate).min(v.farmed);
+        assert!(
+            self.farm_deposits[cheddar_i] >= amount,
+            "Cheddar farm deposit is exhausted"
+        );
+        v.farmed -= units;
+        v.cheddar_staked = add_or_panic(v.cheddar_staked, amount, "vault cheddar stake");
         self.vaults.insert(&a, &v);
-        self._withdraw_crop(&a, farmed_units);
+
+        // the Cheddar is harvested, but it stays in the contract as stake
+        self.farm_deposits[cheddar_i] -= amount;
+        self.total_harvested[cheddar_i] += amount;
+        self.total_cheddar_stake =
+            add_or_panic(self.total_cheddar_stake, amount, "total cheddar stake");
+        log!("@{} staked {} farmed Cheddar", a, amount);
+
+        for i in 0..self.farm_tokens.len() {
+            if i != cheddar_i {
+                let amount = safe_mul(units, self.farm_token_rates[i]);
+                self.transfer_farmed_tokens(&a, &a, i, amount);
+            }
+        }
     }
 
-    /** transfers harvested tokens to the user
+    /** transfers harvested tokens of the user to the receiver
     / NOTE: the destination account must be registered on CHEDDAR first!
     / NOTE: callers MUST set user `vault.farmed_units` to zero prior to the call
-    /       because in case of failure the callbacks will re-add rewards to the vault */
-    fn _withdraw_crop(&mut self, user: &AccountId, farmed_units: u128) {
+    /       because in case of failure the callbacks will re-add rewards to the user vault
+    / Returns number of dispatched transfers. */
+    fn _withdraw_crop(
+        &mut self,
+        user: &AccountId,
+        receiver: &AccountId,
+        farmed_units: u128,
+    ) -> u64 {
         if farmed_units == 0 {
             // nothing to mint nor return.
-            return;
+            return 0;
         }
+        let mut transfers = 0;
         for i in 0..self.farm_tokens.len() {
-            let amount = safe_mul(farmed_units, self.farm_token_rates[i]);
-            self.transfer_farmed_tokens(user, i, amount);
+            let amount = self.harvest_amount(i, farmed_units);
+            if self
+                .transfer_farmed_tokens(user, receiver, i, amount)
+                .is_some()
+            {
+                transfers += 1;
+            }
         }
+        transfers
+    }
+
+    /// Amount of the `token_idx` farm token harvested by `farmed_units`.
+    /// Panics if the harvest would exceed the total emission of the token.
+    fn harvest_amount(&self, token_idx: usize, farmed_units: u128) -> u128 {
+        let amount = safe_mul(farmed_units, self.farm_token_rates[token_idx]);
+        // defense against accounting bugs: never harvest more than the farm emits
+        let total_units = self.emitted_units(0, self.total_rounds());
+        let max_harvest = safe_mul(total_units, self.farm_token_rates[token_idx]);
+        assert!(
+            self.total_harvested[token_idx] + amount <= max_harvest,
+            "{} harvest would exceed the total emission {}",
+            self.farm_tokens[token_idx],
+            max_harvest
+        );
+        amount
     }
 
     /** Withdraws harvested `token` to the user, which failed to transfer in a past call,
@@ -435,7 +1232,28 @@ impl Contract {
         let amount = v.farmed_recovered[token_i];
         assert!(amount > 0, "user {} balance is zero", token);
         v.farmed_recovered[token_i] = 0;
-        self.transfer_farmed_tokens(&a, token_i, amount);
+        self.vaults.insert(&a, &v);
+        self._transfer_farmed_tokens(&a, &a, token_i, amount);
+    }
+
+    /// Same as `withdraw_farmed_recovered`, for all farm tokens with a recovered balance.
+    /// Transfers which fail again are recovered by the callbacks.
+    /// Panics if there is no recovered balance.
+    pub fn withdraw_farmed_recovered_all(&mut self) {
+        self.assert_is_active();
+        let a = env::predecessor_account_id();
+        let mut v = self.get_vault(&a);
+        let recovered = std::mem::replace(&mut v.farmed_recovered, vec![0; self.farm_tokens.len()]);
+        assert!(
+            recovered.iter().any(|x| *x > 0),
+            "no recovered farmed tokens"
+        );
+        self.vaults.insert(&a, &v);
+        for (i, amount) in recovered.into_iter().enumerate() {
+            if amount > 0 {
+                self._transfer_farmed_tokens(&a, &a, i, amount);
+            }
+        }
     }
 
     // ******************* //
@@ -444,64 +1262,495 @@ impl Contract {
 
     /// Opens or closes smart contract operations. When the contract is not active, it won't
     /// reject every user call, until it will be open back again.
+    /// Nothing is farmed while the contract is paused: on resume, the farming start and end
+    /// are postponed by the number of paused rounds.
     pub fn set_active(&mut self, is_open: bool) {
         self.assert_owner();
+        assert!(
+            !is_open || self.accounts.len() == self.accounts_registered,
+            "vaults migration is not finished"
+        );
         self.is_active = is_open;
+        match (is_open, self.paused_at_round) {
+            (false, None) => {
+                let round = self.current_round();
+                self.update_reward_acc(round, self.current_round_offset());
+                self.paused_at_round = Some(round);
+                self.paused_at = env::block_timestamp() / SECOND;
+            }
+            (true, Some(_)) => {
+                self.paused_at_round = None;
+                // nothing is emitted before the start and after the end, so only the paused
+                // time within the farm is postponed, rounded up to whole rounds.
+                if self.paused_at < self.farming_end {
+                    let now = env::block_timestamp() / SECOND;
+                    let paused =
+                        now.saturating_sub(std::cmp::max(self.paused_at, self.farming_start));
+                    if paused > 0 {
+                        let shift = align_to_round(paused, self.round_seconds);
+                        self.farming_start += shift;
+                        self.farming_end += shift;
+                        log!("farming postponed by {} paused rounds", shift / self.round_seconds);
+                    }
+                }
+            }
+            _ => {}
+        }
     }
 
-    /// start and end are unix timestamps (in seconds)
-    pub fn set_start_end(&mut self, start: u64, end: u64) {
+    /// Proposes `new_owner` as the new contract owner. The ownership is transferred
+    /// only once `new_owner` calls `accept_ownership`.
+    pub fn propose_owner(&mut self, new_owner: AccountId) {
         self.assert_owner();
+        log!("Proposing {} as the new owner", new_owner);
+        self.pending_owner = Some(new_owner);
+    }
+
+    /// Completes the ownership transfer. Must be called by the proposed owner.
+    pub fn accept_ownership(&mut self) {
+        let caller = env::predecessor_account_id();
         assert!(
-            start > env::block_timestamp() / SECOND,
-            "start must be in the future"
+            self.pending_owner.as_ref() == Some(&caller),
+            "can only be called by the pending owner"
         );
-        assert!(start < end, "start must be before end");
-        self.farming_start = start;
-        self.farming_end = end;
+        log!("Ownership transferred from {} to {}", self.owner_id, caller);
+        self.owner_id = caller;
+        self.pending_owner = None;
     }
 
-    pub fn stop(&mut self) {
+    /// Sets the loyalty boost: for each round a vault holds its staked NFTs, its stake
+    /// gets `bp_per_round` basis points more, up to `cap_bp`. The boost restarts when
+    /// the user stakes more NFTs. Set `bp_per_round` to 0 to disable.
+    pub fn set_loyalty_boost(&mut self, bp_per_round: u32, cap_bp: u32) {
         self.assert_owner();
-        let now = env::block_timestamp() / SECOND;
-        assert!(now < self.farming_end, "farm already finished");
-        self.farming_end = env::block_timestamp() / SECOND;
+        self.loyalty_boost_bp_per_round = bp_per_round;
+        self.loyalty_boost_cap_bp = cap_bp;
     }
 
-    pub fn finalize_setup(&mut self) {
-        //self.assert_owner();
+    /// Sets a reward multiplier (in basis points) applied to the farm unit emission during
+    /// the rounds `[start_round, end_round)`, eg. 20_000 for 2x rewards. Must be set before
+    /// the farm deposits, which must cover the extra rewards.
+    pub fn set_reward_multiplier(&mut self, multiplier_bp: u32, start_round: u64, end_round: u64) {
+        self.assert_owner();
         assert!(
-            !self.setup_finalized,
-            "setup deposits must be done when contract setup is not finalized"
+            check_all_zeros(&self.farm_deposits),
+            "reward multiplier must be set before the farm deposits"
         );
-        let now = env::block_timestamp() / SECOND;
         assert!(
-            now < self.farming_start - ROUND,
-            "must be finalized at last before farm start"
+            start_round <= end_round && end_round <= self.total_rounds(),
+            "reward multiplier rounds must be within the farming rounds"
         );
-        for i in 0..self.farm_deposits.len() {
-            assert_ne!(
-                self.farm_deposits[i], 0,
-                "Deposit for token {} not done",
-                self.farm_tokens[i]
+        self.reward_multiplier_bp = multiplier_bp;
+        self.reward_multiplier_start = start_round;
+        self.reward_multiplier_end = end_round;
+    }
+
+    /// Enables (or disables) the pro-rata accrual: rewards accrue with the seconds staked
+    /// within a round, so staking (or unstaking) in the middle of a round earns the
+    /// fraction of the round. Disabled by default: rewards accrue with whole rounds.
+    /// Rewards are settled up to now before the mode changes.
+    pub fn set_pro_rata_accrual(&mut self, enabled: bool) {
+        self.assert_owner();
+        self.update_reward_acc(self.current_round(), self.current_round_offset());
+        self.pro_rata_accrual = enabled;
+    }
+
+    /// Sets the max number of NFTs a single account can stake.
+    pub fn set_max_staked_per_account(&mut self, max: u64) {
+        self.assert_owner();
+        assert!(
+            max > 0 && max <= NFT_UNITS_MAX_TRANSFER_NUM as u64,
+            "max_staked_per_account must be between 1 and {}",
+            NFT_UNITS_MAX_TRANSFER_NUM
+        );
+        self.max_staked_per_account = max;
+    }
+
+    /// Sets the min amount of each farm token (in the `farm_tokens` order) transferred on
+    /// a harvest. Use 0 to always transfer.
+    pub fn set_min_harvest_amount(&mut self, amounts: Vec<U128>) {
+        self.assert_owner();
+        assert_eq!(
+            amounts.len(),
+            self.farm_tokens.len(),
+            "expected {} amounts",
+            self.farm_tokens.len()
+        );
+        self.min_harvest_amount = amounts.into_iter().map(|a| a.0).collect();
+    }
+
+    /// Allows `relayer` to unstake on behalf of users with `relayer_unstake`.
+    pub fn add_relayer(&mut self, relayer: AccountId) {
+        self.assert_owner();
+        self.relayers.insert(&relayer);
+    }
+
+    pub fn remove_relayer(&mut self, relayer: AccountId) {
+        self.assert_owner();
+        self.relayers.remove(&relayer);
+    }
+
+    /// Sets the number of rounds after the last NFT stake before the account can unstake:
+    /// with 1, unstaking in the same round as the stake is rejected. Set to 0 to disable
+    /// the cooldown.
+    pub fn set_unstake_cooldown_rounds(&mut self, rounds: u64) {
+        self.assert_owner();
+        self.unstake_cooldown_rounds = rounds;
+    }
+
+    /// Sets the number of rounds a boost NFT must stay deposited before it can be withdrawn
+    /// (with `withdraw_boost_nft` or `close`). Set to 0 to disable the lock.
+    pub fn set_boost_lock_rounds(&mut self, rounds: u64) {
+        self.assert_owner();
+        self.boost_lock_rounds = rounds;
+    }
+
+    /// Sets the fee in Cheddar charged from the staked Cheddar on each `unstake` and `close`.
+    /// The fee can't be bigger than `cheddar_rate`, so the remaining staked NFTs stay covered.
+    pub fn set_unstake_fee_cheddar(&mut self, fee: U128) {
+        self.assert_owner();
+        assert!(
+            fee.0 <= self.cheddar_rate,
+            "unstake fee can't be bigger than cheddar_rate"
+        );
+        self.unstake_fee_cheddar = fee.0;
+    }
+
+    /// Withdraws all collected fee to the treasury.
+    /// Must make sure treasury is registered in the Cheddar contract.
+    pub fn withdraw_fees(&mut self) {
+        assert!(self.fee_collected > 0, "no fee collected");
+        let amount = self.fee_collected;
+        log!("Withdrawing collected fee: {} Cheddar", amount);
+        self.fee_collected = 0;
+        ext_ft::ext(self.cheddar.clone())
+            .with_attached_deposit(ONE_YOCTO)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(
+                self.treasury.clone(),
+                amount.into(),
+                Some("fee withdraw".to_string()),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_CALLBACK)
+                    .withdraw_fees_callback(amount.into()),
+            );
+    }
+
+    /// Sets a new treasury - a destination for the collected fees.
+    pub fn set_treasury(&mut self, new_treasury: AccountId) {
+        self.assert_owner();
+        log!(
+            "Changing treasury from {} to {}",
+            self.treasury,
+            new_treasury
+        );
+        self.treasury = new_treasury;
+    }
+
+    /// Resets the failed transfers counter of `token` for the account, eg once a broken farm
+    /// token contract is fixed.
+    pub fn reset_farmed_transfer_failures(&mut self, account_id: AccountId, token: AccountId) {
+        self.assert_owner();
+        let token_i = find_acc_idx(&token, &self.farm_tokens);
+        let mut v = self.get_vault(&account_id);
+        v.farmed_transfer_failures[token_i] = 0;
+        self.vaults.insert(&account_id, &v);
+    }
+
+    /// Sets a new Cheddar token contract, eg after a token migration.
+    /// Panics if any Cheddar is staked: it would be stranded on the old contract.
+    pub fn set_cheddar_contract(&mut self, new_cheddar: AccountId) {
+        self.assert_owner();
+        assert!(
+            self.total_cheddar_stake == 0,
+            "can't change the Cheddar contract with {} Cheddar staked",
+            self.total_cheddar_stake
+        );
+        log!(
+            "Changing Cheddar contract from {} to {}",
+            self.cheddar,
+            new_cheddar
+        );
+        self.cheddar = new_cheddar;
+    }
+
+    /// Harvests farmed tokens of the registered accounts in range
+    /// `[from_index, from_index + limit)` (in the `accounts` order), to distribute all
+    /// outstanding rewards at the farm end. Failed transfers are recovered to the vaults,
+    /// as in `withdraw_crop`. Returns the number of harvested accounts.
+    pub fn owner_harvest_all(&mut self, from_index: u64, limit: u64) -> u64 {
+        self.assert_owner();
+        let end = std::cmp::min(from_index.saturating_add(limit), self.accounts.len());
+        let mut harvested = 0;
+        for i in from_index..end {
+            let a = self.accounts.as_vector().get(i).unwrap();
+            let mut v = self.get_vault(&a);
+            self.ping_all(&mut v);
+            let farmed_units = v.farmed;
+            if farmed_units == 0 {
+                continue;
+            }
+            v.farmed = 0;
+            self.vaults.insert(&a, &v);
+            self._withdraw_crop(&a, &a, farmed_units);
+            harvested += 1;
+        }
+        log!("Harvested {} accounts", harvested);
+        harvested
+    }
+
+    /// Recomputes `total_cheddar_stake` as the sum of the vaults `cheddar_staked`, in pages of
+    /// registered accounts (in the `accounts` order). Must be called with consecutive pages
+    /// starting from 0; the total is set on the last page. Returns the sum so far.
+    /// The contract must be paused, so the vaults don't change between pages.
+    pub fn recompute_total_cheddar_stake(&mut self, from_index: u64, limit: u64) -> U128 {
+        self.assert_owner();
+        assert!(!self.is_active, "contract must be paused");
+        if from_index == 0 {
+            self.cheddar_stake_recompute = (0, 0);
+        }
+        let (mut total, next) = self.cheddar_stake_recompute;
+        assert!(
+            from_index == next,
+            "recompute must continue from index {}",
+            next
+        );
+        let end = std::cmp::min(from_index.saturating_add(limit), self.accounts.len());
+        for i in from_index..end {
+            let a = self.accounts.as_vector().get(i).unwrap();
+            total = add_or_panic(total, self.get_vault(&a).cheddar_staked, "cheddar stake");
+        }
+        if end == self.accounts.len() {
+            log!(
+                "total_cheddar_stake recomputed: {} -> {}",
+                self.total_cheddar_stake,
+                total
+            );
+            self.total_cheddar_stake = total;
+            self.cheddar_stake_recompute = (0, 0);
+        } else {
+            self.cheddar_stake_recompute = (total, end);
+        }
+        total.into()
+    }
+
+    /// Moves the vault of `from` to `to`, for support operations when a user lost access to
+    /// their account. Nothing is unstaked, so totals are not changed.
+    pub fn owner_migrate_vault(&mut self, from: AccountId, to: AccountId) {
+        self.assert_owner();
+        assert!(
+            self.vaults.get(&to).is_none(),
+            "destination account already has a vault"
+        );
+        let vault = self.vaults.remove(&from).expect(ERR10_NO_ACCOUNT);
+        self.vaults.insert(&to, &vault);
+        self.accounts.remove(&from);
+        self.accounts.insert(&to);
+        log!("Migrated vault from {} to {}", from, to);
+    }
+
+    /// Sets the rarity weight of an NFT from a stake contract. Regular NFTs have weight 1,
+    /// the weight can't be bigger than `MAX_NFT_WEIGHT`.
+    /// The weight is recorded in the vault at stake time, so it only affects future stakes.
+    pub fn set_token_weight(
+        &mut self,
+        nft_contract_id: NftContractId,
+        token_id: TokenId,
+        weight: U128,
+    ) {
+        self.assert_owner();
+        assert!(weight.0 > 0, "weight must be positive");
+        assert!(
+            weight.0 <= MAX_NFT_WEIGHT,
+            "weight can't be bigger than {}",
+            MAX_NFT_WEIGHT
+        );
+        find_acc_idx(&nft_contract_id, &self.stake_nft_tokens);
+        self.token_weights
+            .insert(&(nft_contract_id, token_id), &weight.0);
+    }
+
+    /// Whitelists a new NFT contract for staking with the given stake `rate`.
+    /// NOTE: stake units are the minimum over all stake NFT contracts, so stakers
+    /// need to stake NFTs from the new contract as well to keep farming. That's why
+    /// while farming is running the contract can only be added disabled (rate 0).
+    pub fn add_stake_nft_contract(&mut self, contract: NftContractId, rate: U128) {
+        self.assert_owner();
+        assert!(
+            !self.stake_nft_tokens.contains(&contract),
+            "NFT contract is already whitelisted"
+        );
+        let now = env::block_timestamp() / SECOND;
+        assert!(
+            rate.0 == 0 || now < self.farming_start || now >= self.farming_end,
+            "farming is running, the NFT contract can only be added with rate 0"
+        );
+        log!(
+            "Adding stake NFT contract {} with rate {}",
+            contract,
+            rate.0
+        );
+        self.stake_nft_tokens.push(contract);
+        self.stake_rates.push(rate.0);
+        self.total_stake.push(0);
+        self.pending_stake_transfers.push(0);
+        self.check_vectors();
+    }
+
+    /// Sets the stake rate of a stake NFT contract. Rate 0 disables new stakes from the
+    /// contract, while already staked NFTs can still be unstaked. Disabled contracts are not
+    /// taken into account when computing the stake units, so, same as
+    /// `add_stake_nft_contract`, a disabled contract can't be enabled while farming is running.
+    /// NOTE: vault stakes are only recomputed with the next vault ping, until then the vault
+    /// farms with its old stake. Keepers should `ping` all vaults after a rate change.
+    pub fn set_stake_rate(&mut self, contract: NftContractId, rate: U128) {
+        self.assert_owner();
+        let idx = find_acc_idx(&contract, &self.stake_nft_tokens);
+        let now = env::block_timestamp() / SECOND;
+        assert!(
+            self.stake_rates[idx] > 0
+                || rate.0 == 0
+                || now < self.farming_start
+                || now >= self.farming_end,
+            "farming is running, a disabled NFT contract can't be enabled"
+        );
+        log!(
+            "Changing stake rate of {} from {} to {}",
+            contract,
+            self.stake_rates[idx],
+            rate.0
+        );
+        self.stake_rates[idx] = rate.0;
+    }
+
+    /// Removes an NFT contract from the staking whitelist.
+    /// Panics if there are NFTs from that contract still staked, or being transferred back
+    /// to the users (a failed transfer recovers the NFT to the vault).
+    pub fn remove_stake_nft_contract(&mut self, contract: NftContractId) {
+        self.assert_owner();
+        let idx = find_acc_idx(&contract, &self.stake_nft_tokens);
+        assert_eq!(
+            self.total_stake[idx], 0,
+            "can't remove NFT contract with staked tokens"
+        );
+        assert_eq!(
+            self.pending_stake_transfers[idx], 0,
+            "can't remove NFT contract with pending NFT transfers"
+        );
+        assert!(
+            self.stake_nft_tokens.len() > 1,
+            "at least one stake NFT contract is required"
+        );
+        log!("Removing stake NFT contract {}", contract);
+        self.stake_nft_tokens.remove(idx);
+        self.stake_rates.remove(idx);
+        self.total_stake.remove(idx);
+        self.pending_stake_transfers.remove(idx);
+        self.stake_nft_removals.push(idx);
+        self.check_vectors();
+    }
+
+    /// Returns the rarity weight of an NFT which will be used when it's staked.
+    pub fn get_token_weight(&self, nft_contract_id: NftContractId, token_id: TokenId) -> U128 {
+        self.token_weight(&nft_contract_id, &token_id).into()
+    }
+
+    /// start and end are unix timestamps (in seconds)
+    /// Once the setup is finalized, the new schedule can't have fewer rounds, and the farm
+    /// deposits (see the `reward topup` deposit) must cover the emission of its extra rounds.
+    /// When the farming already started, the start can't be changed.
+    pub fn set_start_end(&mut self, start: u64, end: u64) {
+        self.assert_owner();
+        let now = env::block_timestamp() / SECOND;
+        if now >= self.farming_start {
+            assert_eq!(
+                start, self.farming_start,
+                "farming already started, start can't be changed"
+            );
+        } else {
+            assert!(start > now, "start must be in the future");
+        }
+        assert!(start < end, "start must be before end");
+        // settle the rewards earned with the current schedule
+        self.update_reward_acc(self.current_round(), self.current_round_offset());
+        let total_rounds = self.total_rounds();
+        self.farming_start = start;
+        self.farming_end = end;
+        if self.setup_finalized {
+            assert!(
+                self.total_rounds() >= total_rounds,
+                "setup is finalized, the new schedule can't have fewer rounds"
+            );
+            self.assert_deposits_cover_emission();
+        }
+    }
+
+    /// Panics if a farm deposit, with the tokens already harvested, doesn't cover the
+    /// emission of the farm schedule.
+    fn assert_deposits_cover_emission(&self) {
+        let total_units = self.emitted_units(0, self.total_rounds());
+        for i in 0..self.farm_tokens.len() {
+            let emission = safe_mul(total_units, self.farm_token_rates[i]);
+            let covered = self.farm_deposits[i] + self.total_harvested[i];
+            assert!(
+                covered >= emission,
+                "{} farm deposit doesn't cover the farm emission {}, missing {}",
+                self.farm_tokens[i],
+                emission,
+                emission - covered
+            );
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.assert_owner();
+        let now = env::block_timestamp() / SECOND;
+        assert!(now < self.farming_end, "farm already finished");
+        self.farming_end = env::block_timestamp() / SECOND;
+    }
+
+    pub fn finalize_setup(&mut self) {
+        //self.assert_owner();
+        assert!(
+            !self.setup_finalized,
+            "setup deposits must be done when contract setup is not finalized"
+        );
+        let now = env::block_timestamp() / SECOND;
+        assert!(
+            now < self.farming_start - self.round_seconds,
+            "must be finalized at last before farm start"
+        );
+        for i in 0..self.farm_deposits.len() {
+            assert_ne!(
+                self.farm_deposits[i], 0,
+                "Deposit for token {} not done",
+                self.farm_tokens[i]
             )
         }
+        // the schedule can change after the deposits (`set_start_end`)
+        let (expected, _) = self.finalize_setup_expected();
+        for (i, e) in expected.iter().enumerate() {
+            assert_eq!(
+                self.farm_deposits[i], e.0,
+                "Deposit for token {} doesn't match the farm emission {}",
+                self.farm_tokens[i], e.0
+            );
+        }
         self.setup_finalized = true;
     }
 
     /// Returns expected and received deposits for farmed tokens
     pub fn finalize_setup_expected(&self) -> (Vec<U128>, Vec<U128>) {
         //self.assert_owner();
-        let total_rounds = u128::from(round_number(
-            self.farming_start,
-            self.farming_end,
-            self.farming_end,
-        ));
+        let total_rounds = self.total_rounds();
         log!("rounds: {}", total_rounds);
+        let total_units = self.emitted_units(0, total_rounds);
         let out = self
             .farm_token_rates
             .iter()
-            .map(|rate| safe_mul(total_rounds * self.farm_unit_emission, *rate))
+            .map(|rate| safe_mul(total_units, *rate))
             .collect();
         (to_U128s(&out), to_U128s(&self.farm_deposits))
     }
@@ -526,7 +1775,11 @@ impl Contract {
             U128(self.cheddar_rate)
         };
 
-        self.total_cheddar_stake -= transfered_amount.0;
+        self.total_cheddar_stake = sub_or_panic(
+            self.total_cheddar_stake,
+            transfered_amount.0,
+            "total cheddar stake",
+        );
         log!(
             "@{} unstake Cheddar locked deposit ( {:?} )",
             user.clone(),
@@ -557,11 +1810,13 @@ impl Contract {
         user: AccountId,
         nft_ctr_idx: usize,
         token_id: TokenId,
+        weight: Balance,
     ) -> Promise {
         let nft_contract_id = &self.stake_nft_tokens[nft_ctr_idx];
         log!("unstaking {} token @{}", nft_contract_id, token_id);
 
         self.total_stake[nft_ctr_idx] -= 1;
+        self.pending_stake_transfers[nft_ctr_idx] += 1;
 
         return ext_nft::ext(nft_contract_id.clone())
             .with_attached_deposit(ONE_YOCTO)
@@ -575,62 +1830,216 @@ impl Contract {
             .then(
                 Self::ext(env::current_account_id())
                     .with_static_gas(GAS_FOR_CALLBACK)
-                    .transfer_staked_callback(user, nft_ctr_idx, token_id.clone().into()),
+                    .transfer_staked_callback(
+                        user,
+                        nft_contract_id.clone(),
+                        token_id.clone().into(),
+                        weight.into(),
+                    ),
             );
     }
 
-    #[inline]
+    /// Transfers a recovered staked NFT (`Vault.staked_nft_recovered`) back to the user.
+    /// The vault must be updated by the caller. The callback will recover the NFT again if
+    /// the transfer fails.
+    fn transfer_recovered_nft(
+        &mut self,
+        user: &AccountId,
+        nft_contract_id: NftContractId,
+        token_id: TokenId,
+    ) -> Promise {
+        log!("withdrawing recovered {} token @{}", nft_contract_id, token_id);
+        ext_nft::ext(nft_contract_id.clone())
+            .with_attached_deposit(ONE_YOCTO)
+            .with_static_gas(GAS_FOR_NFT_TRANSFER)
+            .nft_transfer(
+                user.clone(),
+                token_id.clone(),
+                None,
+                Some("unstaking".to_string()),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_CALLBACK)
+                    .transfer_recovered_nft_callback(user.clone(), nft_contract_id, token_id),
+            )
+    }
+
+    /// Transfers farmed tokens of the user to the receiver.
+    /// If `amount`, together with the user `farmed_recovered` balance, is below the token
+    /// `min_harvest_amount`, nothing is transferred and `amount` is kept in the user vault
+    /// (`farmed_recovered`). Otherwise the `farmed_recovered` balance is transferred as well.
+    /// The threshold doesn't apply when the user vault doesn't exist (eg on close).
+    /// Returns `None` if nothing was transferred.
     fn transfer_farmed_tokens(
         &mut self,
         user: &AccountId,
+        receiver: &AccountId,
         token_idx: usize,
-        amount: u128,
-    ) -> Promise {
-        let ft_contract_id = &self.farm_tokens[token_idx];
-        log!("transfer farmed token: @{} ", ft_contract_id.clone());
+        mut amount: u128,
+    ) -> Option<Promise> {
+        if self.min_harvest_amount[token_idx] > 0 {
+            if let Some(mut v) = self.vaults.get(user) {
+                let recovered = v.farmed_recovered[token_idx];
+                if amount + recovered < self.min_harvest_amount[token_idx] {
+                    log!(
+                        "{} {} is below the min harvest amount, kept in the vault",
+                        amount,
+                        self.farm_tokens[token_idx]
+                    );
+                    v.farmed_recovered[token_idx] = recovered + amount;
+                    self.vaults.insert(user, &v);
+                    return None;
+                }
+                if recovered > 0 {
+                    v.farmed_recovered[token_idx] = 0;
+                    self.vaults.insert(user, &v);
+                    amount += recovered;
+                }
+            }
+        }
+        self._transfer_farmed_tokens(user, receiver, token_idx, amount)
+    }
+
+    /// Transfers farmed tokens of the user to the receiver, ignoring `min_harvest_amount`.
+    /// If the farm deposit doesn't cover `amount`, only the remaining deposit is transferred
+    /// and the shortfall is recovered to the user vault (`farmed_recovered`).
+    /// Returns `None` if nothing was transferred.
+    fn _transfer_farmed_tokens(
+        &mut self,
+        user: &AccountId,
+        receiver: &AccountId,
+        token_idx: usize,
+        mut amount: u128,
+    ) -> Option<Promise> {
+        let ft_contract_id = self.farm_tokens[token_idx].clone();
+        log!("transfer farmed token: @{} ", ft_contract_id);
+        let deposit = self.farm_deposits[token_idx];
+        if amount > deposit {
+            let shortfall = amount - deposit;
+            log!(
+                "WARNING: {} farm deposit is exhausted. {} not covered rewards are recovered to @{}",
+                ft_contract_id,
+                shortfall,
+                user
+            );
+            let mut v = self.recovered_vault(user);
+            v.farmed_recovered[token_idx] += shortfall;
+            self.vaults.insert(user, &v);
+            amount = deposit;
+        }
+        if amount == 0 {
+            return None;
+        }
         self.total_harvested[token_idx] += amount;
         self.farm_deposits[token_idx] -= amount;
 
-        if ft_contract_id == &near() {
-            return Promise::new(user.clone()).transfer(amount);
+        if ft_contract_id == near() {
+            return Some(Promise::new(receiver.clone()).transfer(amount));
         }
 
         let amount: U128 = amount.into();
 
-        return ext_ft::ext(ft_contract_id.clone())
-            .with_attached_deposit(ONE_YOCTO)
-            .with_static_gas(GAS_FOR_FT_TRANSFER)
-            .ft_transfer(user.clone(), amount, Some("farming".to_string()))
-            .then(
-                Self::ext(env::current_account_id())
-                    .with_static_gas(GAS_FOR_CALLBACK)
-                    .transfer_farmed_callback(user.clone(), token_idx, amount),
-            );
+        return Some(
+            ext_ft::ext(ft_contract_id)
+                .with_attached_deposit(ONE_YOCTO)
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .ft_transfer(receiver.clone(), amount, Some("farming".to_string()))
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_CALLBACK)
+                        .transfer_farmed_callback(user.clone(), token_idx, amount),
+                ),
+        );
     }
 
     #[private]
     pub fn transfer_staked_callback(
         &mut self,
         user: AccountId,
-        nft_ctr_idx: usize,
+        nft_contract_id: NftContractId,
         token_id: TokenId,
+        weight: U128,
     ) {
-        if promise_result_as_failed() {
-            log!(
-                "transferring token: {} contract: {}  failed. Recovering account state",
-                token_id,
-                self.stake_nft_tokens[nft_ctr_idx],
-            );
-
-            self.total_stake[nft_ctr_idx] += 1;
+        // the contract index is resolved here rather than passed from the caller: stake
+        // NFT contracts can be removed while the transfer is in flight, shifting indexes.
+        let nft_ctr_idx = self
+            .stake_nft_tokens
+            .iter()
+            .position(|c| *c == nft_contract_id);
+        if let Some(idx) = nft_ctr_idx {
+            // transfers made before the upgrade to this version were not counted
+            self.pending_stake_transfers[idx] =
+                self.pending_stake_transfers[idx].saturating_sub(1);
+        }
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                log!(
+                    "transferred token: {} contract: {}",
+                    token_id,
+                    nft_contract_id
+                );
+            }
+            PromiseResult::Failed => {
+                log!(
+                    "transferring token: {} contract: {}  failed. Recovering account state",
+                    token_id,
+                    nft_contract_id,
+                );
+                let nft_ctr_idx = match nft_ctr_idx {
+                    Some(idx) => idx,
+                    None => {
+                        log!(
+                            "stake NFT contract {} was removed, token {} is recovered to @{}",
+                            nft_contract_id,
+                            token_id,
+                            user
+                        );
+                        let mut v = self.recovered_vault(&user);
+                        v.staked_nft_recovered.push((nft_contract_id, token_id));
+                        self.vaults.insert(&user, &v);
+                        return;
+                    }
+                };
+
+                self.recover_state(
+                    &user,
+                    true,           // is_staked
+                    nft_ctr_idx,    // NFT Contract
+                    Some(token_id), // NFT TokenId
+                    Some(weight.0), // NFT weight
+                );
+            }
+        }
+    }
 
-            self.recover_state(
-                &user,
-                true,           // is_staked
-                nft_ctr_idx,    // NFT Contract
-                Some(token_id), // NFT TokenId
-                None,           // no amount - unique token
-            );
+    #[private]
+    pub fn transfer_recovered_nft_callback(
+        &mut self,
+        user: AccountId,
+        nft_contract_id: NftContractId,
+        token_id: TokenId,
+    ) {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                log!(
+                    "transferred recovered token: {} contract: {}",
+                    token_id,
+                    nft_contract_id
+                );
+            }
+            PromiseResult::Failed => {
+                log!(
+                    "transferring recovered token: {} contract: {} failed",
+                    token_id,
+                    nft_contract_id,
+                );
+                let mut v = self.recovered_vault(&user);
+                v.staked_nft_recovered.push((nft_contract_id, token_id));
+                self.vaults.insert(&user, &v);
+            }
         }
     }
 
@@ -638,36 +2047,66 @@ impl Contract {
 
     #[private]
     pub fn transfer_farmed_callback(&mut self, user: AccountId, ft_ctr_idx: usize, amount: U128) {
-        if promise_result_as_failed() {
-            log!(
-                "harvesting {} {} token failed. recovering account state",
-                amount.0,
-                self.farm_tokens[ft_ctr_idx],
-            );
-            self.recover_state(
-                &user,
-                false,          // is_staked
-                ft_ctr_idx,     // FT Contract
-                None,           // no token_ids - FT Contract
-                Some(amount.0), // amount of farmed FTs
-            );
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                log!("harvested {} {}", amount.0, self.farm_tokens[ft_ctr_idx]);
+                if let Some(mut v) = self.vaults.get(&user) {
+                    if v.farmed_transfer_failures[ft_ctr_idx] > 0 {
+                        v.farmed_transfer_failures[ft_ctr_idx] = 0;
+                        self.vaults.insert(&user, &v);
+                    }
+                }
+            }
+            PromiseResult::Failed => {
+                log!(
+                    "harvesting {} {} token failed. recovering account state",
+                    amount.0,
+                    self.farm_tokens[ft_ctr_idx],
+                );
+                self.recover_state(
+                    &user,
+                    false,          // is_staked
+                    ft_ctr_idx,     // FT Contract
+                    None,           // no token_ids - FT Contract
+                    Some(amount.0), // amount of farmed FTs
+                );
+            }
         }
     }
 
     #[private]
     pub fn transfer_staked_cheddar_callback(&mut self, user: AccountId, amount: U128) {
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                log!("transferred Cheddar stake {} to @{}", amount.0, user);
+            }
+            PromiseResult::Failed => {
+                log!(
+                    "transferring Cheddar stake to @{} was failed. Recovering account state",
+                    user.clone(),
+                );
+                // recover cheddar
+                self.total_cheddar_stake =
+                    add_or_panic(self.total_cheddar_stake, amount.0, "total cheddar stake");
+                let mut v = self.recovered_vault(&user);
+                v.cheddar_staked = add_or_panic(v.cheddar_staked, amount.0, "vault cheddar stake");
+
+                self._recompute_stake(&mut v);
+                self.vaults.insert(&user, &v);
+            }
+        }
+    }
+
+    #[private]
+    pub fn withdraw_fees_callback(&mut self, amount: U128) {
         if promise_result_as_failed() {
             log!(
-                "transferring Cheddar stake to @{} was failed. Recovering account state",
-                user.clone(),
+                "transferring fees {} Cheddar failed. Recovering contract state",
+                amount.0
             );
-            // recover cheddar
-            self.total_cheddar_stake += amount.0;
-            let mut v = self.recovered_vault(&user);
-            v.cheddar_staked += amount.0;
-
-            self._recompute_stake(&mut v);
-            self.vaults.insert(&user, &v);
+            self.fee_collected += amount.0;
         }
     }
 
@@ -675,38 +2114,58 @@ impl Contract {
     pub fn withdraw_boost_nft_callback(
         &mut self,
         user: AccountId,
+        receiver: AccountId,
         contract_and_token_id: ContractNftTokenId,
         nft_ctr_idx: usize,
     ) {
-        if promise_result_as_failed() {
-            log!(
-                "transferring {} boost NFT failed. Recovering account state",
-                contract_and_token_id,
-            );
-            // recover boost NFT
-            let mut v = self.recovered_vault(&user);
-
-            self.total_boost[nft_ctr_idx] += 1;
-
-            v.boost_nft = contract_and_token_id;
-            self._recompute_stake(&mut v);
-            self.vaults.insert(&user, &v);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Successful(_) => {
+                log!(
+                    "transferred {} boost NFT to @{}",
+                    contract_and_token_id,
+                    receiver
+                );
+            }
+            PromiseResult::Failed => {
+                log!(
+                    "transferring {} boost NFT failed. Recovering account state",
+                    contract_and_token_id,
+                );
+                // recover boost NFT
+                let mut v = self.recovered_vault(&user);
+
+                self.total_boost[nft_ctr_idx] += 1;
+
+                if v.boost_nft.is_empty() {
+                    v.boost_nft = contract_and_token_id;
+                    self._recompute_stake(&mut v);
+                } else {
+                    // the boost NFT was swapped for another one
+                    v.boost_nft_recovered.push(contract_and_token_id);
+                }
+                self.vaults.insert(&user, &v);
+            }
         }
     }
 
     fn recovered_vault(&mut self, user: &AccountId) -> Vault {
         match self.vaults.get(user) {
-            Some(vault) => vault,
+            Some(mut vault) => {
+                self.sync_vault(&mut vault);
+                vault
+            }
             None => {
                 // If the vault was closed before by another TX, then we must recover the state
                 self.accounts_registered += 1;
+                self.accounts.insert(user);
                 self.new_vault()
             }
         }
     }
 
     /// State recovering.
-    /// If `is_staked` is `true` - push back NFT token to Vault
+    /// If `is_staked` is `true` - push back NFT token with its weight (`amount`) to Vault
     /// Else recover farmed tokens
     fn recover_state(
         &mut self,
@@ -718,14 +2177,31 @@ impl Contract {
     ) {
         let mut v = self.recovered_vault(&user);
 
-        // NFT contract id recovered
+        // NFT contract id recovered. Token ids are only unique within an NFT contract, so the
+        // recovered token is keyed by (contract_i, token_id).
         if is_staked {
-            v.staked[contract_i].push(token_id.unwrap());
+            let token_id = token_id.unwrap();
+            // don't panic in the callback: it would revert the whole recovery
+            if v.staked[contract_i].contains(&token_id) {
+                log!(
+                    "token {} @{} is already staked, skipping the recovery",
+                    token_id,
+                    self.stake_nft_tokens[contract_i]
+                );
+                return;
+            }
+            self.total_stake[contract_i] =
+                add_or_panic(self.total_stake[contract_i], 1, "total stake");
+            v.staked[contract_i].push(token_id);
+            v.staked_weights[contract_i].push(amount.unwrap());
         // FT contract id recovered
         } else {
             let amount = amount.unwrap();
             self.total_harvested[contract_i] -= amount;
+            self.farm_deposits[contract_i] += amount;
             v.farmed_recovered[contract_i] += amount;
+            v.farmed_transfer_failures[contract_i] =
+                v.farmed_transfer_failures[contract_i].saturating_add(1);
         }
 
         self._recompute_stake(&mut v);
@@ -735,27 +2211,69 @@ impl Contract {
     /// Returns the round number since `start`.
     /// If now < start  return 0.
     /// If now == start return 0.
-    /// if now == start + ROUND return 1...
+    /// if now == start + round_seconds return 1...
+    /// Returns the current round. Frozen at `paused_at_round` while the contract is paused.
     fn current_round(&self) -> u64 {
-        round_number(
+        if let Some(r) = self.paused_at_round {
+            return r;
+        }
+        round_number_with_len(
             self.farming_start,
             self.farming_end,
             env::block_timestamp() / SECOND,
+            self.round_seconds,
         )
     }
 
-    fn new_vault(&self) -> Vault {
-        Vault::new(
-            self.stake_nft_tokens.len(),
-            self.farm_tokens.len(),
-            self.reward_acc,
-        )
+    /// Returns the total number of farming rounds.
+    pub(crate) fn total_rounds(&self) -> u64 {
+        round_number_with_len(
+            self.farming_start,
+            self.farming_end,
+            self.farming_end,
+            self.round_seconds,
+        )
+    }
+
+    /// Moves `unstake_fee_cheddar` from the vault staked Cheddar to the collected fees.
+    /// The fee is capped at the vault staked Cheddar.
+    fn charge_unstake_fee(&mut self, vault: &mut Vault) {
+        let fee = std::cmp::min(self.unstake_fee_cheddar, vault.cheddar_staked);
+        if fee == 0 {
+            return;
+        }
+        vault.cheddar_staked -= fee;
+        self.total_cheddar_stake =
+            sub_or_panic(self.total_cheddar_stake, fee, "total cheddar stake");
+        self.fee_collected += fee;
+        log!("Charged {} Cheddar unstake fee", fee);
+    }
+
+    fn token_weight(&self, nft_contract_id: &NftContractId, token_id: &TokenId) -> Balance {
+        self.token_weights
+            .get(&(nft_contract_id.clone(), token_id.clone()))
+            .unwrap_or(DEFAULT_NFT_WEIGHT)
+    }
+
+    fn new_vault(&self) -> Vault {
+        let mut v = Vault::new(
+            self.stake_nft_tokens.len(),
+            self.farm_tokens.len(),
+            self.reward_acc,
+        );
+        v.stake_removals_applied = self.stake_nft_removals.len() as u64;
+        v
     }
 
     /// creates new empty account. User must deposit tokens using nft_transfer_call
-    fn create_account(&mut self, user: &AccountId) {
-        self.vaults.insert(&user, &self.new_vault());
+    fn create_account(&mut self, user: &AccountId, storage_deposit: Balance) {
+        let initial_storage = env::storage_usage();
+        let mut v = self.new_vault();
+        v.storage_deposit = storage_deposit;
+        self.vaults.insert(&user, &v);
+        self.accounts.insert(user);
         self.accounts_registered += 1;
+        self.update_storage_usage(user, &mut v, initial_storage);
     }
 
     fn assert_owner(&self) {
@@ -882,6 +2400,8 @@ mod tests {
             U128(CHEDDAR_RATE), // cheddar charge per 1 staked NFT
             acc_cheddar(),
             accounts(1), // treasury
+            false,
+            ROUND,
         );
         contract.check_vectors();
         testing_env!(context
@@ -893,6 +2413,29 @@ mod tests {
         (context, contract)
     }
 
+    /// creates a contract with the default tokens and the given time setup
+    fn new_contract(start: u64, end: u64, align_start: bool, round_seconds: u64) -> Contract {
+        Contract::new(
+            acc_owner(),
+            vec![acc_staking1(), acc_staking2()],
+            to_U128s(&vec![E24, E24 / 10]),
+            U128(RATE),
+            vec![acc_cheddar(), acc_farming2()],
+            to_U128s(&vec![E24, E24 / 2]),
+            start,
+            end,
+            vec![acc_nft_boost(), acc_nft_boost2(), acc_cheddy_nft()],
+            acc_cheddy_nft(),
+            BOOST,
+            CHEDDY_BOOST,
+            U128(CHEDDAR_RATE),
+            acc_cheddar(),
+            accounts(1),
+            align_start,
+            round_seconds,
+        )
+    }
+
     fn deposit_cheddar(ctx: &mut VMContextBuilder, ctr: &mut Contract, user: &AccountId) {
         testing_env!(ctx
             .attached_deposit(0)
@@ -980,6 +2523,99 @@ mod tests {
         assert_eq!(ctr.is_active, false);
     }
 
+    #[test]
+    fn test_pause_freezes_emission() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        let farmed = |ctx: &mut VMContextBuilder, ctr: &Contract, r: i64| {
+            testing_env!(ctx.block_timestamp(round(r)).build());
+            ctr.status(acc_u1()).unwrap().farmed_units.0
+        };
+        let start = ctr.farming_start;
+
+        // paused for 4 rounds
+        testing_env!(ctx
+            .predecessor_account_id(acc_owner())
+            .block_timestamp(round(3))
+            .build());
+        ctr.set_active(false);
+        assert_eq!(ctr.paused_at_round, Some(3));
+        assert_eq!(farmed(&mut ctx, &ctr, 3), 3 * RATE);
+        assert_eq!(
+            farmed(&mut ctx, &ctr, 6),
+            3 * RATE,
+            "nothing farmed while paused"
+        );
+        testing_env!(ctx.block_timestamp(round(7)).build());
+        ctr.set_active(true);
+        assert_eq!(ctr.paused_at_round, None);
+        assert_eq!(ctr.farming_start, start + 4 * ROUND);
+        assert_eq!(farmed(&mut ctx, &ctr, 7), 3 * RATE);
+        assert_eq!(farmed(&mut ctx, &ctr, 9), 5 * RATE);
+
+        // the farm emits for END active rounds
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(END + 4))
+            .build());
+        ctr.withdraw_crop();
+        assert_eq!(ctr.total_harvested[0], END as u128 * RATE);
+    }
+
+    #[test]
+    fn test_pause_resume_after_farming_end() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        let (start, end) = (ctr.farming_start, ctr.farming_end);
+
+        testing_env!(ctx
+            .predecessor_account_id(acc_owner())
+            .block_timestamp(round(3))
+            .build());
+        ctr.set_active(false);
+        // resumed half a round after the original farming end
+        let resumed_at = round(END + 5) + ROUND_NS / 2;
+        testing_env!(ctx.block_timestamp(resumed_at).build());
+        ctr.set_active(true);
+        // the paused time is postponed rounded up to whole rounds
+        let shift = (END as u64 + 3) * ROUND;
+        assert_eq!(ctr.farming_start, start + shift);
+        assert_eq!(ctr.farming_end, end + shift);
+        assert_eq!(ctr.status(user_1.clone()).unwrap().farmed_units.0, 3 * RATE);
+        // the farm resumes half a round back, at round 2.5: round 3 is already farmed
+        testing_env!(ctx.block_timestamp(resumed_at + ROUND_NS).build());
+        assert_eq!(ctr.status(user_1.clone()).unwrap().farmed_units.0, 3 * RATE);
+        testing_env!(ctx.block_timestamp(resumed_at + 2 * ROUND_NS).build());
+        assert_eq!(ctr.status(user_1.clone()).unwrap().farmed_units.0, 4 * RATE);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(2 * END + 4))
+            .build());
+        ctr.withdraw_crop();
+        assert_eq!(ctr.total_harvested[0], END as u128 * RATE);
+    }
+
     #[test]
     #[should_panic(expected = "can only be called by the owner")]
     fn test_set_active_not_admin() {
@@ -987,6 +2623,203 @@ mod tests {
         ctr.set_active(false);
     }
 
+    /// Copy of the `Vault` layout of the contract deployed before the migration. Kept
+    /// apart from `migration::LegacyVault`, so a drift of the legacy layout fails the tests.
+    #[derive(BorshSerialize, BorshDeserialize)]
+    struct BaselineVault {
+        reward_acc: Balance,
+        staked: Vec<TokenIds>,
+        min_stake: Balance,
+        farmed: Balance,
+        farmed_recovered: Vec<Balance>,
+        boost_nft: ContractNftTokenId,
+        cheddar_staked: Balance,
+    }
+
+    /// Copy of the `Contract` layout of the contract deployed before the migration.
+    #[derive(BorshSerialize)]
+    struct BaselineContract {
+        is_active: bool,
+        setup_finalized: bool,
+        owner_id: AccountId,
+        treasury: AccountId,
+        vaults: LookupMap<AccountId, BaselineVault>,
+        stake_nft_tokens: Vec<NftContractId>,
+        staked_units: u128,
+        stake_rates: Vec<u128>,
+        farm_tokens: Vec<AccountId>,
+        farm_token_rates: Vec<u128>,
+        farm_unit_emission: u128,
+        farm_deposits: Vec<u128>,
+        farming_start: u64,
+        farming_end: u64,
+        boost_nft_contracts: Vec<NftContractId>,
+        cheddy: NftContractId,
+        total_boost: Vec<Balance>,
+        nft_boost: u32,
+        cheddy_boost: u32,
+        total_harvested: Vec<Balance>,
+        reward_acc: u128,
+        reward_acc_round: u64,
+        total_stake: Vec<Balance>,
+        total_cheddar_stake: Balance,
+        accounts_registered: u64,
+        cheddar_rate: Balance,
+        cheddar: AccountId,
+    }
+
+    /// Writes the state of the previous contract version, with a user_1 vault staking the
+    /// `staked` NFTs and a boost NFT and an empty user_2 vault, and migrates it at round 3.
+    fn migrate_legacy_state(staked: Vec<String>) -> (VMContextBuilder, Contract) {
+        let staked_num = staked.len() as u128;
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        let mut vaults = LookupMap::new(b"v".to_vec());
+        vaults.insert(
+            &acc_u1(),
+            &BaselineVault {
+                reward_acc: 0,
+                staked: vec![staked],
+                min_stake: staked_num * E24,
+                farmed: 0,
+                farmed_recovered: vec![0, 0],
+                boost_nft: "1@b1".into(),
+                cheddar_staked: staked_num * CHEDDAR_RATE,
+            },
+        );
+        vaults.insert(
+            &acc_u2(),
+            &BaselineVault {
+                reward_acc: 0,
+                staked: vec![vec![]],
+                min_stake: 0,
+                farmed: 0,
+                farmed_recovered: vec![0, 0],
+                boost_nft: "".into(),
+                cheddar_staked: 0,
+            },
+        );
+        env::state_write(&BaselineContract {
+            is_active: true,
+            setup_finalized: true,
+            owner_id: ctr.owner_id.clone(),
+            treasury: ctr.treasury.clone(),
+            vaults,
+            stake_nft_tokens: ctr.stake_nft_tokens.clone(),
+            staked_units: staked_num * E24,
+            stake_rates: ctr.stake_rates.clone(),
+            farm_tokens: ctr.farm_tokens.clone(),
+            farm_token_rates: ctr.farm_token_rates.clone(),
+            farm_unit_emission: ctr.farm_unit_emission,
+            farm_deposits: ctr.farm_deposits.clone(),
+            farming_start: ctr.farming_start,
+            farming_end: ctr.farming_end,
+            boost_nft_contracts: ctr.boost_nft_contracts.clone(),
+            cheddy: ctr.cheddy.clone(),
+            total_boost: vec![0, 1, 0],
+            nft_boost: ctr.nft_boost,
+            cheddy_boost: ctr.cheddy_boost,
+            total_harvested: vec![0, 0],
+            reward_acc: 0,
+            reward_acc_round: 0,
+            total_stake: vec![staked_num],
+            total_cheddar_stake: staked_num * CHEDDAR_RATE,
+            accounts_registered: 2,
+            cheddar_rate: CHEDDAR_RATE,
+            cheddar: acc_cheddar(),
+        });
+
+        testing_env!(ctx
+            .predecessor_account_id(env::current_account_id())
+            .block_timestamp(round(3))
+            .build());
+        (ctx, Contract::migrate())
+    }
+
+    #[test]
+    fn test_migrate() {
+        let user_1 = acc_u1();
+        let (mut ctx, mut ctr) = migrate_legacy_state(vec!["t1".into()]);
+        assert!(!ctr.is_active);
+        assert_eq!(ctr.get_vaults_to_migrate(), 2);
+        // totals are carried over from the previous layout
+        assert_eq!(ctr.staked_units, E24);
+        assert_eq!(ctr.total_stake, vec![1]);
+        assert_eq!(ctr.total_boost, vec![0, 1, 0]);
+        assert_eq!(ctr.total_cheddar_stake, CHEDDAR_RATE);
+        assert_eq!(ctr.accounts_registered, 2);
+
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        assert_eq!(
+            ctr.migrate_vaults(vec![user_1.clone(), acc_u2(), acc_u3()]),
+            2
+        );
+        assert_eq!(ctr.migrate_vaults(vec![user_1.clone()]), 0);
+        assert_eq!(ctr.get_vaults_to_migrate(), 0);
+
+        let v = ctr.vaults.get(&user_1).unwrap();
+        assert_eq!(v.staked, vec![vec!["t1".to_string()]]);
+        assert_eq!(v.staked_weights, vec![vec![DEFAULT_NFT_WEIGHT]]);
+        assert_eq!(v.boost_nft, format!("{}@b1", acc_nft_boost2()));
+        assert_eq!(v.cheddar_staked, CHEDDAR_RATE);
+        // the storage deposit covers the vault storage, nothing is available to withdraw
+        assert!(v.storage_usage > 0);
+        let balance = ctr.storage_balance_of(user_1.clone()).unwrap();
+        assert_eq!(balance.total.0, STORAGE_COST);
+        assert_eq!(balance.available.0, 0);
+        // no boost NFT stays empty
+        let v2 = ctr.vaults.get(&acc_u2()).unwrap();
+        assert!(v2.boost_nft.is_empty());
+        assert!(v2.boost_nft_recovered.is_empty());
+        assert!(ctr.verify_invariants(0, 10));
+
+        ctr.set_active(true);
+        testing_env!(ctx.block_timestamp(round(5)).build());
+        assert!(ctr.status(user_1.clone()).unwrap().farmed_units.0 > 0);
+        // the migrated vault keeps working
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &acc_staking1(), "t2".into());
+        assert_eq!(ctr.vaults.get(&user_1).unwrap().staked[0].len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "vaults migration is not finished")]
+    fn test_migrate_activate_before_vaults_migration() {
+        let (mut ctx, mut ctr) = migrate_legacy_state(vec!["t1".into()]);
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.migrate_vaults(vec![acc_u1()]);
+        ctr.set_active(true);
+    }
+
+    #[test]
+    #[should_panic(expected = "Use `unstake` instead")]
+    fn test_migrate_close_above_max_transfer_num() {
+        // legacy vaults were not limited by `max_staked_per_account`, eg. the mainnet vault
+        // in `migration::tests` stakes 6 NFTs
+        let staked: Vec<String> = (1..=6).map(|i| format!("t{}", i)).collect();
+        assert!(staked.len() > NFT_UNITS_MAX_TRANSFER_NUM);
+        let (mut ctx, mut ctr) = migrate_legacy_state(staked);
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.migrate_vaults(vec![acc_u1(), acc_u2()]);
+        ctr.set_active(true);
+
+        testing_env!(ctx
+            .predecessor_account_id(acc_u1())
+            .attached_deposit(1)
+            .block_timestamp(round(5))
+            .build());
+        ctr.close();
+    }
+
     fn finalize(ctr: &mut Contract, farm_deposits: Vec<u128>) {
         ctr._setup_deposit(&acc_cheddar().into(), farm_deposits[0]);
         ctr._setup_deposit(&acc_farming2().into(), farm_deposits[1]);
@@ -1029,6 +2862,129 @@ mod tests {
         ctr.finalize_setup();
     }
 
+    #[test]
+    #[should_panic(
+        expected = "Deposit for token cheddar doesn't match the farm emission 24000000000000000000000000"
+    )]
+    fn test_finalize_setup_schedule_changed() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr._setup_deposit(&acc_cheddar(), 20 * E24);
+        ctr._setup_deposit(&acc_farming2(), 10 * E24);
+        // 2 more rounds
+        ctr.set_start_end(round(0) / SECOND, round(END + 2) / SECOND);
+        ctr.finalize_setup();
+    }
+
+    #[test]
+    fn test_farm_token_rates() {
+        let (_, ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        let (expected, _) = ctr.finalize_setup_expected();
+        assert_eq!(expected, vec![U128(20 * E24), U128(10 * E24)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "farm token rates must be positive")]
+    fn test_zero_farm_token_rate() {
+        testing_env!(VMContextBuilder::new().build());
+        Contract::new(
+            acc_owner(),
+            vec![acc_staking1(), acc_staking2()],
+            to_U128s(&vec![E24, E24 / 10]),
+            U128(RATE),
+            vec![acc_cheddar(), acc_farming2()],
+            to_U128s(&vec![E24, 0]),
+            round(0) / SECOND,
+            round(END) / SECOND,
+            vec![acc_nft_boost(), acc_nft_boost2(), acc_cheddy_nft()],
+            acc_cheddy_nft(),
+            BOOST,
+            CHEDDY_BOOST,
+            U128(CHEDDAR_RATE),
+            acc_cheddar(),
+            accounts(1),
+            false,
+            ROUND,
+        );
+    }
+
+    #[test]
+    fn test_set_start_end() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        let start = round(1) / SECOND;
+        ctr.set_start_end(start, start + 20 * ROUND);
+        assert_eq!(ctr.farming_start, start);
+        assert_eq!(ctr.total_rounds(), 20);
+    }
+
+    #[test]
+    fn test_set_start_end_after_finalize_same_rounds() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        let start = ctr.farming_start + ROUND;
+        ctr.set_start_end(start, start + END as u64 * ROUND);
+        assert_eq!(ctr.farming_start, start);
+        assert_eq!(ctr.total_rounds(), END as u64);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "cheddar farm deposit doesn't cover the farm emission 22000000000000000000000000"
+    )]
+    fn test_set_start_end_after_finalize() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        let start = ctr.farming_start;
+        ctr.set_start_end(start, start + (END as u64 + 1) * ROUND);
+    }
+
+    #[test]
+    #[should_panic(expected = "setup is finalized, the new schedule can't have fewer rounds")]
+    fn test_set_start_end_after_finalize_fewer_rounds() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        let start = ctr.farming_start;
+        ctr.set_start_end(start, start + (END as u64 - 1) * ROUND);
+    }
+
+    #[test]
+    fn test_set_start_end_live_farm() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id(acc_owner())
+            .block_timestamp(round(3))
+            .build());
+        let farmed = ctr.status(user_1.clone()).unwrap().farmed_units;
+        assert_eq!(farmed.0, 3 * RATE);
+        // the last round is shortened, the number of rounds stays the same
+        let start = ctr.farming_start;
+        ctr.set_start_end(start, ctr.farming_end - ROUND / 2);
+        assert_eq!(ctr.total_rounds(), END as u64);
+        assert_eq!(ctr.reward_acc_round, 3);
+        assert_eq!(ctr.status(user_1.clone()).unwrap().farmed_units, farmed);
+    }
+
+    #[test]
+    #[should_panic(expected = "farming already started, start can't be changed")]
+    fn test_set_start_end_move_start_live_farm() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        testing_env!(ctx.block_timestamp(round(3)).build());
+        let start = round(4) / SECOND;
+        ctr.set_start_end(start, start + END as u64 * ROUND);
+    }
+
     #[test]
     fn test_round_number() {
         let (mut ctx, ctr) = setup_contract(acc_u1(), 0, None, None, RATE, END);
@@ -1057,6 +3013,85 @@ mod tests {
         assert_eq!(total_rounds, 10);
     }
 
+    #[test]
+    fn test_round_at() {
+        let (mut ctx, ctr) = setup_contract(acc_u1(), 0, None, None, RATE, END);
+        for r in [-5, 0, 1, 3, END, END + 4] {
+            let ts = round(r) + ROUND_NS / 3;
+            testing_env!(ctx.block_timestamp(ts).build());
+            assert_eq!(
+                ctr.round_at(ts / SECOND),
+                ctr.current_round(),
+                "round {}",
+                r
+            );
+        }
+        assert_eq!(ctr.round_at(0), 0);
+        assert_eq!(ctr.round_at(ctr.farming_start - 1), 0);
+        assert_eq!(ctr.round_at(ctr.farming_start + ROUND), 1);
+        assert_eq!(ctr.round_at(u64::MAX), END as u64);
+    }
+
+    #[test]
+    fn test_round_number_custom_round_seconds() {
+        const HOUR: u64 = 60 * 60;
+        let mut ctx = VMContextBuilder::new();
+        testing_env!(ctx.build());
+        let start = 10 * HOUR;
+        let mut ctr = new_contract(start, start + 5 * HOUR, false, HOUR);
+        assert_eq!(ctr.get_contract_params().round_seconds, HOUR);
+        assert_eq!(ctr.current_round(), 0);
+
+        let at = |ctx: &mut VMContextBuilder, seconds: u64| {
+            testing_env!(ctx.block_timestamp(seconds * SECOND).build());
+        };
+        // a default length round doesn't advance the round
+        at(&mut ctx, start + ROUND);
+        assert_eq!(ctr.current_round(), 0);
+        at(&mut ctx, start + HOUR - 1);
+        assert_eq!(ctr.current_round(), 0);
+        at(&mut ctx, start + HOUR);
+        assert_eq!(ctr.current_round(), 1);
+        at(&mut ctx, start + 3 * HOUR + HOUR / 2);
+        assert_eq!(ctr.current_round(), 3);
+        at(&mut ctx, start + 5 * HOUR);
+        assert_eq!(ctr.current_round(), 5);
+        at(&mut ctx, start + 10 * HOUR);
+        assert_eq!(ctr.current_round(), 5);
+        assert_eq!(ctr.total_rounds(), 5);
+
+        // expected deposits are computed for 5 rounds
+        let (expected, _) = ctr.finalize_setup_expected();
+        assert_eq!(expected, to_U128s(&vec![5 * RATE, 5 * RATE / 2]));
+
+        // finalization must be done at least one round before the start
+        at(&mut ctx, start - HOUR - 1);
+        ctr._setup_deposit(&acc_cheddar(), 5 * RATE);
+        ctr._setup_deposit(&acc_farming2(), 5 * RATE / 2);
+        ctr.finalize_setup();
+    }
+
+    #[test]
+    #[should_panic(expected = "must be finalized at last before farm start")]
+    fn test_finalize_custom_round_seconds_too_late() {
+        const HOUR: u64 = 60 * 60;
+        let mut ctx = VMContextBuilder::new();
+        testing_env!(ctx.build());
+        let start = 10 * HOUR;
+        let mut ctr = new_contract(start, start + 5 * HOUR, false, HOUR);
+        ctr._setup_deposit(&acc_cheddar(), 5 * RATE);
+        ctr._setup_deposit(&acc_farming2(), 5 * RATE / 2);
+        testing_env!(ctx.block_timestamp((start - HOUR) * SECOND).build());
+        ctr.finalize_setup();
+    }
+
+    #[test]
+    #[should_panic(expected = "round_seconds must be between 1 and 604800")]
+    fn test_zero_round_seconds() {
+        testing_env!(VMContextBuilder::new().build());
+        new_contract(round(0) / SECOND, round(END) / SECOND, false, 0);
+    }
+
     #[test]
     #[should_panic(
         expected = "The attached deposit is less than the minimum storage balance (60000000000000000000000)"
@@ -1092,56 +3127,182 @@ mod tests {
     }
 
     #[test]
-    fn test_staking_nft_unit() {
+    fn test_storage_usage_grows_with_stake() {
         let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let nft_1 = acc_staking1();
         let (mut ctx, mut ctr) = setup_contract(
-            user_1.clone(),
+            acc_owner(),
             0,
-            Some(vec![acc_staking1()]),
+            Some(vec![nft_1.clone()]),
             Some(vec![E24]),
             RATE,
             END,
         );
         finalize(&mut ctr, vec![20 * E24, 10 * E24]);
-
-        let user_1_stake_token = "some_token_id".to_string();
-        let user_1_stake_contract = acc_staking1();
-        register_user_and_stake(
-            &mut ctx,
-            &mut ctr,
-            &user_1,
-            &user_1_stake_contract,
-            user_1_stake_token.clone(),
-            2, // round
-        );
-
-        let user_1_status = ctr.status(user_1.clone()).unwrap();
-        assert_eq!(user_1_status.stake.0, E24, "u1 should have staked units!");
-        unstake(
-            &mut ctx,
-            &mut ctr,
-            &user_1,
-            &user_1_stake_contract,
-            user_1_stake_token.clone(),
-        );
-        let status = ctr.status(user_1.clone());
-
-        assert!(status.is_none());
-        dbg!("{:?}", ctr.get_contract_params());
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "a1".into(), -2);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &nft_1, "b1".into(), -2);
+        for token_id in ["b2", "b3"] {
+            deposit_cheddar(&mut ctx, &mut ctr, &user_2);
+            stake(&mut ctx, &mut ctr, &user_2, &nft_1, token_id.into());
+        }
+        let usage_1 = ctr.get_vault(&user_1).storage_usage;
+        let usage_2 = ctr.get_vault(&user_2).storage_usage;
+        assert!(usage_1 > 0);
+        assert!(usage_2 > usage_1, "a multi-token staker uses more storage");
+
+        // unstaking releases the storage
+        unstake(&mut ctx, &mut ctr, &user_2, &nft_1, "b3".into());
+        let usage = ctr.get_vault(&user_2).storage_usage;
+        assert!(usage < usage_2 && usage > usage_1);
     }
 
     #[test]
-    #[should_panic]
-    fn test_staking_without_cheddar() {
+    #[should_panic(expected = "Not enough storage deposit")]
+    fn test_stake_storage_not_covered() {
         let user_1 = acc_u1();
-        let nft_1 = acc_staking1(); // nft contract 1
-
-        let (mut ctx, mut ctr) = setup_contract(user_1.clone(), 0, None, None, RATE, END);
-        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
-
-        assert!(
-            ctr.status(user_1.clone()).is_none(),
-            "u1 is not registered yet"
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        // token ids are stored in the vault: a long one doesn't fit in STORAGE_COST
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "x".repeat(10_000));
+    }
+
+    #[test]
+    fn test_storage_deposit_top_up_and_withdraw() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .attached_deposit(E24)
+            .build());
+        let balance = ctr.storage_deposit(None, None);
+        assert_eq!(balance.total.0, STORAGE_COST + E24);
+        assert_eq!(balance.available.0, E24);
+
+        // the top up covers a long token id
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "x".repeat(10_000));
+        let cost = u128::from(ctr.get_vault(&user_1).storage_usage) * env::storage_byte_cost();
+        assert!(cost > STORAGE_COST);
+        let balance = ctr.storage_balance_of(user_1.clone()).unwrap();
+        assert_eq!(balance.available.0, STORAGE_COST + E24 - cost);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .attached_deposit(1)
+            .build());
+        let balance = ctr.storage_withdraw(None);
+        assert_eq!(balance.total.0, cost);
+        assert_eq!(balance.available.0, 0);
+    }
+
+    #[test]
+    fn test_close_refunds_storage_deposit() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .attached_deposit(E24)
+            .build());
+        ctr.storage_deposit(None, None);
+        let cost = u128::from(ctr.get_vault(&user_1).storage_usage) * env::storage_byte_cost();
+
+        testing_env!(ctx.block_timestamp(round(2)).build());
+        close(&mut ctx, &mut ctr, &user_1);
+        let refunds: Vec<Balance> = near_sdk::test_utils::get_created_receipts()
+            .iter()
+            .filter(|r| r.receiver_id == user_1)
+            .flat_map(|r| r.actions.iter())
+            .filter_map(|a| match a {
+                near_sdk::mock::VmAction::Transfer { deposit } => Some(*deposit),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(refunds, vec![STORAGE_COST + E24 - cost]);
+    }
+
+    #[test]
+    fn test_staking_nft_unit() {
+        let user_1 = acc_u1();
+        let (mut ctx, mut ctr) = setup_contract(
+            user_1.clone(),
+            0,
+            Some(vec![acc_staking1()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        let user_1_stake_token = "some_token_id".to_string();
+        let user_1_stake_contract = acc_staking1();
+        register_user_and_stake(
+            &mut ctx,
+            &mut ctr,
+            &user_1,
+            &user_1_stake_contract,
+            user_1_stake_token.clone(),
+            2, // round
+        );
+
+        let user_1_status = ctr.status(user_1.clone()).unwrap();
+        assert_eq!(user_1_status.stake.0, E24, "u1 should have staked units!");
+        unstake(
+            &mut ctx,
+            &mut ctr,
+            &user_1,
+            &user_1_stake_contract,
+            user_1_stake_token.clone(),
+        );
+        let status = ctr.status(user_1.clone());
+
+        assert!(status.is_none());
+        dbg!("{:?}", ctr.get_contract_params());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_staking_without_cheddar() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1(); // nft contract 1
+
+        let (mut ctx, mut ctr) = setup_contract(user_1.clone(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        assert!(
+            ctr.status(user_1.clone()).is_none(),
+            "u1 is not registered yet"
         );
         // register user1 account and stake without Cheddar deposited before
         testing_env!(ctx.attached_deposit(STORAGE_COST).build());
@@ -1960,7 +4121,7 @@ mod tests {
             .block_timestamp(round(2) + 1000)
             .attached_deposit(1)
             .build());
-        ctr.withdraw_boost_nft();
+        ctr.withdraw_boost_nft(None);
 
         // check at round 4 - user1 should farm at equal rate as user2
         testing_env!(ctx.block_timestamp(round(3)).build());
@@ -2487,7 +4648,7 @@ mod tests {
             .block_timestamp(round(2) + 1000)
             .attached_deposit(1)
             .build());
-        ctr.withdraw_boost_nft();
+        ctr.withdraw_boost_nft(None);
 
         // check at round 4 - user2 should farm at equal rate as user4
         testing_env!(ctx.block_timestamp(round(3)).build());
@@ -2543,7 +4704,7 @@ mod tests {
         register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
         assert!(ctr.total_cheddar_stake == 1 * CHEDDAR_RATE, "staked 1 NFT");
 
-        // stake 4 more
+        // stake 4 more (max 5 staked NFTs per account)
         deposit_cheddar(&mut ctx, &mut ctr, &user_1);
         stake(&mut ctx, &mut ctr, &user_1, &nft_2, "2".into());
         deposit_cheddar(&mut ctx, &mut ctr, &user_1);
@@ -2552,10 +4713,6 @@ mod tests {
         stake(&mut ctx, &mut ctr, &user_1, &nft_2, "4".into());
         deposit_cheddar(&mut ctx, &mut ctr, &user_1);
         stake(&mut ctx, &mut ctr, &user_1, &nft_2, "5".into());
-        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
-        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "6".into());
-        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
-        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "7".into());
         // trying to unstake all from contract 1
         unstake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into());
         unstake(&mut ctx, &mut ctr, &user_1, &nft_1, "3".into());
@@ -2564,21 +4721,15 @@ mod tests {
         assert!(user_1_status.stake_tokens[0].is_empty());
         assert!(
             user_1_status.stake_tokens[1]
-                == Vec::<String>::from([
-                    "2".into(),
-                    "4".into(),
-                    "5".into(),
-                    "6".into(),
-                    "7".into()
-                ]),
+                == Vec::<String>::from(["2".into(), "4".into(), "5".into()]),
             "nft_2 staked should keeped"
         );
         assert!(
             user_1_status.total_cheddar_staked.0 == 5 * CHEDDAR_RATE,
-            "2 unstaked, now we have only 5 tokens from nft_2 contract"
+            "2 unstaked, the staked Cheddar is kept in the vault"
         );
         assert_eq!(ctr.total_stake[0], 0, "no tokens for nft_1 contract");
-        assert_eq!(ctr.total_stake[1], 5, "5 tokens for nft_2 contract");
+        assert_eq!(ctr.total_stake[1], 3, "3 tokens for nft_2 contract");
 
         // stake contract 1 (nft_1) again
         deposit_cheddar(&mut ctx, &mut ctr, &user_1);
@@ -2588,8 +4739,6 @@ mod tests {
         unstake(&mut ctx, &mut ctr, &user_1, &nft_2, "2".into());
         unstake(&mut ctx, &mut ctr, &user_1, &nft_2, "4".into());
         unstake(&mut ctx, &mut ctr, &user_1, &nft_2, "5".into());
-        unstake(&mut ctx, &mut ctr, &user_1, &nft_2, "6".into());
-        unstake(&mut ctx, &mut ctr, &user_1, &nft_2, "7".into());
 
         // status check
         let user_1_status = ctr.status(user_1.clone()).unwrap();
@@ -2601,8 +4750,8 @@ mod tests {
             "nft_1 staked should keeped"
         );
         assert!(
-            user_1_status.total_cheddar_staked.0 == 1 * CHEDDAR_RATE,
-            "5 unstaked, now we have only 1 token from nft_2 contract"
+            user_1_status.total_cheddar_staked.0 == 6 * CHEDDAR_RATE,
+            "3 unstaked, the staked Cheddar is kept in the vault"
         );
         assert_eq!(ctr.total_stake[0], 1, "1 token for nft_1 contract");
         assert_eq!(ctr.total_stake[1], 0, "no tokens for nft_2 contract");
@@ -2637,7 +4786,7 @@ mod tests {
         register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
         assert!(ctr.total_cheddar_stake == 1 * CHEDDAR_RATE, "staked 1 NFT");
 
-        // stake 4 more
+        // stake 4 more (max 5 staked NFTs per account)
         deposit_cheddar(&mut ctx, &mut ctr, &user_1);
         stake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
         deposit_cheddar(&mut ctx, &mut ctr, &user_1);
@@ -2645,24 +4794,3020 @@ mod tests {
         deposit_cheddar(&mut ctx, &mut ctr, &user_1);
         stake(&mut ctx, &mut ctr, &user_1, &nft_1, "4".into());
         deposit_cheddar(&mut ctx, &mut ctr, &user_1);
-        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "5".into());
-        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
-        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "6".into());
-        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
-        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "7".into());
+        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "5".into());
 
-        assert!(ctr.total_cheddar_stake == 7 * CHEDDAR_RATE, "staked 8 NFT");
-        assert!(ctr.total_stake[0] == 6, "staked 4 NFT from nft_1");
-        assert!(ctr.total_stake[1] == 1, "staked 3 NFT from nft_2");
+        assert!(ctr.total_cheddar_stake == 5 * CHEDDAR_RATE, "staked 5 NFT");
+        assert!(ctr.total_stake[0] == 4, "staked 4 NFT from nft_1");
+        assert!(ctr.total_stake[1] == 1, "staked 1 NFT from nft_2");
         assert!(
             ctr.status(user_1.clone()).unwrap().stake_tokens[0].len()
                 + ctr.status(user_1.clone()).unwrap().stake_tokens[1].len()
-                == 7,
-            "staked 7 NFT"
+                == 5,
+            "staked 5 NFT"
         );
 
-        // trying to unstake all by close() func call (MAX=7)
+        // trying to unstake all by close() func call (MAX=5)
         close(&mut ctx, &mut ctr, &user_1);
         assert!(ctr.status(user_1.clone()).is_none());
     }
+
+    #[test]
+    fn test_weighted_nft_stake() {
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let nft_1 = acc_staking1();
+
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        // rare NFT counts as 3 regular NFTs
+        ctr.set_token_weight(nft_1.clone(), "rare".into(), U128(3));
+        assert_eq!(ctr.get_token_weight(nft_1.clone(), "rare".into()).0, 3);
+        assert_eq!(ctr.get_token_weight(nft_1.clone(), "common".into()).0, 1);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "rare".into(), -2);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &nft_1, "common".into(), -2);
+        assert_eq!(ctr.status(user_1.clone()).unwrap().stake.0, 3 * E24);
+        assert_eq!(ctr.status(user_2.clone()).unwrap().stake.0, E24);
+        assert_eq!(ctr.staked_units, 4 * E24);
+        assert_eq!(
+            ctr.total_stake[0], 2,
+            "total stake counts tokens, not weights"
+        );
+
+        // weight changes don't alter existing stakes
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_token_weight(nft_1.clone(), "rare".into(), U128(10));
+
+        testing_env!(ctx.block_timestamp(round(2)).build());
+        assert_eq!(
+            ctr.status(user_1.clone()).unwrap().farmed_units.0,
+            2 * RATE * 3 / 4,
+            "user1 farms proportionally to the NFT weight"
+        );
+        assert_eq!(
+            ctr.status(user_2.clone()).unwrap().farmed_units.0,
+            2 * RATE / 4,
+            "user2 farms as a regular NFT"
+        );
+
+        // closing removes the recorded weight, not the current one
+        close(&mut ctx, &mut ctr, &user_1);
+        assert_eq!(ctr.staked_units, E24);
+    }
+
+    #[test]
+    #[should_panic(expected = "weight can't be bigger than 1000")]
+    fn test_set_token_weight_too_big() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr.set_token_weight(acc_staking1(), "rare".into(), U128(MAX_NFT_WEIGHT + 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_set_token_weight_not_owner() {
+        let (_, mut ctr) = setup_contract(acc_u1(), 0, None, None, RATE, END);
+        ctr.set_token_weight(acc_staking1(), "rare".into(), U128(3));
+    }
+
+    #[test]
+    fn test_set_treasury() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr.set_treasury(acc_u2());
+        assert_eq!(ctr.treasury, acc_u2());
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_set_treasury_not_owner() {
+        let (_, mut ctr) = setup_contract(acc_u1(), 0, None, None, RATE, END);
+        ctr.set_treasury(acc_u1());
+    }
+
+    #[test]
+    fn test_set_cheddar_contract() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        let new_cheddar: AccountId = "cheddar2.near".parse().unwrap();
+        ctr.set_cheddar_contract(new_cheddar.clone());
+        assert_eq!(ctr.cheddar, new_cheddar);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't change the Cheddar contract with")]
+    fn test_set_cheddar_contract_with_stake() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(
+            &mut ctx,
+            &mut ctr,
+            &acc_u1(),
+            &acc_staking1(),
+            "1".into(),
+            -2,
+        );
+        assert!(ctr.total_cheddar_stake > 0);
+
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_cheddar_contract("cheddar2.near".parse().unwrap());
+    }
+
+    #[test]
+    fn test_owner_migrate_vault() {
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        let vault = ctr.get_vault(&user_1);
+        let staked_units = ctr.staked_units;
+        let accounts_registered = ctr.accounts_registered;
+
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.owner_migrate_vault(user_1.clone(), user_2.clone());
+        assert!(ctr.vaults.get(&user_1).is_none());
+        let migrated = ctr.get_vault(&user_2);
+        assert_eq!(migrated.staked, vault.staked);
+        assert_eq!(migrated.cheddar_staked, vault.cheddar_staked);
+        assert_eq!(ctr.staked_units, staked_units);
+        assert_eq!(ctr.accounts_registered, accounts_registered);
+    }
+
+    #[test]
+    #[should_panic(expected = "destination account already has a vault")]
+    fn test_owner_migrate_vault_to_registered() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        for user in [acc_u1(), acc_u2()] {
+            testing_env!(ctx
+                .attached_deposit(STORAGE_COST)
+                .predecessor_account_id(user)
+                .build());
+            ctr.storage_deposit(None, None);
+        }
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.owner_migrate_vault(acc_u1(), acc_u2());
+    }
+
+    #[test]
+    #[should_panic(expected = "E10: account not found. Register the account.")]
+    fn test_owner_migrate_vault_no_vault() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr.owner_migrate_vault(acc_u1(), acc_u2());
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_owner_migrate_vault_not_owner() {
+        let (_, mut ctr) = setup_contract(acc_u1(), 0, None, None, RATE, END);
+        ctr.owner_migrate_vault(acc_u1(), acc_u2());
+    }
+
+    #[test]
+    fn test_reward_acc_view() {
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        assert_eq!(ctr.get_reward_acc(), (U128(0), 0));
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), 1);
+        let acc_r3 = ctr.compute_reward_acc_view(3);
+        assert_eq!(ctr.get_reward_acc().1, 1);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &nft_1, "2".into(), 3);
+        assert_eq!(ctr.get_reward_acc(), (acc_r3, 3));
+        assert_eq!(ctr.reward_acc, acc_r3.0);
+
+        let acc_r6 = ctr.compute_reward_acc_view(6);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        testing_env!(ctx.block_timestamp(round(6)).build());
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "3".into());
+        assert_eq!(ctr.get_reward_acc(), (acc_r6, 6));
+    }
+
+    #[test]
+    #[should_panic(expected = "round can't be before the last reward_acc update (3)")]
+    fn test_reward_acc_view_past_round() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), 3);
+        ctr.compute_reward_acc_view(2);
+    }
+
+    #[test]
+    fn test_max_staked_per_account() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_max_staked_per_account(3);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        for token_id in ["2", "3"] {
+            deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+            stake(&mut ctx, &mut ctr, &user_1, &nft_1, token_id.into());
+        }
+        assert_eq!(ctr.get_vault(&user_1).get_number_of_staked_tokens(), 3);
+
+        // the at-cap vault can be closed
+        testing_env!(ctx
+            .attached_deposit(1)
+            .predecessor_account_id(user_1.clone())
+            .build());
+        let receipt = ctr.close();
+        assert_eq!(receipt.nft_transfers, 3);
+        assert!(ctr.vaults.get(&user_1).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "You can stake at most 2 NFTs")]
+    fn test_max_staked_per_account_exceeded() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_max_staked_per_account(2);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+        // the NFT contract returns the token when the receiver panics
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "3".into());
+    }
+
+    #[test]
+    #[should_panic(expected = "max_staked_per_account must be between 1 and 5")]
+    fn test_max_staked_per_account_above_close_limit() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr.set_max_staked_per_account(NFT_UNITS_MAX_TRANSFER_NUM as u64 + 1);
+    }
+
+    #[test]
+    fn test_account_storage_usage() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "token-1".into(), -2);
+        let mut usage = ctr.get_account_storage_usage(user_1.clone()).0;
+        assert!(usage > 0);
+        for token_id in ["token-2", "token-3", "token-4", "token-5"] {
+            deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+            stake(&mut ctx, &mut ctr, &user_1, &nft_1, token_id.into());
+            let new_usage = ctr.get_account_storage_usage(user_1.clone()).0;
+            assert!(new_usage > usage, "usage must grow with staked tokens");
+            usage = new_usage;
+        }
+    }
+
+    #[test]
+    fn test_contract_version() {
+        let (_, ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        assert_eq!(ctr.get_contract_params().version, CONTRACT_PARAMS_VERSION);
+        assert_eq!(ctr.contract_version(), "1.0.0");
+    }
+
+    #[test]
+    fn test_contract_params_setup_and_boost() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        let params = ctr.get_contract_params();
+        assert!(!params.setup_finalized);
+        assert_eq!(params.cheddy, acc_cheddy_nft());
+        assert_eq!(params.nft_boost, BOOST);
+        assert_eq!(params.cheddy_boost, CHEDDY_BOOST);
+
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        assert!(ctr.get_contract_params().setup_finalized);
+    }
+
+    #[test]
+    fn test_contract_params_gauges() {
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &acc_staking1(), "1".into(), -2);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &acc_staking2(), "2".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_2);
+        stake(&mut ctx, &mut ctr, &user_2, &acc_staking1(), "3".into());
+        testing_env!(ctx
+            .predecessor_account_id(acc_nft_boost())
+            .signer_account_id(user_1.clone())
+            .build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+
+        let params = ctr.get_contract_params();
+        assert_eq!(params.total_staked, vec![U128(2), U128(1)]);
+        assert_eq!(params.total_nfts_staked, U128(3));
+        assert_eq!(params.total_boost_nfts, U128(1));
+        assert_eq!(params.accounts_registered, 2);
+    }
+
+    #[test]
+    fn test_get_vault_raw() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        // rewards are farmed, but the stored vault is not pinged
+        testing_env!(ctx
+            .predecessor_account_id(acc_owner())
+            .block_timestamp(round(5))
+            .build());
+        let stored = ctr.vaults.get(&user_1).unwrap();
+        let raw = ctr.get_vault_raw(user_1.clone()).unwrap();
+        assert_eq!(raw.farmed, U128(0));
+        assert_eq!(raw.reward_acc, U128(stored.reward_acc));
+        assert_eq!(raw.min_stake, U128(stored.min_stake));
+        assert_eq!(raw.cheddar_staked, U128(CHEDDAR_RATE));
+        assert_eq!(raw.staked, stored.staked);
+        assert_eq!(raw.farmed_recovered, vec![U128(0), U128(0)]);
+        assert!(raw.boost_nft.is_empty());
+        assert!(ctr.status(user_1.clone()).unwrap().farmed_units.0 > 0);
+
+        assert_eq!(ctr.get_vault_raw(acc_u2()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_get_vault_raw_not_owner() {
+        let (mut ctx, ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        testing_env!(ctx.predecessor_account_id(acc_u1()).build());
+        ctr.get_vault_raw(acc_u1());
+    }
+
+    #[test]
+    fn test_get_effective_stake_units() {
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &nft_1, "2".into(), -2);
+        testing_env!(ctx
+            .predecessor_account_id(acc_nft_boost())
+            .signer_account_id(user_1.clone())
+            .build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+
+        // same raw stake, only user_1 is boosted
+        let boosted = E24 + E24 * u128::from(BOOST) / BASIS_P;
+        assert_eq!(ctr.get_effective_stake_units(user_1.clone()), U128(boosted));
+        assert_eq!(ctr.get_effective_stake_units(user_2.clone()), U128(E24));
+        assert_eq!(ctr.status(user_2).unwrap().stake, U128(E24));
+        assert_eq!(ctr.staked_units, boosted + E24);
+        assert_eq!(ctr.get_effective_stake_units(acc_u3()), U128(0));
+    }
+
+    #[test]
+    fn test_unstake_v2() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+
+        testing_env!(ctx
+            .attached_deposit(1)
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        assert_eq!(
+            ctr.unstake_v2(&nft_1, "2".into()),
+            UnstakeResult {
+                remaining_tokens: vec!["1".into()],
+                farmed_units: U128(3 * RATE),
+                cheddar_refunded: U128(0),
+                account_closed: false,
+            }
+        );
+
+        // the last token closes the account
+        testing_env!(ctx.block_timestamp(round(5)).build());
+        assert_eq!(
+            ctr.unstake_v2(&nft_1, "1".into()),
+            UnstakeResult {
+                remaining_tokens: vec![],
+                farmed_units: U128(5 * RATE),
+                cheddar_refunded: U128(2 * CHEDDAR_RATE),
+                account_closed: true,
+            }
+        );
+        assert!(ctr.vaults.get(&user_1).is_none());
+        assert_eq!(ctr.total_harvested[0], 5 * RATE);
+    }
+
+    #[test]
+    fn test_relayer_unstake() {
+        let user_1 = acc_u1();
+        let relayer: AccountId = "relayer".parse().unwrap();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.add_relayer(relayer.clone());
+        assert_eq!(ctr.get_relayers(), vec![relayer.clone()]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+
+        // no yNEAR attached
+        testing_env!(ctx
+            .attached_deposit(0)
+            .predecessor_account_id(relayer.clone())
+            .build());
+        let left = ctr.relayer_unstake(user_1.clone(), &nft_1, "2".into());
+        assert_eq!(left, vec!["1".to_string()]);
+        assert_eq!(ctr.get_staked_nft_count(user_1.clone()), vec![1, 0]);
+        assert!(ctr.vaults.get(&relayer).is_none());
+
+        // the token goes back to the user
+        assert_eq!(nft_transfer_receiver(&nft_1, "2"), user_1.to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "caller is not a whitelisted relayer")]
+    fn test_relayer_unstake_not_whitelisted() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.add_relayer("relayer".parse().unwrap());
+        ctr.remove_relayer("relayer".parse().unwrap());
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id("relayer".parse().unwrap())
+            .build());
+        ctr.relayer_unstake(user_1, &nft_1, "1".into());
+    }
+
+    #[test]
+    fn test_unstake_cooldown() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_unstake_cooldown_rounds(1);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx.block_timestamp(round(2)).build());
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+        assert_eq!(ctr.get_vault(&user_1).last_action_round, 2);
+
+        testing_env!(ctx.block_timestamp(round(3)).build());
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+        assert_eq!(ctr.get_staked_nft_count(user_1), vec![1, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't unstake before round 3")]
+    fn test_unstake_cooldown_early() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_unstake_cooldown_rounds(1);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx.block_timestamp(round(2)).build());
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+    }
+
+    #[test]
+    fn test_unstake_cooldown_after_farm_end() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_unstake_cooldown_rounds(5);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), END - 1);
+
+        // staked after the farm end
+        testing_env!(ctx.block_timestamp(round(END + 1)).build());
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+        assert_eq!(ctr.get_staked_nft_count(user_1.clone()), vec![1, 0]);
+
+        close(&mut ctx, &mut ctr, &user_1);
+        assert!(ctr.vaults.get(&user_1).is_none());
+    }
+
+    #[test]
+    fn test_boost_lock() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_boost_lock_rounds(3);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), 1);
+        testing_env!(ctx.predecessor_account_id(acc_nft_boost()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+        assert_eq!(ctr.get_vault(&user_1).boost_staked_round, 1);
+
+        testing_env!(ctx
+            .attached_deposit(1)
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(4))
+            .build());
+        ctr.withdraw_boost_nft(None);
+        assert!(ctr.get_vault(&user_1).boost_nft.is_empty());
+    }
+
+    #[test]
+    fn test_withdraw_boost_nft_receiver() {
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        let deposit_boost = |ctx: &mut VMContextBuilder, ctr: &mut Contract, token_id: &str| {
+            testing_env!(ctx.predecessor_account_id(acc_nft_boost()).build());
+            ctr.nft_on_transfer(acc_u1(), acc_u1(), token_id.into(), "to boost".into());
+            testing_env!(ctx
+                .attached_deposit(ONE_YOCTO)
+                .predecessor_account_id(acc_u1())
+                .build());
+        };
+
+        // withdraw to self
+        deposit_boost(&mut ctx, &mut ctr, "b1");
+        ctr.withdraw_boost_nft(None);
+        assert_eq!(
+            nft_transfer_receiver(&acc_nft_boost(), "b1"),
+            user_1.to_string()
+        );
+        assert!(ctr.get_vault(&user_1).boost_nft.is_empty());
+
+        // withdraw to a third party account
+        deposit_boost(&mut ctx, &mut ctr, "b2");
+        ctr.withdraw_boost_nft(Some(user_2.clone()));
+        assert_eq!(
+            nft_transfer_receiver(&acc_nft_boost(), "b2"),
+            user_2.to_string()
+        );
+        assert!(ctr.get_vault(&user_1).boost_nft.is_empty());
+        assert!(ctr.vaults.get(&user_2).is_none());
+
+        // a failed transfer recovers the boost NFT to the caller vault
+        failed_promise_ctx(&mut ctx);
+        let boost = format!("{}@b2", acc_nft_boost());
+        ctr.withdraw_boost_nft_callback(user_1.clone(), user_2.clone(), boost.clone(), 0);
+        assert_eq!(ctr.get_vault(&user_1).boost_nft, boost);
+        assert!(ctr.vaults.get(&user_2).is_none());
+        assert_eq!(ctr.total_boost, vec![1, 0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "boost NFT is locked until round 4")]
+    fn test_boost_lock_withdraw_early() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_boost_lock_rounds(3);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), 1);
+        testing_env!(ctx.predecessor_account_id(acc_nft_boost()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+
+        testing_env!(ctx
+            .attached_deposit(1)
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        ctr.withdraw_boost_nft(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "boost NFT is locked until round 4")]
+    fn test_boost_lock_swap_early() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_boost_lock_rounds(3);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), 1);
+        testing_env!(ctx.predecessor_account_id(acc_nft_boost()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+
+        testing_env!(ctx
+            .predecessor_account_id(acc_nft_boost2())
+            .block_timestamp(round(3))
+            .build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b2".into(),
+            "to boost".into(),
+        );
+    }
+
+    #[test]
+    fn test_boost_lock_farm_end() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_boost_lock_rounds(100);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), 1);
+        testing_env!(ctx.predecessor_account_id(acc_nft_boost()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+
+        // the lock ends with the farm
+        testing_env!(ctx
+            .attached_deposit(1)
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(END + 1))
+            .build());
+        ctr.withdraw_boost_nft(None);
+        assert!(ctr.get_vault(&user_1).boost_nft.is_empty());
+        assert_eq!(ctr.total_boost, vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn test_boost_swap_total_boost() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), 1);
+        testing_env!(ctx.predecessor_account_id(acc_nft_boost()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+        assert_eq!(ctr.total_boost, vec![1, 0, 0]);
+
+        testing_env!(ctx.predecessor_account_id(acc_nft_boost2()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b2".into(),
+            "to boost".into(),
+        );
+        assert_eq!(ctr.total_boost, vec![0, 1, 0]);
+        assert!(ctr.verify_invariants(0, 10));
+    }
+
+    #[test]
+    fn test_boost_lock_paused() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_boost_lock_rounds(3);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), 1);
+        testing_env!(ctx.predecessor_account_id(acc_nft_boost()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_active(false);
+        testing_env!(ctx
+            .attached_deposit(1)
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(2))
+            .build());
+        ctr.withdraw_boost_nft(None);
+        assert!(ctr.get_vault(&user_1).boost_nft.is_empty());
+    }
+
+    #[test]
+    fn test_ft_on_transfer_msg() {
+        let user_1 = acc_u1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+
+        // setup deposits
+        testing_env!(ctx.predecessor_account_id(acc_cheddar()).build());
+        ctr.ft_on_transfer(acc_owner(), U128(20 * E24), "setup deposit".into());
+        testing_env!(ctx.predecessor_account_id(acc_farming2()).build());
+        ctr.ft_on_transfer(acc_owner(), U128(10 * E24), "setup reward deposit".into());
+        assert_eq!(ctr.farm_deposits, vec![20 * E24, 10 * E24]);
+        ctr.finalize_setup();
+
+        // reward topup
+        testing_env!(ctx.predecessor_account_id(acc_farming2()).build());
+        ctr.ft_on_transfer(acc_owner(), U128(5 * E24), "reward topup".into());
+        assert_eq!(ctr.farm_deposits, vec![20 * E24, 15 * E24]);
+
+        // cheddar stake
+        testing_env!(ctx
+            .attached_deposit(STORAGE_COST)
+            .predecessor_account_id(user_1.clone())
+            .build());
+        ctr.storage_deposit(None, None);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        assert_eq!(ctr.get_vault(&user_1).cheddar_staked, CHEDDAR_RATE);
+
+        // unknown msg is refunded
+        testing_env!(ctx.predecessor_account_id(acc_cheddar()).build());
+        match ctr.ft_on_transfer(user_1.clone(), U128(E24), "stake".into()) {
+            PromiseOrValue::Value(refund) => assert_eq!(refund.0, E24),
+            _ => panic!("expected a refund value"),
+        }
+        assert_eq!(ctr.get_vault(&user_1).cheddar_staked, CHEDDAR_RATE);
+        assert_eq!(ctr.farm_deposits, vec![20 * E24, 15 * E24]);
+    }
+
+    #[test]
+    fn test_reward_topup_extends_farm() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        // top up the deposits for 2 more rounds
+        testing_env!(ctx.block_timestamp(round(3)).build());
+        testing_env!(ctx.predecessor_account_id(acc_cheddar()).build());
+        ctr.ft_on_transfer(acc_owner(), U128(2 * RATE), "reward topup".into());
+        testing_env!(ctx.predecessor_account_id(acc_farming2()).build());
+        ctr.ft_on_transfer(acc_owner(), U128(RATE), "reward topup".into());
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        let (start, end) = (ctr.farming_start, ctr.farming_end);
+        ctr.set_start_end(start, end + 2 * ROUND);
+        assert_eq!(ctr.total_rounds(), END as u64 + 2);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(END + 2))
+            .build());
+        assert_eq!(
+            ctr.status(user_1.clone()).unwrap().farmed_units.0,
+            (END as u128 + 2) * RATE
+        );
+        ctr.withdraw_crop();
+        assert_eq!(ctr.total_harvested, vec![24 * E24, 12 * E24]);
+        assert_eq!(ctr.farm_deposits, vec![0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "reward topup is only possible after the setup is finalized")]
+    fn test_reward_topup_before_finalize() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        testing_env!(ctx.predecessor_account_id(acc_cheddar()).build());
+        ctr.ft_on_transfer(acc_owner(), U128(E24), "reward topup".into());
+    }
+
+    #[test]
+    fn test_verify_invariants() {
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "2".into());
+        testing_env!(ctx.predecessor_account_id(acc_nft_boost()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &nft_1, "3".into(), -1);
+        assert!(ctr.verify_invariants(0, 10));
+        assert!(ctr.verify_invariants(1, 1));
+
+        // total stake out of sync with the vaults
+        ctr.total_stake[0] += 1;
+        assert!(!ctr.verify_invariants(0, 10));
+    }
+
+    #[test]
+    fn test_set_stake_rate_zero() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) =
+            setup_contract(acc_owner(), 0, None, Some(vec![E24, E24]), RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        // only nft_1 staked: the min over both contracts is 0
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+        assert_eq!(ctr.get_vault(&user_1).min_stake, 0);
+
+        // disabled nft_2 doesn't dominate the min
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_stake_rate(nft_2.clone(), U128(0));
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "3".into());
+        assert_eq!(ctr.get_vault(&user_1).min_stake, 3 * E24);
+        assert_eq!(ctr.staked_units, 3 * E24);
+
+        // all contracts disabled
+        assert_eq!(min_stake(&vec![3, 0], &vec![0, 0]), 0);
+    }
+
+    #[test]
+    fn test_unstake_zero_rate() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) =
+            setup_contract(acc_owner(), 0, None, Some(vec![E24, E24]), RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "2".into());
+        assert_eq!(ctr.staked_units, E24);
+
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_stake_rate(nft_2.clone(), U128(0));
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_2, "2".into());
+        assert_eq!(ctr.total_stake[1], 0);
+        assert_eq!(ctr.get_vault(&user_1).min_stake, E24);
+        assert_eq!(ctr.staked_units, E24);
+    }
+
+    #[test]
+    #[should_panic(expected = "staking nft2 NFTs is disabled")]
+    fn test_stake_zero_rate() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_stake_rate(nft_2.clone(), U128(0));
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "2".into());
+    }
+
+    #[test]
+    #[should_panic(expected = "farming is running, a disabled NFT contract can't be enabled")]
+    fn test_set_stake_rate_enable_farming_running() {
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_stake_rate(nft_2.clone(), U128(0));
+        // enabled again before the farm starts
+        ctr.set_stake_rate(nft_2.clone(), U128(E24));
+        ctr.set_stake_rate(nft_2.clone(), U128(0));
+
+        testing_env!(ctx.block_timestamp(round(1)).build());
+        // a positive rate can change while farming
+        ctr.set_stake_rate(acc_staking1(), U128(2 * E24));
+        ctr.set_stake_rate(nft_2, U128(E24));
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_set_stake_rate_not_owner() {
+        let (_, mut ctr) = setup_contract(acc_u1(), 0, None, None, RATE, END);
+        ctr.set_stake_rate(acc_staking1(), U128(0));
+    }
+
+    #[test]
+    fn test_align_start_to_round() {
+        let context = VMContextBuilder::new();
+        testing_env!(context.build());
+        let unaligned_start = round(0) / SECOND + 7;
+        let new_contract =
+            |align: bool| new_contract(unaligned_start, round(END) / SECOND, align, ROUND);
+
+        let ctr = new_contract(false);
+        assert_eq!(ctr.get_contract_params().farming_start, unaligned_start);
+
+        let ctr = new_contract(true);
+        assert_eq!(ctr.get_contract_params().farming_start, round(1) / SECOND);
+        assert_eq!(ctr.farming_start % ROUND, 0);
+    }
+
+    #[test]
+    fn test_loyalty_boost() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        // 1% per round, max 5%
+        ctr.set_loyalty_boost(100, 500);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        assert_eq!(ctr.get_vault(&user_1).min_stake, E24);
+
+        let withdraw_crop_at = |ctx: &mut VMContextBuilder, ctr: &mut Contract, r: i64| {
+            testing_env!(ctx
+                .attached_deposit(0)
+                .predecessor_account_id(user_1.clone())
+                .block_timestamp(round(r))
+                .build());
+            ctr.withdraw_crop();
+            ctr.get_vault(&user_1).min_stake
+        };
+
+        // boost grows over rounds
+        assert_eq!(withdraw_crop_at(&mut ctx, &mut ctr, 2), E24 * 102 / 100);
+        assert_eq!(withdraw_crop_at(&mut ctx, &mut ctr, 4), E24 * 104 / 100);
+        // up to the cap
+        assert_eq!(withdraw_crop_at(&mut ctx, &mut ctr, 7), E24 * 105 / 100);
+        assert_eq!(ctr.staked_units, E24 * 105 / 100);
+
+        // staking one more NFT resets the boost
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t2".into());
+        assert_eq!(ctr.get_vault(&user_1).min_stake, 2 * E24);
+        assert_eq!(ctr.staked_units, 2 * E24);
+        assert_eq!(withdraw_crop_at(&mut ctx, &mut ctr, 8), 2 * E24 * 101 / 100);
+    }
+
+    #[test]
+    fn test_add_remove_stake_nft_contract() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let nft_3: AccountId = "nft3".parse().unwrap();
+
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone(), nft_2.clone()]),
+            Some(vec![E24, 0]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        // vault created before the new contract is added
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        assert_eq!(ctr.get_vault(&user_1).min_stake, E24);
+
+        // farming is running: the contract is added disabled
+        testing_env!(ctx
+            .predecessor_account_id(acc_owner())
+            .block_timestamp(round(1))
+            .build());
+        ctr.add_stake_nft_contract(nft_3.clone(), U128(0));
+        assert_eq!(
+            ctr.stake_nft_tokens,
+            vec![nft_1.clone(), nft_2.clone(), nft_3.clone()]
+        );
+        assert_eq!(ctr.stake_rates, vec![E24, 0, 0]);
+        assert_eq!(ctr.total_stake, vec![1, 0, 0]);
+
+        // the stakes are unchanged
+        ctr.ping(user_1.clone());
+        let v = ctr.get_vault(&user_1);
+        assert_eq!(v.staked, vec![vec!["t1".to_string()], vec![], vec![]]);
+        assert_eq!(v.min_stake, E24);
+        assert_eq!(ctr.staked_units, E24);
+
+        ctr.remove_stake_nft_contract(nft_2.clone());
+        assert_eq!(ctr.stake_nft_tokens, vec![nft_1.clone(), nft_3.clone()]);
+        assert_eq!(ctr.stake_rates, vec![E24, 0]);
+        assert_eq!(ctr.total_stake, vec![1, 0]);
+
+        // the vault is migrated and stake units are recomputed on the next interaction
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(2))
+            .build());
+        ctr.ping(user_1.clone());
+        let v = ctr.get_vault(&user_1);
+        assert_eq!(v.staked, vec![vec!["t1".to_string()], vec![]]);
+        assert_eq!(v.min_stake, E24);
+        assert_eq!(ctr.staked_units, E24);
+    }
+
+    #[test]
+    #[should_panic(expected = "farming is running, the NFT contract can only be added with rate 0")]
+    fn test_add_stake_nft_contract_farming_running() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        testing_env!(ctx.block_timestamp(round(1)).build());
+        ctr.add_stake_nft_contract("nft3".parse().unwrap(), U128(E24));
+    }
+
+    #[test]
+    #[should_panic(expected = "nothing to harvest")]
+    fn test_withdraw_crop_nothing_farmed() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        // staked before the farm starts, so nothing is farmed yet
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(-1))
+            .build());
+        ctr.withdraw_crop();
+    }
+
+    #[test]
+    fn test_stake_approved_transfer() {
+        let user_1 = acc_u1();
+        let operator: AccountId = "market".parse().unwrap();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+
+        // the operator transfers the user's NFT using its approval
+        testing_env!(ctx
+            .predecessor_account_id(nft_1.clone())
+            .signer_account_id(operator.clone())
+            .build());
+        ctr.nft_on_transfer(
+            operator.clone(),
+            user_1.clone(),
+            "t2".into(),
+            "to farm".into(),
+        );
+
+        assert!(ctr.vaults.get(&operator).is_none());
+        assert_eq!(
+            ctr.status(user_1).unwrap().stake_tokens,
+            vec![vec!["t1".to_string(), "t2".to_string()], vec![]]
+        );
+        assert_eq!(ctr.total_stake, vec![2, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_OWNER_NOT_SIGNER")]
+    fn test_stake_transfer_signer_not_owner_nor_sender() {
+        let user_1 = acc_u1();
+        let operator: AccountId = "market".parse().unwrap();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+
+        testing_env!(ctx
+            .predecessor_account_id(nft_1.clone())
+            .signer_account_id(acc_u2())
+            .build());
+        ctr.nft_on_transfer(operator, user_1, "t2".into(), "to farm".into());
+    }
+
+    #[test]
+    fn test_get_unharvested_global() {
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &nft_1, "t2".into(), 2);
+
+        testing_env!(ctx.block_timestamp(round(5)).build());
+        let farmed_1 = ctr.status(user_1).unwrap().farmed_units.0;
+        let farmed_2 = ctr.status(user_2).unwrap().farmed_units.0;
+        assert!(farmed_1 > farmed_2 && farmed_2 > 0);
+        assert_eq!(ctr.get_unharvested_global(0, 10).0, farmed_1 + farmed_2);
+        // pages
+        assert_eq!(ctr.get_unharvested_global(0, 1).0, farmed_1);
+        assert_eq!(ctr.get_unharvested_global(1, 1).0, farmed_2);
+        assert_eq!(ctr.get_unharvested_global(2, 1).0, 0);
+    }
+
+    /// user_1 stakes before the farming start, user_2 stakes in the middle of round 2.
+    /// Returns the farmed units of both at round 4.
+    fn farmed_with_mid_round_stake(pro_rata: bool) -> (u128, u128) {
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_pro_rata_accrual(pro_rata);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        testing_env!(ctx
+            .attached_deposit(STORAGE_COST)
+            .predecessor_account_id(user_2.clone())
+            .signer_account_id(user_2.clone())
+            .build());
+        ctr.storage_deposit(None, None);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_2);
+
+        testing_env!(ctx.block_timestamp(round(2) + ROUND_NS / 2).build());
+        stake(&mut ctx, &mut ctr, &user_2, &nft_1, "t2".into());
+
+        testing_env!(ctx.block_timestamp(round(4)).build());
+        (
+            ctr.status(user_1).unwrap().farmed_units.0,
+            ctr.status(user_2).unwrap().farmed_units.0,
+        )
+    }
+
+    #[test]
+    fn test_whole_round_accrual_mid_round_stake() {
+        // user_2 earns the full round 2
+        let (farmed_1, farmed_2) = farmed_with_mid_round_stake(false);
+        assert_eq!(farmed_1, 2 * RATE + RATE);
+        assert_eq!(farmed_2, RATE);
+    }
+
+    #[test]
+    fn test_pro_rata_accrual_mid_round_stake() {
+        // user_2 earns half of round 2, the first half goes to user_1 alone
+        let (farmed_1, farmed_2) = farmed_with_mid_round_stake(true);
+        assert_eq!(farmed_1, 2 * RATE + RATE / 2 + RATE * 3 / 4);
+        assert_eq!(farmed_2, RATE * 3 / 4);
+        // the emission is the same as with whole rounds
+        assert_eq!(farmed_1 + farmed_2, 4 * RATE);
+    }
+
+    #[test]
+    fn test_pro_rata_accrual_mid_round_status() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+
+        testing_env!(ctx.block_timestamp(round(1) + ROUND_NS / 4).build());
+        assert_eq!(ctr.status(user_1.clone()).unwrap().farmed_units.0, RATE);
+
+        // settles the whole-round rewards before switching the mode
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_pro_rata_accrual(true);
+        assert!(ctr.get_contract_params().pro_rata_accrual);
+        let status = ctr.status(user_1.clone()).unwrap();
+        assert_eq!(status.farmed_units.0, RATE + RATE / 4);
+        assert_eq!(status.timestamp, (round(1) + ROUND_NS / 4) / SECOND);
+
+        // the total emission is not changed at the farming end
+        testing_env!(ctx.block_timestamp(round(END + 1)).build());
+        assert_eq!(
+            ctr.status(user_1).unwrap().farmed_units.0,
+            END as u128 * RATE
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "can't remove NFT contract with staked tokens")]
+    fn test_remove_stake_nft_contract_with_stake() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.remove_stake_nft_contract(nft_1);
+    }
+
+    #[test]
+    fn test_remove_stake_nft_contract_pending_transfer() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "t2".into());
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_2, "t2".into());
+        assert_eq!(ctr.total_stake, vec![1, 0]);
+        assert_eq!(ctr.pending_stake_transfers, vec![0, 1]);
+
+        // the contract can be removed once the transfer is resolved
+        promise_result_ctx(&mut ctx, PromiseResult::Successful(vec![]));
+        ctr.transfer_staked_callback(user_1.clone(), nft_2.clone(), "t2".into(), U128(E24));
+        assert_eq!(ctr.pending_stake_transfers, vec![0, 0]);
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.remove_stake_nft_contract(nft_2);
+        assert_eq!(ctr.stake_nft_tokens, vec![nft_1]);
+        assert_eq!(ctr.pending_stake_transfers, vec![0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't remove NFT contract with pending NFT transfers")]
+    fn test_remove_stake_nft_contract_transfer_in_flight() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "t2".into());
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_2, "t2".into());
+
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.remove_stake_nft_contract(nft_2);
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_add_stake_nft_contract_not_owner() {
+        let (_, mut ctr) = setup_contract(acc_u1(), 0, None, None, RATE, END);
+        ctr.add_stake_nft_contract("nft3".parse().unwrap(), U128(E24));
+    }
+
+    #[test]
+    #[should_panic(expected = "Math underflow while decreasing total cheddar stake")]
+    fn test_total_cheddar_stake_underflow() {
+        let user_1 = acc_u1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(
+            &mut ctx,
+            &mut ctr,
+            &user_1,
+            &acc_staking1(),
+            "t1".into(),
+            -2,
+        );
+        assert_eq!(ctr.total_cheddar_stake, CHEDDAR_RATE);
+        // mismatched recovery: more Cheddar is returned than it's staked in total
+        ctr.transfer_staked_cheddar(user_1, Some(2 * CHEDDAR_RATE));
+    }
+
+    #[test]
+    #[should_panic(expected = "Math underflow while decreasing staked units")]
+    fn test_staked_units_underflow() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        // staked units were already removed (double counted), so closing can't remove them
+        ctr.staked_units = 0;
+        close(&mut ctx, &mut ctr, &user_1);
+    }
+
+    #[test]
+    fn test_harvest_exhausted_farm_deposit() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        // rewards are over-subscribed: the first farm token covers only 1 round
+        ctr.farm_deposits[0] = RATE;
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+
+        testing_env!(ctx
+            .attached_deposit(0)
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        ctr.withdraw_crop();
+        assert_eq!(ctr.farm_deposits, vec![0, 10 * E24 - 3 * RATE / 2]);
+        assert_eq!(ctr.total_harvested, vec![RATE, 3 * RATE / 2]);
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered, vec![2 * RATE, 0]);
+
+        // nothing left to transfer, everything is recovered
+        testing_env!(ctx.block_timestamp(round(5)).build());
+        ctr.withdraw_crop();
+        assert_eq!(ctr.total_harvested, vec![RATE, 5 * RATE / 2]);
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered, vec![4 * RATE, 0]);
+
+        // closing the account without deposit doesn't panic: everything stays recovered
+        testing_env!(ctx.block_timestamp(round(6)).build());
+        close(&mut ctx, &mut ctr, &user_1);
+        assert_eq!(ctr.total_harvested, vec![RATE, 6 * RATE / 2]);
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered, vec![5 * RATE, 0]);
+
+        // once the deposit is topped up, closing the recovered account pays out the rewards
+        ctr.farm_deposits[0] = 10 * RATE;
+        close(&mut ctx, &mut ctr, &user_1);
+        assert_eq!(ft_transfer_amount(&acc_cheddar(), &user_1), 5 * RATE);
+        assert_eq!(ctr.total_harvested[0], 6 * RATE);
+        assert_eq!(ctr.farm_deposits[0], 5 * RATE);
+        assert!(ctr.vaults.get(&user_1).is_none());
+    }
+
+    #[test]
+    fn test_total_farmed_units() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        assert_eq!(ctr.total_farmed_units().0, 0);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+
+        testing_env!(ctx.block_timestamp(round(3)).build());
+        assert_eq!(ctr.total_farmed_units().0, 3 * RATE);
+
+        // the cap holds at the farm end: everything emitted is harvested, nothing more
+        testing_env!(ctx.block_timestamp(round(END + 5)).build());
+        assert_eq!(ctr.total_farmed_units().0, END as u128 * RATE);
+        close(&mut ctx, &mut ctr, &user_1);
+        assert_eq!(ctr.total_harvested, vec![20 * E24, 10 * E24]);
+        assert_eq!(ctr.farm_deposits, vec![0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "cheddar harvest would exceed the total emission")]
+    fn test_harvest_above_emission() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        // accounting bug: harvests recorded without being farmed
+        ctr.total_harvested[0] = 9 * RATE;
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        ctr.withdraw_crop();
+    }
+
+    #[test]
+    fn test_use_farmed_cheddar_for_stake() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        let total_cheddar_stake = ctr.total_cheddar_stake;
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        // farmed Cheddar (3 * RATE) covers the next slot (1.5 * RATE), the rest stays farmed
+        ctr.cheddar_rate = 3 * E24;
+        ctr.use_farmed_cheddar_for_stake();
+        let v = ctr.get_vault(&user_1);
+        assert_eq!(v.farmed, 3 * E24);
+        assert_eq!(v.cheddar_staked, CHEDDAR_RATE + 3 * E24);
+        assert_eq!(ctr.total_cheddar_stake, total_cheddar_stake + 3 * E24);
+        assert_eq!(
+            ctr.farm_deposits,
+            vec![20 * E24 - 3 * E24, 10 * E24 - 3 * E24 / 2]
+        );
+        assert_eq!(ctr.total_harvested, vec![3 * E24, 3 * E24 / 2]);
+        // the second farm token is transferred to the user
+        assert_eq!(ft_transfer_amount(&acc_farming2(), &user_1), 3 * E24 / 2);
+        assert!(ctr.verify_invariants(0, 10));
+
+        // the stake is topped up to the next full slot
+        ctr.cheddar_rate = 4 * E24;
+        assert_eq!(v.cheddar_staked % ctr.cheddar_rate, 2 * E24);
+        ctr.use_farmed_cheddar_for_stake();
+        let v = ctr.get_vault(&user_1);
+        assert_eq!(v.farmed, E24);
+        assert_eq!(v.cheddar_staked % ctr.cheddar_rate, 0);
+        assert_eq!(ctr.total_harvested, vec![5 * E24, 5 * E24 / 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "not enough farmed Cheddar to stake one more NFT")]
+    fn test_use_farmed_cheddar_for_stake_partial() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+
+        // farmed Cheddar (3 * RATE) doesn't cover cheddar_rate
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        ctr.use_farmed_cheddar_for_stake();
+    }
+
+    #[test]
+    #[should_panic(expected = "no farmed Cheddar")]
+    fn test_use_farmed_cheddar_for_stake_twice() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        // the next slot takes all the farmed Cheddar (3 * RATE)
+        ctr.cheddar_rate = CHEDDAR_RATE + 3 * RATE;
+        ctr.use_farmed_cheddar_for_stake();
+        // the farmed Cheddar can't be used twice
+        ctr.use_farmed_cheddar_for_stake();
+    }
+
+    #[test]
+    fn test_get_deposits_health() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        assert_eq!(
+            ctr.get_deposits_health(),
+            vec![
+                (acc_cheddar(), U128(20 * E24), U128(20 * E24), true),
+                (acc_farming2(), U128(10 * E24), U128(10 * E24), true)
+            ]
+        );
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx.block_timestamp(round(4)).build());
+        let health = ctr.get_deposits_health();
+        assert_eq!(health[0].2, U128(6 * RATE));
+        assert!(health.iter().all(|(_, _, _, ok)| *ok));
+
+        // drained deposit
+        ctr.farm_deposits[1] = RATE;
+        let health = ctr.get_deposits_health();
+        assert_eq!(
+            health[1],
+            (acc_farming2(), U128(RATE), U128(6 * RATE / 2), false)
+        );
+        assert!(health[0].3);
+    }
+
+    #[test]
+    fn test_withdraw_farmed_recovered_all() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        ctr.withdraw_crop();
+        let harvested = ctr.total_harvested.clone();
+        assert_eq!(harvested, vec![3 * RATE, 3 * RATE / 2]);
+        // both transfers failed
+        failed_promise_ctx(&mut ctx);
+        ctr.transfer_farmed_callback(user_1.clone(), 0, harvested[0].into());
+        ctr.transfer_farmed_callback(user_1.clone(), 1, harvested[1].into());
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered, harvested);
+
+        testing_env!(ctx.predecessor_account_id(user_1.clone()).build());
+        ctr.withdraw_farmed_recovered_all();
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered, vec![0, 0]);
+        assert_eq!(ctr.total_harvested, harvested);
+        assert_eq!(ft_transfer_amount(&acc_cheddar(), &user_1), harvested[0]);
+        assert_eq!(ft_transfer_amount(&acc_farming2(), &user_1), harvested[1]);
+
+        // the second token transfer fails again: it's recovered
+        failed_promise_ctx(&mut ctx);
+        ctr.transfer_farmed_callback(user_1.clone(), 1, harvested[1].into());
+        assert_eq!(
+            ctr.get_vault(&user_1).farmed_recovered,
+            vec![0, harvested[1]]
+        );
+        assert_eq!(ctr.total_harvested, vec![harvested[0], 0]);
+    }
+
+    #[test]
+    fn test_unstake_keep_open() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx
+            .attached_deposit(1)
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        let remaining = ctr.unstake_keep_open(&nft_1, "1".into());
+        assert!(remaining.is_empty());
+        assert_eq!(ctr.total_stake, vec![0]);
+        assert_eq!(ctr.staked_units, 0);
+        let v = ctr.get_vault(&user_1);
+        assert!(check_all_empty(&v.staked));
+        assert_eq!(v.farmed, 3 * RATE);
+        assert_eq!(v.cheddar_staked, CHEDDAR_RATE);
+
+        // the account is still registered and farmed units can be withdrawn
+        testing_env!(ctx.attached_deposit(0).block_timestamp(round(5)).build());
+        ctr.withdraw_crop();
+        assert_eq!(ctr.get_vault(&user_1).farmed, 0);
+        assert_eq!(ctr.total_harvested, vec![3 * RATE, 3 * RATE / 2]);
+        assert!(ctr.storage_balance_of(user_1.clone()).is_some());
+    }
+
+    #[test]
+    fn test_reward_multiplier() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        // 2x rewards in rounds 2 and 3: 2 extra rounds of emission
+        ctr.set_reward_multiplier(20_000, 2, 4);
+        assert_eq!(
+            ctr.finalize_setup_expected().0,
+            vec![U128(24 * E24), U128(12 * E24)]
+        );
+        finalize(&mut ctr, vec![24 * E24, 12 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        let farmed = |ctx: &mut VMContextBuilder, ctr: &Contract, r: i64| {
+            testing_env!(ctx.block_timestamp(round(r)).build());
+            ctr.status(user_1.clone()).unwrap().farmed_units.0
+        };
+        assert_eq!(farmed(&mut ctx, &ctr, 2), 2 * RATE);
+        assert_eq!(farmed(&mut ctx, &ctr, 3), 4 * RATE);
+        assert_eq!(farmed(&mut ctx, &ctr, 4), 6 * RATE);
+        assert_eq!(farmed(&mut ctx, &ctr, 5), 7 * RATE);
+        assert_eq!(ctr.total_farmed_units().0, 7 * RATE);
+
+        testing_env!(ctx.block_timestamp(round(END)).build());
+        close(&mut ctx, &mut ctr, &user_1);
+        assert_eq!(ctr.total_harvested, vec![24 * E24, 12 * E24]);
+        assert_eq!(ctr.farm_deposits, vec![0, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected deposit for token cheddar is 24000000000000000000000000")]
+    fn test_reward_multiplier_deposit() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr.set_reward_multiplier(20_000, 2, 4);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+    }
+
+    #[test]
+    fn test_swap_boost_nft() {
+        let user_1 = acc_u1();
+        let user_2 = acc_u2();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_2, &nft_1, "t2".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id(acc_nft_boost())
+            .signer_account_id(user_1.clone())
+            .build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+        let boosted = |boost: u32| E24 + E24 * u128::from(boost) / BASIS_P;
+        assert_eq!(ctr.get_vault(&user_1).min_stake, boosted(BOOST));
+
+        // swap for a Cheddy at round 2
+        testing_env!(ctx
+            .predecessor_account_id(acc_cheddy_nft())
+            .block_timestamp(round(2))
+            .build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "c1".into(),
+            "to boost".into(),
+        );
+        let v = ctr.get_vault(&user_1);
+        assert_eq!(v.boost_nft, format!("{}@c1", acc_cheddy_nft()));
+        assert_eq!(
+            v.min_stake,
+            boosted(CHEDDY_BOOST),
+            "boost updated in the same round"
+        );
+        assert_eq!(ctr.total_boost, vec![0, 0, 1]);
+        assert_eq!(ctr.staked_units, E24 + boosted(CHEDDY_BOOST));
+
+        // old boost NFT is returned to the user
+        assert_eq!(
+            nft_transfer_receiver(&acc_nft_boost(), "b1"),
+            user_1.to_string()
+        );
+
+        // user1 farmed with the boost for all rounds
+        testing_env!(ctx.block_timestamp(round(4)).build());
+        let farmed_1 = ctr.status(user_1.clone()).unwrap().farmed_units.0;
+        // stake units in basis points of the base stake
+        let bp = |boost: u32| BASIS_P + u128::from(boost);
+        let expected = 2 * RATE * bp(BOOST) / (BASIS_P + bp(BOOST))
+            + 2 * RATE * bp(CHEDDY_BOOST) / (BASIS_P + bp(CHEDDY_BOOST));
+        // reward accumulator precision
+        assert!(
+            expected.abs_diff(farmed_1) < expected / 1_000_000,
+            "{} != {}",
+            farmed_1,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_swap_boost_nft_return_failed() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+
+        testing_env!(ctx.predecessor_account_id(acc_nft_boost()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "b1".into(),
+            "to boost".into(),
+        );
+        testing_env!(ctx.predecessor_account_id(acc_cheddy_nft()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "c1".into(),
+            "to boost".into(),
+        );
+        assert_eq!(ctr.total_boost, vec![0, 0, 1]);
+
+        // returning the old boost NFT failed
+        failed_promise_ctx(&mut ctx);
+        let old_boost = format!("{}@b1", acc_nft_boost());
+        ctr.withdraw_boost_nft_callback(user_1.clone(), user_1.clone(), old_boost.clone(), 0);
+        let v = ctr.get_vault(&user_1);
+        assert_eq!(v.boost_nft, format!("{}@c1", acc_cheddy_nft()));
+        assert_eq!(v.boost_nft_recovered, vec![old_boost]);
+        assert_eq!(ctr.total_boost, vec![1, 0, 1]);
+
+        // and can be withdrawn again
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        ctr.withdraw_boost_nft_recovered();
+        assert!(ctr.get_vault(&user_1).boost_nft_recovered.is_empty());
+        assert_eq!(ctr.total_boost, vec![0, 0, 1]);
+    }
+
+    #[test]
+    fn test_harvest() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "t1".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        assert_eq!(ctr.status(user_1.clone()).unwrap().farmed_units.0, 3 * RATE);
+        let status = ctr.harvest();
+        assert_eq!(status.farmed_units.0, 0);
+        assert_eq!(status.farmed_tokens, vec![U128(0), U128(0)]);
+        assert_eq!(status.stake.0, E24);
+        assert_eq!(status.stake_tokens, vec![vec!["t1".to_string()]]);
+        assert_eq!(ctr.total_harvested, vec![3 * RATE, 3 * RATE / 2]);
+    }
+
+    #[test]
+    fn test_get_staked_token_ids() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "a1".into(), -2);
+        for token_id in ["a2", "a3"] {
+            deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+            stake(&mut ctx, &mut ctr, &user_1, &nft_1, token_id.into());
+        }
+        for token_id in ["b1", "b2"] {
+            deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+            stake(&mut ctx, &mut ctr, &user_1, &nft_2, token_id.into());
+        }
+
+        let page = |from_index: u64, limit: u64, nft: &AccountId| {
+            ctr.get_staked_token_ids(user_1.clone(), nft.clone(), from_index, limit)
+        };
+        assert_eq!(page(0, 2, &nft_1), vec!["a1", "a2"]);
+        assert_eq!(page(2, 2, &nft_1), vec!["a3"]);
+        assert_eq!(page(3, 2, &nft_1), Vec::<TokenId>::new());
+        assert_eq!(page(0, 10, &nft_2), vec!["b1", "b2"]);
+        assert_eq!(page(1, 1, &nft_2), vec!["b2"]);
+        assert_eq!(page(100, 1, &nft_2), Vec::<TokenId>::new());
+
+        // unregistered account
+        assert!(ctr
+            .get_staked_token_ids(acc_u2(), nft_1.clone(), 0, 10)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_same_token_id_on_two_contracts() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "1".into());
+        assert_eq!(ctr.total_stake, vec![1, 1]);
+
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_2, "1".into());
+        assert_eq!(
+            ctr.get_staked_token_ids(user_1.clone(), nft_1.clone(), 0, 10),
+            vec!["1"]
+        );
+        assert!(ctr
+            .get_staked_token_ids(user_1.clone(), nft_2.clone(), 0, 10)
+            .is_empty());
+        assert_eq!(ctr.total_stake, vec![1, 0]);
+
+        // failed transfer: the token is recovered to the nft_2 slot only
+        failed_promise_ctx(&mut ctx);
+        ctr.transfer_staked_callback(user_1.clone(), nft_2.clone(), "1".into(), U128(1));
+        assert_eq!(
+            ctr.get_staked_token_ids(user_1.clone(), nft_1.clone(), 0, 10),
+            vec!["1"]
+        );
+        assert_eq!(
+            ctr.get_staked_token_ids(user_1.clone(), nft_2.clone(), 0, 10),
+            vec!["1"]
+        );
+        assert_eq!(ctr.total_stake, vec![1, 1]);
+        assert!(ctr.verify_invariants(0, 10));
+
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into());
+        assert!(ctr
+            .get_staked_token_ids(user_1.clone(), nft_1.clone(), 0, 10)
+            .is_empty());
+        assert_eq!(
+            ctr.get_staked_token_ids(user_1.clone(), nft_2.clone(), 0, 10),
+            vec!["1"]
+        );
+        assert_eq!(ctr.total_stake, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_transfer_staked_failed_removed_contract() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "1".into());
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_2, "1".into());
+
+        // transfer sent before the upgrade, so not counted as pending
+        ctr.pending_stake_transfers[1] = 0;
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.remove_stake_nft_contract(nft_2.clone());
+
+        failed_promise_ctx(&mut ctx);
+        ctr.transfer_staked_callback(user_1.clone(), nft_2.clone(), "1".into(), U128(E24));
+        let v = ctr.get_vault(&user_1);
+        assert_eq!(v.staked, vec![vec!["1".to_string()]]);
+        assert_eq!(v.staked_nft_recovered, vec![(nft_2.clone(), "1".to_string())]);
+        assert_eq!(ctr.total_stake, vec![1]);
+        assert!(ctr.verify_invariants(0, 10));
+
+        // withdrawing fails again: the NFT stays recovered
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        ctr.withdraw_staked_nft_recovered();
+        assert!(ctr.get_vault(&user_1).staked_nft_recovered.is_empty());
+        assert_eq!(nft_transfer_receiver(&nft_2, "1"), user_1.to_string());
+        failed_promise_ctx(&mut ctx);
+        ctr.transfer_recovered_nft_callback(user_1.clone(), nft_2.clone(), "1".into());
+        assert_eq!(
+            ctr.get_vault(&user_1).staked_nft_recovered,
+            vec![(nft_2.clone(), "1".to_string())]
+        );
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .attached_deposit(ONE_YOCTO)
+            .build());
+        ctr.withdraw_staked_nft_recovered();
+        promise_result_ctx(&mut ctx, PromiseResult::Successful(vec![]));
+        ctr.transfer_recovered_nft_callback(user_1.clone(), nft_2, "1".into());
+        assert!(ctr.get_vault(&user_1).staked_nft_recovered.is_empty());
+    }
+
+    #[test]
+    fn test_transfer_staked_failed_already_staked() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        // the recovery is skipped rather than reverted
+        failed_promise_ctx(&mut ctx);
+        ctr.transfer_staked_callback(user_1.clone(), nft_1.clone(), "1".into(), U128(E24));
+        assert_eq!(ctr.get_vault(&user_1).staked, vec![vec!["1".to_string()], vec![]]);
+        assert_eq!(ctr.total_stake, vec![1, 0]);
+        assert!(ctr.verify_invariants(0, 10));
+    }
+
+    #[test]
+    #[should_panic(expected = "token 1 @nft1 is already staked")]
+    fn test_stake_same_token_twice() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into());
+    }
+
+    #[test]
+    fn test_get_min_stake_breakdown() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+        let (mut ctx, mut ctr) =
+            setup_contract(acc_owner(), 0, None, Some(vec![E24, E24]), RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        assert!(ctr.get_min_stake_breakdown(user_1.clone()).is_empty());
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "a1".into(), -2);
+        for token_id in ["a2", "a3"] {
+            deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+            stake(&mut ctx, &mut ctr, &user_1, &nft_1, token_id.into());
+        }
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "b1".into());
+
+        let breakdown = ctr.get_min_stake_breakdown(user_1.clone());
+        assert_eq!(
+            breakdown,
+            vec![(nft_1, U128(3 * E24)), (nft_2.clone(), U128(E24))]
+        );
+        // nft_2 is the bottleneck
+        let limiter = breakdown.iter().min_by_key(|(_, s)| s.0).unwrap();
+        assert_eq!(limiter.0, nft_2);
+        assert_eq!(ctr.get_vault(&user_1).min_stake, limiter.1 .0);
+    }
+
+    #[test]
+    #[should_panic(expected = "only cheddar can be staked with the cheddar stake msg")]
+    fn test_cheddar_stake_wrong_token() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        // a worthless token can't be credited as staked Cheddar
+        testing_env!(ctx
+            .attached_deposit(0)
+            .predecessor_account_id(acc_farming2())
+            .build());
+        ctr.ft_on_transfer(user_1, U128(CHEDDAR_RATE), "cheddar stake".into());
+    }
+
+    #[test]
+    fn test_cheddar_stake_refund() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        let total_cheddar_stake = ctr.total_cheddar_stake;
+
+        testing_env!(ctx
+            .attached_deposit(0)
+            .predecessor_account_id(acc_cheddar())
+            .build());
+        // exact amount: no refund
+        let refund = ctr.ft_on_transfer(user_1.clone(), U128(CHEDDAR_RATE), "cheddar stake".into());
+        assert!(matches!(refund, PromiseOrValue::Value(U128(0))));
+        assert_eq!(ctr.get_vault(&user_1).cheddar_staked, 2 * CHEDDAR_RATE);
+
+        // more than cheddar_rate: the difference is refunded
+        let refund = ctr.ft_on_transfer(
+            user_1.clone(),
+            U128(CHEDDAR_RATE + 7),
+            "cheddar stake".into(),
+        );
+        assert!(matches!(refund, PromiseOrValue::Value(U128(7))));
+        assert_eq!(ctr.get_vault(&user_1).cheddar_staked, 3 * CHEDDAR_RATE);
+        assert_eq!(
+            ctr.total_cheddar_stake,
+            total_cheddar_stake + 2 * CHEDDAR_RATE
+        );
+    }
+
+    #[test]
+    fn test_status_timestamp() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        let start = ctr.farming_start;
+
+        let timestamp = |ctr: &Contract| ctr.status(user_1.clone()).unwrap().timestamp;
+        testing_env!(ctx.block_timestamp(round(0)).build());
+        assert_eq!(timestamp(&ctr), start);
+        testing_env!(ctx.block_timestamp(round(1)).build());
+        assert_eq!(timestamp(&ctr), start + ROUND);
+        // in the middle of the third round: settled up to the end of the second one
+        testing_env!(ctx.block_timestamp(round(2) + ROUND_NS / 2).build());
+        assert_eq!(timestamp(&ctr), start + 2 * ROUND);
+        testing_env!(ctx.block_timestamp(round(END + 3)).build());
+        assert_eq!(timestamp(&ctr), ctr.farming_end);
+    }
+
+    #[test]
+    fn test_ping() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id(acc_u2())
+            .block_timestamp(round(3) + ROUND_NS / 2)
+            .build());
+        let expected = ctr.status(user_1.clone()).unwrap().farmed_units;
+        assert_eq!(expected.0, 3 * RATE);
+        ctr.ping(user_1.clone());
+        assert_eq!(ctr.vaults.get(&user_1).unwrap().farmed, expected.0);
+        assert_eq!(ctr.status(user_1.clone()).unwrap().farmed_units, expected);
+
+        // unregistered accounts are ignored
+        ctr.ping(acc_u3());
+        assert!(ctr.vaults.get(&acc_u3()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "token not staked by this account")]
+    fn test_unstake_not_staked_token() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        register_user_and_stake(&mut ctx, &mut ctr, &acc_u2(), &nft_1, "2".into(), -2);
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 5 farm tokens are supported, got 6")]
+    fn test_too_many_farm_tokens() {
+        testing_env!(VMContextBuilder::new().build());
+        let farm_tokens: Vec<AccountId> = (0..6)
+            .map(|i| format!("farm{}.near", i).parse().unwrap())
+            .collect();
+        Contract::new(
+            acc_owner(),
+            vec![acc_staking1(), acc_staking2()],
+            to_U128s(&vec![E24, E24 / 10]),
+            U128(RATE),
+            farm_tokens,
+            to_U128s(&vec![E24; 6]),
+            round(0) / SECOND,
+            round(END) / SECOND,
+            vec![acc_nft_boost(), acc_nft_boost2(), acc_cheddy_nft()],
+            acc_cheddy_nft(),
+            BOOST,
+            CHEDDY_BOOST,
+            U128(CHEDDAR_RATE),
+            acc_cheddar(),
+            accounts(1),
+            false,
+            ROUND,
+        );
+    }
+
+    #[test]
+    fn test_token_indexes() {
+        let (_, ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![acc_staking1(), acc_farming2()]),
+            None,
+            RATE,
+            END,
+        );
+        assert_eq!(ctr.token_indexes(acc_farming2()), (Some(1), Some(1)));
+        assert_eq!(ctr.token_indexes(acc_staking1()), (Some(0), None));
+        assert_eq!(ctr.token_indexes(acc_cheddar()), (None, Some(0)));
+        assert_eq!(ctr.token_indexes(acc_u1()), (None, None));
+    }
+
+    #[test]
+    fn test_transfer_farmed_callback_result() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        ctr.withdraw_crop();
+
+        let callback = |ctx: &mut VMContextBuilder, ctr: &mut Contract, result: PromiseResult| {
+            promise_result_ctx(ctx, result);
+            ctr.transfer_farmed_callback(acc_u1(), 0, (3 * RATE).into());
+        };
+        callback(&mut ctx, &mut ctr, PromiseResult::Successful(vec![]));
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered, vec![0, 0]);
+        callback(&mut ctx, &mut ctr, PromiseResult::Failed);
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered, vec![3 * RATE, 0]);
+    }
+
+    #[test]
+    fn test_min_harvest_amount() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_min_harvest_amount(vec![U128(0), U128(2 * RATE)]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        let transfers_to = |token: &AccountId| {
+            near_sdk::test_utils::get_created_receipts()
+                .iter()
+                .filter(|r| &r.receiver_id == token)
+                .count()
+        };
+
+        // the second farm token amount (3*RATE/2) is below the threshold
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        ctr.withdraw_crop();
+        assert_eq!(transfers_to(&acc_cheddar()), 1);
+        assert_eq!(transfers_to(&acc_farming2()), 0);
+        assert_eq!(ctr.total_harvested, vec![3 * RATE, 0]);
+        assert_eq!(
+            ctr.get_vault(&user_1).farmed_recovered,
+            vec![0, 3 * RATE / 2]
+        );
+
+        // together with the kept amount it reaches the threshold
+        testing_env!(ctx.block_timestamp(round(5)).build());
+        ctr.withdraw_crop();
+        assert_eq!(transfers_to(&acc_cheddar()), 1);
+        assert_eq!(transfers_to(&acc_farming2()), 1);
+        assert_eq!(ctr.total_harvested, vec![5 * RATE, 5 * RATE / 2]);
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered, vec![0, 0]);
+
+        // closing the account pays out the kept amount, even below the threshold
+        testing_env!(ctx.block_timestamp(round(6)).build());
+        ctr.withdraw_crop();
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered, vec![0, RATE / 2]);
+        close(&mut ctx, &mut ctr, &user_1);
+        assert_eq!(ft_transfer_amount(&acc_farming2(), &user_1), RATE / 2);
+        assert_eq!(ctr.total_harvested, vec![6 * RATE, 3 * RATE]);
+        assert!(ctr.vaults.get(&user_1).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "entered unreachable code")]
+    fn test_callback_not_ready() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        promise_result_ctx(&mut ctx, PromiseResult::NotReady);
+        ctr.transfer_staked_cheddar_callback(acc_u1(), CHEDDAR_RATE.into());
+    }
+
+    #[test]
+    fn test_get_staked_nft_count() {
+        let user_1 = acc_u1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        assert_eq!(ctr.get_staked_nft_count(user_1.clone()), vec![0, 0]);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &acc_staking1(), "1".into(), -2);
+        for id in ["2", "3"] {
+            deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+            stake(&mut ctx, &mut ctr, &user_1, &acc_staking2(), id.into());
+        }
+        assert_eq!(ctr.get_staked_nft_count(user_1.clone()), vec![1, 2]);
+        assert_eq!(ctr.get_vault(&user_1).get_number_of_staked_tokens(), 3);
+    }
+
+    #[test]
+    fn test_new_single_reward() {
+        let nft_1 = acc_staking1();
+        // the contracts share the mocked storage, so each one has its own staker
+        let new_farm = |single: bool, user: &AccountId| {
+            testing_env!(VMContextBuilder::new().build());
+            let (farm_token, start, end) = (acc_cheddar(), round(0) / SECOND, round(END) / SECOND);
+            let mut ctr = if single {
+                Contract::new_single_reward(SingleRewardInitArgs {
+                    owner_id: acc_owner(),
+                    stake_nft_tokens: vec![nft_1.clone()],
+                    stake_rates: to_U128s(&vec![E24]),
+                    farm_token,
+                    emission: U128(RATE),
+                    farming_start: start,
+                    farming_end: end,
+                    boost_nft_contracts: vec![acc_nft_boost(), acc_nft_boost2(), acc_cheddy_nft()],
+                    cheddy: acc_cheddy_nft(),
+                    nft_boost: BOOST,
+                    cheddy_boost: CHEDDY_BOOST,
+                    cheddar_rate: U128(CHEDDAR_RATE),
+                    cheddar: acc_cheddar(),
+                    treasury: accounts(1),
+                    align_start_to_round: false,
+                    round_seconds: ROUND,
+                })
+            } else {
+                Contract::new(
+                    acc_owner(),
+                    vec![nft_1.clone()],
+                    to_U128s(&vec![E24]),
+                    U128(RATE),
+                    vec![farm_token],
+                    to_U128s(&vec![E24]),
+                    start,
+                    end,
+                    vec![acc_nft_boost(), acc_nft_boost2(), acc_cheddy_nft()],
+                    acc_cheddy_nft(),
+                    BOOST,
+                    CHEDDY_BOOST,
+                    U128(CHEDDAR_RATE),
+                    acc_cheddar(),
+                    accounts(1),
+                    false,
+                    ROUND,
+                )
+            };
+            let mut ctx = VMContextBuilder::new();
+            testing_env!(ctx.block_timestamp(round(-10)).build());
+            let (deposit, _) = ctr.finalize_setup_expected();
+            ctr._setup_deposit(&acc_cheddar(), deposit[0].0);
+            ctr.finalize_setup();
+            register_user_and_stake(&mut ctx, &mut ctr, user, &nft_1, user.to_string(), -2);
+            testing_env!(ctx.block_timestamp(round(3)).build());
+            ctr
+        };
+        let (user_1, user_2) = (acc_u1(), acc_u2());
+        let single = new_farm(true, &user_1);
+        let multi = new_farm(false, &user_2);
+        assert_eq!(single.farm_tokens, multi.farm_tokens);
+        assert_eq!(single.farm_token_rates, multi.farm_token_rates);
+        assert_eq!(single.farm_unit_emission, multi.farm_unit_emission);
+        assert_eq!(single.farm_deposits, multi.farm_deposits);
+        assert_eq!(
+            single.get_single_reward_status(user_1.clone()),
+            Some(U128(3 * RATE))
+        );
+        assert_eq!(
+            multi.status(user_2).unwrap().farmed_tokens,
+            vec![U128(3 * RATE)]
+        );
+        assert_eq!(single.get_single_reward_status(acc_u3()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a single reward farm: 2 farm tokens")]
+    fn test_single_reward_status_multi_token_farm() {
+        let (_, ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr.get_single_reward_status(acc_u1());
+    }
+
+    #[test]
+    fn test_stuck_farm_tokens() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        ctr.withdraw_crop();
+
+        let callback = |ctx: &mut VMContextBuilder, ctr: &mut Contract, result: PromiseResult| {
+            promise_result_ctx(ctx, result);
+            ctr.transfer_farmed_callback(acc_u1(), 0, (3 * RATE).into());
+        };
+        for _ in 0..MAX_FARMED_TRANSFER_FAILURES - 1 {
+            callback(&mut ctx, &mut ctr, PromiseResult::Failed);
+            assert!(ctr.get_stuck_farm_tokens(user_1.clone()).is_empty());
+            testing_env!(ctx.predecessor_account_id(user_1.clone()).build());
+            ctr.withdraw_farmed_recovered(&acc_cheddar());
+        }
+        // a successful transfer resets the failures
+        callback(&mut ctx, &mut ctr, PromiseResult::Successful(vec![]));
+        assert_eq!(ctr.get_vault(&user_1).farmed_transfer_failures, vec![0, 0]);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(6))
+            .build());
+        ctr.withdraw_crop();
+        for i in 0..MAX_FARMED_TRANSFER_FAILURES {
+            if i > 0 {
+                testing_env!(ctx.predecessor_account_id(user_1.clone()).build());
+                ctr.withdraw_farmed_recovered(&acc_cheddar());
+            }
+            callback(&mut ctx, &mut ctr, PromiseResult::Failed);
+        }
+        assert_eq!(
+            ctr.get_stuck_farm_tokens(user_1.clone()),
+            vec![acc_cheddar()]
+        );
+        assert!(ctr.get_stuck_farm_tokens(acc_u2()).is_empty());
+
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.reset_farmed_transfer_failures(user_1.clone(), acc_cheddar());
+        assert!(ctr.get_stuck_farm_tokens(user_1.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_get_top_stakers() {
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        assert!(ctr.get_top_stakers(10).is_empty());
+
+        let stakers = [(acc_u1(), 1), (acc_u2(), 3), (acc_u3(), 2)];
+        for (user, n) in stakers.iter() {
+            register_user_and_stake(&mut ctx, &mut ctr, user, &nft_1, format!("{}-0", user), -2);
+            for i in 1..*n {
+                deposit_cheddar(&mut ctx, &mut ctr, user);
+                stake(&mut ctx, &mut ctr, user, &nft_1, format!("{}-{}", user, i));
+            }
+        }
+
+        assert_eq!(
+            ctr.get_top_stakers(10),
+            vec![
+                (acc_u2(), U128(3 * E24)),
+                (acc_u3(), U128(2 * E24)),
+                (acc_u1(), U128(E24))
+            ]
+        );
+        assert_eq!(
+            ctr.get_top_stakers(2),
+            vec![(acc_u2(), U128(3 * E24)), (acc_u3(), U128(2 * E24))]
+        );
+        assert_eq!(ctr.get_top_stakers(1), vec![(acc_u2(), U128(3 * E24))]);
+        assert!(ctr.get_top_stakers(0).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "limit can't be bigger than 100")]
+    fn test_get_top_stakers_limit_too_big() {
+        let (_, ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr.get_top_stakers(101);
+    }
+
+    #[test]
+    fn test_get_cheddar_stake_requirement() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        assert_eq!(
+            ctr.get_cheddar_stake_requirement(user_1.clone(), 2).0,
+            2 * CHEDDAR_RATE
+        );
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        assert_eq!(
+            ctr.get_cheddar_stake_requirement(user_1.clone(), 2).0,
+            2 * CHEDDAR_RATE
+        );
+
+        // Cheddar deposited for the next NFT
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        assert_eq!(
+            ctr.get_cheddar_stake_requirement(user_1.clone(), 2).0,
+            CHEDDAR_RATE
+        );
+        assert_eq!(ctr.get_cheddar_stake_requirement(user_1.clone(), 1).0, 0);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "User need at least 555000000000000000000000000 to stake one more token. Got 554999999999999999999999999"
+    )]
+    fn test_cheddar_stake_insufficient() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx
+            .attached_deposit(0)
+            .predecessor_account_id(acc_cheddar())
+            .build());
+        ctr.ft_on_transfer(
+            user_1.clone(),
+            U128(CHEDDAR_RATE - 1),
+            "cheddar stake".into(),
+        );
+    }
+
+    #[test]
+    fn test_transfer_ownership() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr.propose_owner(acc_u1());
+        assert_eq!(ctr.pending_owner, Some(acc_u1()));
+        assert_eq!(ctr.owner_id, acc_owner());
+
+        testing_env!(ctx.predecessor_account_id(acc_u1()).build());
+        ctr.accept_ownership();
+        assert_eq!(ctr.owner_id, acc_u1());
+        assert_eq!(ctr.pending_owner, None);
+        // the new owner can manage the contract
+        ctr.set_active(false);
+        assert!(!ctr.is_active);
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the pending owner")]
+    fn test_accept_ownership_wrong_account() {
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr.propose_owner(acc_u1());
+        testing_env!(ctx.predecessor_account_id(acc_u2()).build());
+        ctr.accept_ownership();
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_propose_owner_not_owner() {
+        let (_, mut ctr) = setup_contract(acc_u1(), 0, None, None, RATE, END);
+        ctr.propose_owner(acc_u1());
+    }
+
+    /// sets the context of a callback receiving the `result` of the promise
+    fn promise_result_ctx(ctx: &mut VMContextBuilder, result: PromiseResult) {
+        testing_env!(
+            ctx.predecessor_account_id(env::current_account_id())
+                .build(),
+            near_sdk::VMConfig::test(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+    }
+
+    /// sets the context of a callback of a failed promise
+    fn failed_promise_ctx(ctx: &mut VMContextBuilder) {
+        promise_result_ctx(ctx, PromiseResult::Failed);
+    }
+
+    /// returns the `receiver_id` of the `nft_transfer` receipt of `token_id` sent to
+    /// `nft_contract`
+    fn nft_transfer_receiver(nft_contract: &AccountId, token_id: &str) -> String {
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let transfer = receipts
+            .iter()
+            .find(|r| r.receiver_id == *nft_contract)
+            .expect("nft_transfer receipt not created");
+        match &transfer.actions[0] {
+            near_sdk::mock::VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } => {
+                assert_eq!(function_name, "nft_transfer");
+                let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                assert_eq!(args["token_id"], token_id);
+                args["receiver_id"].as_str().unwrap().to_string()
+            }
+            _ => panic!("expected a function call"),
+        }
+    }
+
+    /// returns the `amount` of the `ft_transfer` receipt sent to `ft_contract`
+    fn ft_transfer_amount(ft_contract: &AccountId, receiver: &AccountId) -> u128 {
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        let transfer = receipts
+            .iter()
+            .find(|r| r.receiver_id == *ft_contract)
+            .expect("ft_transfer receipt not created");
+        match &transfer.actions[0] {
+            near_sdk::mock::VmAction::FunctionCall {
+                function_name,
+                args,
+                ..
+            } => {
+                assert_eq!(function_name, "ft_transfer");
+                let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                assert_eq!(args["receiver_id"], receiver.to_string());
+                args["amount"].as_str().unwrap().parse().unwrap()
+            }
+            _ => panic!("expected a function call"),
+        }
+    }
+
+    #[test]
+    fn test_recompute_total_cheddar_stake() {
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        let users = [acc_u1(), acc_u2(), accounts(3)];
+        for (i, user) in users.iter().enumerate() {
+            register_user_and_stake(&mut ctx, &mut ctr, user, &nft_1, i.to_string(), -2);
+        }
+        let total = ctr.total_cheddar_stake;
+        assert_eq!(total, 3 * CHEDDAR_RATE);
+        ctr.total_cheddar_stake += 7;
+
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_active(false);
+        assert_eq!(ctr.recompute_total_cheddar_stake(0, 2).0, 2 * CHEDDAR_RATE);
+        assert_eq!(
+            ctr.total_cheddar_stake,
+            total + 7,
+            "set only on the last page"
+        );
+        assert_eq!(ctr.recompute_total_cheddar_stake(2, 2).0, total);
+        assert_eq!(ctr.total_cheddar_stake, total);
+    }
+
+    #[test]
+    #[should_panic(expected = "recompute must continue from index 2")]
+    fn test_recompute_total_cheddar_stake_skipped_page() {
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        let users = [acc_u1(), acc_u2(), accounts(3)];
+        for (i, user) in users.iter().enumerate() {
+            register_user_and_stake(&mut ctx, &mut ctr, user, &nft_1, i.to_string(), -2);
+        }
+        testing_env!(ctx.predecessor_account_id(acc_owner()).build());
+        ctr.set_active(false);
+        ctr.recompute_total_cheddar_stake(0, 2);
+        ctr.recompute_total_cheddar_stake(1, 2);
+    }
+
+    #[test]
+    fn test_owner_harvest_all() {
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        let users = [acc_u1(), acc_u2(), accounts(3)];
+        for (i, user) in users.iter().enumerate() {
+            register_user_and_stake(&mut ctx, &mut ctr, user, &nft_1, i.to_string(), -2);
+        }
+        assert_eq!(ctr.accounts.len(), 3);
+
+        testing_env!(ctx
+            .predecessor_account_id(acc_owner())
+            .block_timestamp(round(END))
+            .build());
+        // bounded sweep harvests only the first two accounts
+        assert_eq!(ctr.owner_harvest_all(0, 2), 2);
+        assert_eq!(ctr.get_vault(&users[0]).farmed, 0);
+        assert_eq!(ctr.get_vault(&users[1]).farmed, 0);
+        assert!(ctr.status(users[2].clone()).unwrap().farmed_units.0 > 0);
+
+        assert_eq!(ctr.owner_harvest_all(2, 10), 1);
+        assert_eq!(ctr.get_vault(&users[2]).farmed, 0);
+        // nothing left to harvest
+        assert_eq!(ctr.owner_harvest_all(0, 10), 0);
+        assert_eq!(ctr.owner_harvest_all(10, 10), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_owner_harvest_all_not_owner() {
+        let (_, mut ctr) = setup_contract(acc_u1(), 0, None, None, RATE, END);
+        ctr.owner_harvest_all(0, 10);
+    }
+
+    #[test]
+    fn test_withdraw_crop_to() {
+        let user_1 = acc_u1();
+        let receiver = acc_u2();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(3))
+            .build());
+        let farmed = ctr.status(user_1.clone()).unwrap().farmed_tokens[0].0;
+        assert!(farmed > 0);
+        ctr.withdraw_crop_to(receiver.clone());
+        assert_eq!(ctr.get_vault(&user_1).farmed, 0);
+        assert_eq!(ft_transfer_amount(&acc_cheddar(), &receiver), farmed);
+
+        // failed transfer is recovered to the caller vault
+        failed_promise_ctx(&mut ctx);
+        ctr.transfer_farmed_callback(user_1.clone(), 0, farmed.into());
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered[0], farmed);
+        assert!(ctr.vaults.get(&receiver).is_none());
+    }
+
+    #[test]
+    fn test_claim_on_behalf() {
+        let user_1 = acc_u1();
+        let caller = acc_u2();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(
+            acc_owner(),
+            0,
+            Some(vec![nft_1.clone()]),
+            Some(vec![E24]),
+            RATE,
+            END,
+        );
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx
+            .predecessor_account_id(caller.clone())
+            .block_timestamp(round(3))
+            .build());
+        let farmed = ctr.status(user_1.clone()).unwrap().farmed_tokens[0].0;
+        assert!(farmed > 0);
+        ctr.claim_on_behalf(user_1.clone());
+        assert_eq!(ctr.get_vault(&user_1).farmed, 0);
+        assert_eq!(ft_transfer_amount(&acc_cheddar(), &user_1), farmed);
+
+        // failed transfer is recovered to the staker vault, not to the caller
+        failed_promise_ctx(&mut ctx);
+        ctr.transfer_farmed_callback(user_1.clone(), 0, farmed.into());
+        assert_eq!(ctr.get_vault(&user_1).farmed_recovered[0], farmed);
+        assert!(ctr.vaults.get(&caller).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "nothing to harvest")]
+    fn test_claim_on_behalf_nothing_farmed() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+
+        testing_env!(ctx.predecessor_account_id(acc_u2()).build());
+        ctr.claim_on_behalf(user_1);
+    }
+
+    #[test]
+    fn test_estimate_harvest_gas() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        let per_token = GAS_FOR_FT_TRANSFER.0 + GAS_FOR_CALLBACK.0;
+        assert_eq!(ctr.farm_tokens.len(), 2);
+        let two_tokens = ctr.estimate_harvest_gas();
+        assert_eq!(two_tokens, GAS_FOR_HARVEST_BASE.0 + 2 * per_token);
+
+        ctr.farm_tokens.push("farming_token2".parse().unwrap());
+        assert_eq!(ctr.estimate_harvest_gas(), two_tokens + per_token);
+        ctr.farm_tokens.truncate(1);
+        assert_eq!(ctr.estimate_harvest_gas(), two_tokens - per_token);
+    }
+
+    #[test]
+    fn test_unstake_fee_cheddar() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let fee = E24;
+
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+        ctr.set_unstake_fee_cheddar(fee.into());
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+        assert_eq!(ctr.total_cheddar_stake, 2 * CHEDDAR_RATE);
+
+        // unstaking one of the NFTs charges the fee from the staked Cheddar
+        unstake(&mut ctx, &mut ctr, &user_1, &nft_1, "2".into());
+        assert_eq!(ctr.fee_collected, fee);
+        assert_eq!(
+            ctr.get_vault(&user_1).cheddar_staked,
+            2 * CHEDDAR_RATE - fee
+        );
+        assert_eq!(ctr.total_cheddar_stake, 2 * CHEDDAR_RATE - fee);
+
+        // close charges the fee again, the user gets the rest of the staked Cheddar
+        close(&mut ctx, &mut ctr, &user_1);
+        assert_eq!(ctr.fee_collected, 2 * fee);
+        assert_eq!(ctr.total_cheddar_stake, 0);
+        assert_eq!(
+            ft_transfer_amount(&acc_cheddar(), &user_1),
+            2 * CHEDDAR_RATE - 2 * fee
+        );
+
+        // collected fee goes to the treasury
+        testing_env!(ctx.predecessor_account_id(acc_u2()).build());
+        ctr.withdraw_fees();
+        assert_eq!(ctr.fee_collected, 0);
+        assert_eq!(ft_transfer_amount(&acc_cheddar(), &accounts(1)), 2 * fee);
+
+        // failed transfer recovers the collected fee
+        failed_promise_ctx(&mut ctx);
+        ctr.withdraw_fees_callback((2 * fee).into());
+        assert_eq!(ctr.fee_collected, 2 * fee);
+    }
+
+    #[test]
+    #[should_panic(expected = "unstake fee can't be bigger than cheddar_rate")]
+    fn test_unstake_fee_cheddar_too_big() {
+        let (_, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        ctr.set_unstake_fee_cheddar((CHEDDAR_RATE + 1).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "can only be called by the owner")]
+    fn test_unstake_fee_cheddar_not_owner() {
+        let (_, mut ctr) = setup_contract(acc_u1(), 0, None, None, RATE, END);
+        ctr.set_unstake_fee_cheddar(E24.into());
+    }
+
+    #[test]
+    fn test_close_receipt() {
+        let user_1 = acc_u1();
+        let nft_1 = acc_staking1();
+        let nft_2 = acc_staking2();
+
+        let (mut ctx, mut ctr) = setup_contract(acc_owner(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        register_user_and_stake(&mut ctx, &mut ctr, &user_1, &nft_1, "1".into(), -2);
+        deposit_cheddar(&mut ctx, &mut ctr, &user_1);
+        stake(&mut ctx, &mut ctr, &user_1, &nft_2, "2".into());
+        testing_env!(ctx.predecessor_account_id(acc_nft_boost()).build());
+        ctr.nft_on_transfer(
+            user_1.clone(),
+            user_1.clone(),
+            "boost".into(),
+            "to boost".into(),
+        );
+
+        testing_env!(ctx
+            .attached_deposit(1)
+            .predecessor_account_id(user_1.clone())
+            .block_timestamp(round(2))
+            .build());
+        let receipt = ctr.close();
+        assert_eq!(
+            receipt,
+            P4CloseReceipt {
+                nft_transfers: 2,
+                farm_token_transfers: 2,
+                cheddar_transfers: 1,
+                boost_transfers: 1,
+                vault_removed: true,
+            }
+        );
+        assert!(ctr.status(user_1.clone()).is_none());
+    }
+
+    #[test]
+    fn test_close_receipt_empty_vault() {
+        let user_1 = acc_u1();
+        let (mut ctx, mut ctr) = setup_contract(user_1.clone(), 0, None, None, RATE, END);
+        finalize(&mut ctr, vec![20 * E24, 10 * E24]);
+
+        testing_env!(ctx.attached_deposit(STORAGE_COST).build());
+        ctr.storage_deposit(None, None);
+        testing_env!(ctx.attached_deposit(1).build());
+        let receipt = ctr.close();
+        assert_eq!(receipt.nft_transfers + receipt.farm_token_transfers, 0);
+        assert!(receipt.vault_removed);
+    }
 }
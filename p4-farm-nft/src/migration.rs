@@ -0,0 +1,237 @@
+//! State migration from the contract deployed before the vault layout changes.
+//! The boost NFT was stored as `boost_contract_index@token_id`, it's now stored as
+//! `nft_contract@token_id` (an empty string stays empty: no boost NFT).
+//!
+//! Deploy procedure:
+//! 1. deploy the new code and call `migrate` (in the same batch transaction). The contract
+//!    is paused: rounds and the emission are frozen, as with `set_active(false)`.
+//! 2. call `migrate_vaults` with pages of the registered accounts (eg from an indexer),
+//!    until `get_vaults_to_migrate` returns 0.
+//! 3. call `set_active(true)`. It panics while some vaults are not migrated.
+use crate::*;
+
+/// `Vault` layout of the previous contract version.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct LegacyVault {
+    pub reward_acc: Balance,
+    pub staked: Vec<TokenIds>,
+    pub min_stake: Balance,
+    pub farmed: Balance,
+    pub farmed_recovered: Vec<Balance>,
+    /// stored as `boost_contract_index@token_id`
+    pub boost_nft: String,
+    pub cheddar_staked: Balance,
+}
+
+/// `Contract` layout of the previous contract version.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct LegacyContract {
+    pub is_active: bool,
+    pub setup_finalized: bool,
+    pub owner_id: AccountId,
+    pub treasury: AccountId,
+    pub vaults: LookupMap<AccountId, LegacyVault>,
+    pub stake_nft_tokens: Vec<NftContractId>,
+    pub staked_units: u128,
+    pub stake_rates: Vec<u128>,
+    pub farm_tokens: Vec<AccountId>,
+    pub farm_token_rates: Vec<u128>,
+    pub farm_unit_emission: u128,
+    pub farm_deposits: Vec<u128>,
+    pub farming_start: u64,
+    pub farming_end: u64,
+    pub boost_nft_contracts: Vec<NftContractId>,
+    pub cheddy: NftContractId,
+    pub total_boost: Vec<Balance>,
+    pub nft_boost: u32,
+    pub cheddy_boost: u32,
+    pub total_harvested: Vec<Balance>,
+    pub reward_acc: u128,
+    pub reward_acc_round: u64,
+    pub total_stake: Vec<Balance>,
+    pub total_cheddar_stake: Balance,
+    pub accounts_registered: u64,
+    pub cheddar_rate: Balance,
+    pub cheddar: AccountId,
+}
+
+impl LegacyVault {
+    /// Converts the vault to the current layout. `boost_nft_contracts` resolves the boost
+    /// NFT contract index, the staked NFTs get the default weight.
+    pub(crate) fn into_vault(self, boost_nft_contracts: &[NftContractId], round: u64) -> Vault {
+        let boost_nft = if self.boost_nft.is_empty() {
+            self.boost_nft
+        } else {
+            let (idx, token_id) = self
+                .boost_nft
+                .split_once(NFT_DELIMETER)
+                .expect("expected 'contract_index@token_id' boost NFT");
+            let idx: usize = idx.parse().expect("invalid boost NFT contract index");
+            format!("{}{}{}", boost_nft_contracts[idx], NFT_DELIMETER, token_id)
+        };
+        let farm_len = self.farmed_recovered.len();
+        Vault {
+            reward_acc: self.reward_acc,
+            staked_weights: self
+                .staked
+                .iter()
+                .map(|ids| vec![DEFAULT_NFT_WEIGHT; ids.len()])
+                .collect(),
+            staked: self.staked,
+            min_stake: self.min_stake,
+            farmed: self.farmed,
+            farmed_recovered: self.farmed_recovered,
+            farmed_transfer_failures: vec![0; farm_len],
+            boost_nft,
+            boost_nft_recovered: Vec::new(),
+            staked_nft_recovered: Vec::new(),
+            cheddar_staked: self.cheddar_staked,
+            staked_since_round: round,
+            last_action_round: 0,
+            boost_staked_round: 0,
+            stake_removals_applied: 0,
+            storage_deposit: 0,
+            storage_usage: 0,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Migrates the contract state from the previous version and pauses the contract.
+    /// Vaults are migrated separately with `migrate_vaults`.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: LegacyContract = env::state_read().expect("no contract state to migrate");
+        let farm_len = old.farm_tokens.len();
+        let mut c = Self {
+            is_active: true,
+            setup_finalized: old.setup_finalized,
+            owner_id: old.owner_id,
+            pending_owner: None,
+            treasury: old.treasury,
+            vaults: LookupMap::new(b"v".to_vec()),
+            accounts: UnorderedSet::new(b"a".to_vec()),
+            stake_nft_tokens: old.stake_nft_tokens,
+            staked_units: old.staked_units,
+            stake_rates: old.stake_rates,
+            farm_tokens: old.farm_tokens,
+            farm_token_rates: old.farm_token_rates,
+            farm_unit_emission: old.farm_unit_emission,
+            farm_deposits: old.farm_deposits,
+            farming_start: old.farming_start,
+            farming_end: old.farming_end,
+            round_seconds: ROUND,
+            boost_nft_contracts: old.boost_nft_contracts,
+            cheddy: old.cheddy,
+            total_boost: old.total_boost,
+            nft_boost: old.nft_boost,
+            cheddy_boost: old.cheddy_boost,
+            total_harvested: old.total_harvested,
+            reward_acc: old.reward_acc,
+            reward_acc_round: old.reward_acc_round,
+            reward_acc_offset: 0,
+            pending_stake_transfers: vec![0; old.total_stake.len()],
+            total_stake: old.total_stake,
+            total_cheddar_stake: old.total_cheddar_stake,
+            accounts_registered: old.accounts_registered,
+            cheddar_rate: old.cheddar_rate,
+            cheddar: old.cheddar,
+            token_weights: LookupMap::new(b"w".to_vec()),
+            loyalty_boost_bp_per_round: 0,
+            loyalty_boost_cap_bp: 0,
+            boost_lock_rounds: 0,
+            unstake_cooldown_rounds: 0,
+            stake_nft_removals: Vec::new(),
+            unstake_fee_cheddar: 0,
+            fee_collected: 0,
+            max_staked_per_account: NFT_UNITS_MAX_TRANSFER_NUM as u64,
+            reward_multiplier_bp: BASIS_P as u32,
+            reward_multiplier_start: 0,
+            reward_multiplier_end: 0,
+            cheddar_stake_recompute: (0, 0),
+            min_harvest_amount: vec![0; farm_len],
+            relayers: UnorderedSet::new(b"r".to_vec()),
+            paused_at_round: None,
+            paused_at: 0,
+            pro_rata_accrual: false,
+        };
+        // paused until all vaults are migrated, same as `set_active(false)`
+        let round = c.current_round();
+        c.update_reward_acc(round, 0);
+        c.is_active = false;
+        c.paused_at_round = Some(round);
+        c.paused_at = env::block_timestamp() / SECOND;
+        c
+    }
+
+    /// Migrates the vaults of `account_ids` to the current layout. Accounts already migrated
+    /// (or not registered) are skipped. The vault is credited with a storage deposit covering
+    /// its storage: the previous version charged a fixed `STORAGE_COST` and paid the rest.
+    /// Returns the number of migrated vaults.
+    pub fn migrate_vaults(&mut self, account_ids: Vec<AccountId>) -> u64 {
+        self.assert_owner();
+        let mut legacy: LookupMap<AccountId, LegacyVault> = LookupMap::new(b"v".to_vec());
+        let round = self.current_round();
+        let mut migrated = 0;
+        for a in account_ids {
+            if self.accounts.contains(&a) {
+                continue;
+            }
+            let old = match legacy.remove(&a) {
+                Some(old) => old,
+                None => continue,
+            };
+            let initial_storage = env::storage_usage();
+            let mut v = old.into_vault(&self.boost_nft_contracts, round);
+            self.vaults.insert(&a, &v);
+            self.accounts.insert(&a);
+            // the storage fields have a fixed size: saving them doesn't change the usage
+            v.storage_usage = env::storage_usage() - initial_storage;
+            v.storage_deposit = std::cmp::max(STORAGE_COST, storage_cost(&v));
+            self.vaults.insert(&a, &v);
+            migrated += 1;
+        }
+        log!("Migrated {} vaults", migrated);
+        migrated
+    }
+
+    /// Returns the number of registered accounts which vaults are not migrated yet.
+    pub fn get_vaults_to_migrate(&self) -> u64 {
+        self.accounts_registered - self.accounts.len()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::base64;
+
+    use super::*;
+
+    /// `mepen.near` vault, as stored on mainnet before the migration: 6 staked NFTs,
+    /// 3330 Cheddar staked and no boost NFT.
+    const MAINNET_VAULT: &str = "gPACAAAAAAAAAAAAAAAAAAEAAAAGAAAABQAAADE6MjE3BAAAADE6OTEFAAAAMToyMjMEAAAAMTo2NwUAAAAxOjE5MgQAAAAxOjg4AAAAxpHN2KaM9gQAAAAAAAAAAAAAAAAAAAAAAAAAAAABAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABCCKsFuO2CwgoAAAAA";
+
+    #[test]
+    fn test_into_vault_mainnet_snapshot() {
+        let bytes = base64::decode(MAINNET_VAULT).unwrap();
+        let old = LegacyVault::try_from_slice(&bytes).unwrap();
+        let reward_acc = old.reward_acc;
+        let v = old.into_vault(&[], 7);
+
+        assert_eq!(v.reward_acc, reward_acc);
+        assert_eq!(v.staked.len(), 1);
+        assert_eq!(v.staked[0].len(), 6);
+        assert_eq!(v.staked[0][0], "1:217");
+        assert_eq!(v.staked_weights, vec![vec![DEFAULT_NFT_WEIGHT; 6]]);
+        assert_eq!(v.min_stake, 6 * E24);
+        assert_eq!(v.farmed_recovered, vec![0]);
+        assert_eq!(v.farmed_transfer_failures, vec![0]);
+        assert!(v.boost_nft.is_empty());
+        assert!(v.boost_nft_recovered.is_empty());
+        assert_eq!(v.cheddar_staked, 3330 * E24);
+        assert_eq!(v.staked_since_round, 7);
+        assert_eq!(v.storage_deposit, 0);
+    }
+}
@@ -5,8 +5,9 @@ use near_contract_standards::storage_management::{
 
 #[near_bindgen]
 impl StorageManagement for Contract {
-    /// Registers a new account
-    #[allow(unused_variables)]
+    /// Registers a new account or tops up the storage deposit of a registered account.
+    /// A new account requires at least `STORAGE_COST`. With `registration_only` the deposit
+    /// above `STORAGE_COST` is refunded.
     #[payable]
     fn storage_deposit(
         &mut self,
@@ -18,32 +19,59 @@ impl StorageManagement for Contract {
             .map(|a| a.into())
             .unwrap_or_else(|| env::predecessor_account_id());
 
-        if self.vaults.contains_key(&account_id) {
-            log!("The account is already registered, refunding the deposit");
-            if amount > 0 {
-                Promise::new(env::predecessor_account_id()).transfer(amount);
+        if let Some(mut vault) = self.vaults.get(&account_id) {
+            if registration_only == Some(true) {
+                log!("The account is already registered, refunding the deposit");
+                if amount > 0 {
+                    Promise::new(env::predecessor_account_id()).transfer(amount);
+                }
+            } else {
+                vault.storage_deposit =
+                    add_or_panic(vault.storage_deposit, amount, "storage deposit");
+                self.vaults.insert(&account_id, &vault);
             }
-        } else {
-            assert!(
-                amount >= STORAGE_COST,
-                "The attached deposit is less than the minimum storage balance ({})",
-                STORAGE_COST
-            );
-            self.create_account(&account_id);
+            return storage_balance(&vault);
+        }
 
+        assert!(
+            amount >= STORAGE_COST,
+            "The attached deposit is less than the minimum storage balance ({})",
+            STORAGE_COST
+        );
+        let deposit = if registration_only == Some(true) {
             let refund = amount - STORAGE_COST;
             if refund > 0 {
                 Promise::new(env::predecessor_account_id()).transfer(refund);
             }
-        }
-        storage_balance()
+            STORAGE_COST
+        } else {
+            amount
+        };
+        self.create_account(&account_id, deposit);
+        storage_balance(&self.vaults.get(&account_id).unwrap())
     }
 
-    /// Method not supported. Close the account (`close()` or
-    /// `storage_unregister(true)`) to close the account and withdraw deposited NEAR.
-    #[allow(unused_variables)]
+    /// Withdraws the storage deposit not used by the account (`available`). Withdraws all
+    /// available deposit when `amount` is not specified.
+    /// Close the account (`close()` or `storage_unregister(true)`) to withdraw the rest.
+    #[payable]
     fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
-        panic!("Storage withdraw not possible, close the account instead");
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut vault = self.vaults.get(&account_id).expect(ERR10_NO_ACCOUNT);
+        let available = storage_available(&vault);
+        let amount = amount.map(|a| a.0).unwrap_or(available);
+        assert!(
+            amount <= available,
+            "The amount is greater than the available storage balance ({})",
+            available
+        );
+        if amount > 0 {
+            vault.storage_deposit -= amount;
+            self.vaults.insert(&account_id, &vault);
+            Promise::new(account_id).transfer(amount);
+        }
+        storage_balance(&vault)
     }
 
     /// When force == true it will close the account. Otherwise this is noop.
@@ -57,28 +85,64 @@ impl StorageManagement for Contract {
         false
     }
 
-    /// Mix and min balance is always MIN_BALANCE.
+    /// Min balance is `STORAGE_COST`. Accounts staking many NFTs need more.
     fn storage_balance_bounds(&self) -> StorageBalanceBounds {
         StorageBalanceBounds {
             min: STORAGE_COST.into(),
-            max: Some(STORAGE_COST.into()),
+            max: None,
         }
     }
 
-    /// If the account is registered the total and available balance is always MIN_BALANCE.
-    /// Otherwise None.
+    /// If the account is registered returns its storage deposit and the part of it not used
+    /// by the account. Otherwise None.
     fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
         let account_id: AccountId = account_id.into();
-        if self.vaults.contains_key(&account_id) {
-            return Some(storage_balance());
+        self.vaults.get(&account_id).map(|v| storage_balance(&v))
+    }
+}
+
+impl Contract {
+    /// Updates the vault storage usage with the storage used (or released) since
+    /// `initial_storage` and saves the vault.
+    /// Panics if the vault storage deposit doesn't cover the storage usage.
+    pub(crate) fn update_storage_usage(
+        &mut self,
+        user: &AccountId,
+        vault: &mut Vault,
+        initial_storage: StorageUsage,
+    ) {
+        let storage = env::storage_usage();
+        if storage >= initial_storage {
+            vault.storage_usage += storage - initial_storage;
+        } else {
+            vault.storage_usage = vault
+                .storage_usage
+                .saturating_sub(initial_storage - storage);
         }
-        None
+        let required = storage_cost(vault);
+        assert!(
+            vault.storage_deposit >= required,
+            "Not enough storage deposit, {} yoctoNEAR more is required. Use storage_deposit to top it up",
+            required - vault.storage_deposit
+        );
+        self.vaults.insert(user, vault);
     }
 }
 
-fn storage_balance() -> StorageBalance {
+/// Cost of the storage used by the vault.
+pub(crate) fn storage_cost(vault: &Vault) -> Balance {
+    Balance::from(vault.storage_usage) * env::storage_byte_cost()
+}
+
+/// Storage deposit which can be withdrawn: not used by the vault and above `STORAGE_COST`.
+fn storage_available(vault: &Vault) -> Balance {
+    let locked = std::cmp::max(storage_cost(vault), STORAGE_COST);
+    vault.storage_deposit.saturating_sub(locked)
+}
+
+fn storage_balance(vault: &Vault) -> StorageBalance {
     StorageBalance {
-        total: STORAGE_COST.into(),
-        available: U128::from(0),
+        total: vault.storage_deposit.into(),
+        available: storage_available(vault).into(),
     }
 }
@@ -24,8 +24,28 @@ impl From<String> for TransferInstruction {
     }
 } 
 
+/// `ft_on_transfer` message options
+enum FtTransferInstruction {
+    CheddarStake,
+    SetupDeposit,
+    RewardTopup,
+    Unknown
+}
+
+impl From<String> for FtTransferInstruction {
+    fn from(msg: String) -> Self {
+        match &msg[..] {
+            "cheddar stake" => FtTransferInstruction::CheddarStake,
+            "setup deposit" | "setup reward deposit" => FtTransferInstruction::SetupDeposit,
+            "reward topup" => FtTransferInstruction::RewardTopup,
+            _ => FtTransferInstruction::Unknown
+        }
+    }
+}
+
 /// NFT Receiver
-/// Used when an NFT is transferred using `nft_transfer_call`.
+/// Used when an NFT is transferred using `nft_transfer_call`, either by the owner or by an
+/// approved operator. The NFT is credited to `previous_owner_id` (the real owner).
 /// This function is considered safe and will work when contract is paused to allow user
 /// to accumulate bonuses.
 /// Message from transfer switch options:
@@ -46,10 +66,17 @@ impl NonFungibleTokenReceiver for Contract {
             nft_contract_id, env::signer_account_id(),
             "ERR_NOT_CROSS_CONTRACT_CALL"
         );
-        assert_eq!(
-            previous_owner_id, env::signer_account_id(),
+        // `sender_id` differs from `previous_owner_id` when the transfer is made by an
+        // approved operator (eg a marketplace). The NFT contract already checked the approval,
+        // and the token is always credited to the owner.
+        let signer_id = env::signer_account_id();
+        assert!(
+            previous_owner_id == signer_id || sender_id == signer_id,
             "ERR_OWNER_NOT_SIGNER"
         );
+        if sender_id != previous_owner_id {
+            log!("@{} transferred NFT {} on behalf of @{}", sender_id, token_id, previous_owner_id);
+        }
         
         match TransferInstruction::from(msg) {
             // "to boost" message for transfer P4 boost
@@ -79,10 +106,13 @@ impl NonFungibleTokenReceiver for Contract {
 impl FungibleTokenReceiver for Contract {
     /**
     FungibleTokenReceiver implementation Callback on receiving tokens by this contract.
-    Handles both farm deposits and stake deposits. For farm deposit (sending tokens
-    to setup the farm) you must set "setup reward deposit" msg.
-    Otherwise tokens will be staken.
-    Returns zero.
+    The `msg` selects the scenario:
+    - "cheddar stake": Cheddar deposit required to stake NFTs
+    - "setup deposit" (or "setup reward deposit"): farm deposit to setup the farm
+    - "reward topup": farm deposit after the setup is finalized, to extend the farm runway
+      with `set_start_end`
+    Returns the amount to refund: the Cheddar above `cheddar_rate` for "cheddar stake", zero
+    for farm deposits. Transfers with an unknown msg are refunded.
     Panics when:
     - account is not registered
     - or receiving a wrong token
@@ -98,14 +128,14 @@ impl FungibleTokenReceiver for Contract {
         let ft_token_id = env::predecessor_account_id();
 
         assert!(amount.0 > 0, "deposited amount must be positive");
-        // deposit rewards
-        if msg == "setup reward deposit" {
-            self._setup_deposit(&ft_token_id, amount.0);
-        } else {
-            // cheddar staking
-            if msg == "cheddar stake" {
-                self.stake_cheddar(&sender_id, amount.0);
-            } else {
+        match FtTransferInstruction::from(msg) {
+            FtTransferInstruction::CheddarStake => {
+                let refund = self.stake_cheddar(&sender_id, amount.0);
+                return PromiseOrValue::Value(U128(refund))
+            }
+            FtTransferInstruction::SetupDeposit => self._setup_deposit(&ft_token_id, amount.0),
+            FtTransferInstruction::RewardTopup => self._reward_topup(&ft_token_id, amount.0),
+            FtTransferInstruction::Unknown => {
                 log!(
                     "Contract accept only NFT farming and staking! 
                      If you need to deposit Cheddar to be able for stake NFT, use 'cheddar stake' as msg.
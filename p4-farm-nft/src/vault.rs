@@ -10,6 +10,9 @@ pub struct Vault {
     /// index - contract id
     /// value - token ids - []
     pub staked: Vec<TokenIds>,
+    /// Rarity weights of the staked NFTs, recorded at stake time.
+    /// Same layout as `staked`: `staked_weights[i][j]` is the weight of `staked[i][j]`.
+    pub staked_weights: Vec<Vec<Balance>>,
     pub min_stake: Balance,
     /// Amount of accumulated, not withdrawn farmed units. When withdrawing the
     /// farmed units are translated to all `Contract.farm_tokens` based on
@@ -17,13 +20,33 @@ pub struct Vault {
     pub farmed: Balance,
     /// farmed tokens which failed to withdraw to the user.
     pub farmed_recovered: Vec<Balance>,
+    /// number of consecutive failed transfers per farm token. After
+    /// `MAX_FARMED_TRANSFER_FAILURES` the token is reported as stuck.
+    pub farmed_transfer_failures: Vec<u32>,
     /// NFTs deposited to get an extra boost. Only one NFT can be deposited to a
     /// single acocunt.
     /// Storing like `nft_contract@token_id`
     pub boost_nft: ContractNftTokenId,
+    /// boost NFTs which failed to return to the user while another boost NFT was deposited.
+    pub boost_nft_recovered: Vec<ContractNftTokenId>,
+    /// staked NFTs which failed to return to the user after their stake NFT contract was
+    /// removed, as `(nft_contract_id, token_id)`. See `withdraw_staked_nft_recovered`.
+    pub staked_nft_recovered: Vec<(NftContractId, TokenId)>,
     /// Staked Cheddar. Equals to `Contract.cheddar_rate` * total_staked_tokens.
     /// not depends on which NFT contract staked more or less tokens, rate used as a const
     pub cheddar_staked: Balance,
+    /// Round when the number of staked NFTs last increased. Used to compute the loyalty boost.
+    pub staked_since_round: u64,
+    /// Round of the last NFT stake. Used to enforce `Contract.unstake_cooldown_rounds`.
+    pub last_action_round: u64,
+    /// Round when the current boost NFT was deposited. Used to enforce `Contract.boost_lock_rounds`.
+    pub boost_staked_round: u64,
+    /// Number of `Contract.stake_nft_removals` already applied to `staked`.
+    pub stake_removals_applied: u64,
+    /// NEAR deposited with `storage_deposit` to cover the account storage.
+    pub storage_deposit: Balance,
+    /// Storage (in bytes) used by the account, measured when registering and (un)staking.
+    pub storage_usage: StorageUsage,
 }
 
 impl Vault {
@@ -31,11 +54,21 @@ impl Vault {
         Self {
             reward_acc,
             staked: vec![TokenIds::new(); staked_len],
+            staked_weights: vec![Vec::new(); staked_len],
             min_stake: 0,
             farmed: 0,
             farmed_recovered: vec![0; farmed_len],
+            farmed_transfer_failures: vec![0; farmed_len],
             boost_nft: TokenId::new(),
+            boost_nft_recovered: Vec::new(),
+            staked_nft_recovered: Vec::new(),
             cheddar_staked: 0,
+            staked_since_round: 0,
+            last_action_round: 0,
+            boost_staked_round: 0,
+            stake_removals_applied: 0,
+            storage_deposit: 0,
+            storage_usage: 0,
         }
     }
 
@@ -43,14 +76,9 @@ impl Vault {
     Update rewards for locked tokens in past epochs
     Arguments:
      - `reward_acc`: Contract.reward_acc value
-     - `round`: current round
      */
-    pub fn ping(&mut self, reward_acc: Balance, round: u64) {
-        // note: the last round is at self.farming_end
-        // if farming didn't start, ignore the rewards update
-        if round == 0 {
-            return;
-        }
+    pub fn ping(&mut self, reward_acc: Balance) {
+        // note: the accumulator doesn't move before the farming starts
         // no new rewards
         if self.reward_acc >= reward_acc {
             return; // self.farmed;
@@ -64,7 +92,10 @@ impl Vault {
     pub fn is_empty(&self) -> bool {
         check_all_empty(&self.staked)
             && self.farmed == 0
+            && self.farmed_recovered.iter().all(|x| *x == 0)
             && self.boost_nft.is_empty()
+            && self.boost_nft_recovered.is_empty()
+            && self.staked_nft_recovered.is_empty()
             && self.cheddar_staked == 0
     }
     /// Returns amount of user NFT tokens staked (from all supported NFT contracts).
@@ -74,6 +105,29 @@ impl Vault {
             .map(|contract_tokens| contract_tokens.len())
             .sum()
     }
+    /// Aligns `staked` and `staked_weights` with the current `Contract.stake_nft_tokens`:
+    /// drops slots of NFT contracts removed since the vault was last updated and adds
+    /// empty slots for newly added NFT contracts.
+    pub fn sync_stake_layout(&mut self, removals: &[usize], stake_len: usize) {
+        for idx in removals.iter().skip(self.stake_removals_applied as usize) {
+            // slot may be missing if the contract was added after the vault was updated
+            if *idx < self.staked.len() {
+                self.staked.remove(*idx);
+                self.staked_weights.remove(*idx);
+            }
+        }
+        self.stake_removals_applied = removals.len() as u64;
+        self.staked.resize(stake_len, TokenIds::new());
+        self.staked_weights.resize(stake_len, Vec::new());
+    }
+
+    /// Returns the summed weight of staked NFTs for each NFT contract.
+    pub fn get_staked_weights(&self) -> Vec<Balance> {
+        self.staked_weights
+            .iter()
+            .map(|weights| weights.iter().sum())
+            .collect()
+    }
 }
 
 impl Contract {
@@ -81,64 +135,173 @@ impl Contract {
     /// Panics if the account is not registered.
     #[inline]
     pub(crate) fn get_vault(&self, account_id: &AccountId) -> Vault {
-        self.vaults.get(account_id).expect(ERR10_NO_ACCOUNT)
+        let mut vault = self.vaults.get(account_id).expect(ERR10_NO_ACCOUNT);
+        self.sync_vault(&mut vault);
+        vault
+    }
+
+    /// Lazily migrates vaults created before stake NFT contracts were added or removed.
+    pub(crate) fn sync_vault(&self, vault: &mut Vault) {
+        vault.sync_stake_layout(&self.stake_nft_removals, self.stake_nft_tokens.len());
     }
 
     pub(crate) fn ping_all(&mut self, vault: &mut Vault) {
-        let r = self.current_round();
-        self.update_reward_acc(r);
-        vault.ping(self.reward_acc, r);
+        self.update_reward_acc(self.current_round(), self.current_round_offset());
+        vault.ping(self.reward_acc);
+        // loyalty boost depends on the current round, so stake units must be refreshed
+        self._recompute_stake(vault);
     }
 
-    /// updates the rewards accumulator
-    pub(crate) fn update_reward_acc(&mut self, round: u64) {
-        let new_acc = self.compute_reward_acc(round);
+    /// updates the rewards accumulator up to `offset` seconds of the `round`.
+    pub(crate) fn update_reward_acc(&mut self, round: u64, offset: u64) {
+        let new_acc = self.compute_reward_acc(round, offset);
         // we should advance with rounds if self.t is zero, otherwise we have a jump and
         // don't compute properly the accumulator.
         if self.staked_units == 0 || new_acc != self.reward_acc {
             self.reward_acc = new_acc;
             self.reward_acc_round = round;
+            self.reward_acc_offset = offset;
         }
     }
 
-    /// computes the rewards accumulator.
+    /// computes the rewards accumulator up to `offset` seconds of the `round` (`offset` is
+    /// always 0 in the whole-round accrual).
     /// NOTE: the current, optimized algorithm will not farm anything if
     /// `self.rate * ACC_OVERFLOW / self.t < 1`
-    pub(crate) fn compute_reward_acc(&self, round: u64) -> u128 {
+    pub(crate) fn compute_reward_acc(&self, round: u64, offset: u64) -> u128 {
         // covers also when round == 0
-        if self.reward_acc_round == round || self.staked_units == 0 {
+        if (round, offset) <= (self.reward_acc_round, self.reward_acc_offset)
+            || self.staked_units == 0
+        {
             return self.reward_acc;
         }
 
-        self.reward_acc
-            + u128::from(round - self.reward_acc_round) * self.farm_unit_emission * ACC_OVERFLOW
-                / u128::from(self.staked_units)
+        // emitted_units >= partial units of the `reward_acc_round`, so it doesn't underflow
+        let emitted = self.emitted_units(self.reward_acc_round, round)
+            + self.partial_round_units(round, offset)
+            - self.partial_round_units(self.reward_acc_round, self.reward_acc_offset);
+        let farmed = emitted
+            .checked_mul(ACC_OVERFLOW)
+            .expect("Math overflow while computing the rewards accumulator")
+            / u128::from(self.staked_units);
+        add_or_panic(self.reward_acc, farmed, "rewards accumulator")
     }
 
-    pub(crate) fn _recompute_stake(&mut self, vault: &mut Vault) {
-        let mut s = min_stake(&vault.staked, &self.stake_rates);
+    /// Farm units emitted in the rounds `[from_round, to_round)`, with the reward multiplier
+    /// applied to the rounds in the multiplier window.
+    pub(crate) fn emitted_units(&self, from_round: u64, to_round: u64) -> u128 {
+        let promo_rounds = std::cmp::min(to_round, self.reward_multiplier_end)
+            .saturating_sub(std::cmp::max(from_round, self.reward_multiplier_start));
+        let regular_rounds = u128::from(to_round - from_round - promo_rounds);
+        let promo = u128::from(promo_rounds)
+            .checked_mul(self.farm_unit_emission)
+            .and_then(|x| x.checked_mul(u128::from(self.reward_multiplier_bp)))
+            .expect("Math overflow while computing the emission")
+            / BASIS_P;
+        add_or_panic(regular_rounds * self.farm_unit_emission, promo, "emission")
+    }
 
-        if !vault.boost_nft.is_empty() {
-            let (boost_contract, _) = extract_contract_token_ids(&vault.boost_nft);
-            let nft_boost_rate = if boost_contract == self.cheddy {
-                self.cheddy_boost
-            } else {
-                self.nft_boost
-            };
-            s += s * u128::from(nft_boost_rate) / BASIS_P;
+    /// Farm units emitted in the first `offset` seconds of the `round`.
+    fn partial_round_units(&self, round: u64, offset: u64) -> u128 {
+        if offset == 0 {
+            return 0;
+        }
+        self.emitted_units(round, round + 1)
+            .checked_mul(u128::from(offset))
+            .expect("Math overflow while computing the emission")
+            / u128::from(self.round_seconds)
+    }
+
+    /// Seconds elapsed in the current round when the pro-rata accrual is enabled. Zero in
+    /// the whole-round accrual, while the contract is paused and outside the farming period.
+    pub(crate) fn current_round_offset(&self) -> u64 {
+        if !self.pro_rata_accrual || self.paused_at_round.is_some() {
+            return 0;
+        }
+        let now = env::block_timestamp() / SECOND;
+        if now < self.farming_start || now >= self.farming_end {
+            return 0;
+        }
+        (now - self.farming_start) % self.round_seconds
+    }
+
+    /// Loyalty boost (in basis points) of the vault: grows with each round the staked NFTs
+    /// are held, up to `Contract.loyalty_boost_cap_bp`.
+    pub(crate) fn loyalty_boost(&self, vault: &Vault) -> u32 {
+        if self.loyalty_boost_bp_per_round == 0 {
+            return 0;
+        }
+        let rounds_held = self
+            .current_round()
+            .saturating_sub(vault.staked_since_round);
+        let boost = rounds_held.saturating_mul(u64::from(self.loyalty_boost_bp_per_round));
+        boost.min(u64::from(self.loyalty_boost_cap_bp)) as u32
+    }
+
+    /// Panics if the vault boost NFT is still locked. The lock is not enforced when the
+    /// contract is paused, so users can always withdraw their NFTs in an emergency.
+    pub(crate) fn assert_boost_unlocked(&self, vault: &Vault) {
+        if !self.is_active || vault.boost_nft.is_empty() {
+            return;
+        }
+        // the round number stops at the farm end, so the lock can't last longer
+        let unlock_round = std::cmp::min(
+            vault.boost_staked_round + self.boost_lock_rounds,
+            self.total_rounds(),
+        );
+        assert!(
+            self.current_round() >= unlock_round,
+            "boost NFT is locked until round {}",
+            unlock_round
+        );
+    }
+
+    /// Panics if the vault staked an NFT less than `unstake_cooldown_rounds` rounds ago.
+    /// There is no cooldown after the farm end: the round number doesn't advance anymore.
+    pub(crate) fn assert_unstake_cooldown(&self, vault: &Vault) {
+        if env::block_timestamp() / SECOND >= self.farming_end {
+            return;
         }
+        let unlock_round = vault.last_action_round + self.unstake_cooldown_rounds;
+        assert!(
+            self.current_round() >= unlock_round,
+            "can't unstake before round {}",
+            unlock_round
+        );
+    }
 
+    pub(crate) fn _recompute_stake(&mut self, vault: &mut Vault) {
+        let s = self.effective_stake(vault);
         if s > vault.min_stake {
             let diff = s - vault.min_stake;
-            self.staked_units += diff; // must be called after ping_s
+            // must be called after ping_s
+            self.staked_units = add_or_panic(self.staked_units, diff, "staked units");
             vault.min_stake = s;
         } else if s < vault.min_stake {
             let diff = vault.min_stake - s;
-            self.staked_units -= diff; // must be called after ping_s
+            // must be called after ping_s
+            self.staked_units = sub_or_panic(self.staked_units, diff, "staked units");
             vault.min_stake = s;
         }
     }
 
+    /// Stake units of the vault: the min stake of the staked NFTs, increased by the boost NFT
+    /// and the loyalty boost (at the current round).
+    pub(crate) fn effective_stake(&self, vault: &Vault) -> Balance {
+        let s = min_stake(&vault.get_staked_weights(), &self.stake_rates);
+
+        let mut boost_rate = self.loyalty_boost(vault);
+        if !vault.boost_nft.is_empty() {
+            let (boost_contract, _) = extract_contract_token_ids(&vault.boost_nft);
+            boost_rate += if boost_contract == self.cheddy {
+                self.cheddy_boost
+            } else {
+                self.nft_boost
+            };
+        }
+        s + s * u128::from(boost_rate) / BASIS_P
+    }
+
     /// Returns stake operation status.
     /// Stake works only for 1 NFT token coming at the moment.
     /// Revert transfer if nft_contract (`predecessor_account_id`) not in `Contract.stake_tokens`
@@ -154,11 +317,29 @@ impl Contract {
         token_id: TokenId,
     ) -> bool {
         // find index for staking token into Contract.stake_tokens
+        let initial_storage = env::storage_usage();
         let nft_ctr_idx = find_acc_idx(nft_contract_id, &self.stake_nft_tokens);
+        assert!(
+            self.stake_rates[nft_ctr_idx] > 0,
+            "staking {} NFTs is disabled",
+            nft_contract_id
+        );
         let mut vault = self.get_vault(&user);
+        // token ids are only unique within an NFT contract
+        assert!(
+            !vault.staked[nft_ctr_idx].contains(&token_id),
+            "token {} @{} is already staked",
+            token_id,
+            nft_contract_id
+        );
 
         // firstly check cheddar stake
         let total_staked_tokens = vault.get_number_of_staked_tokens();
+        assert!(
+            (total_staked_tokens as u64) < self.max_staked_per_account,
+            "You can stake at most {} NFTs",
+            self.max_staked_per_account
+        );
 
         // we expect for user who stake one more token have enough cheddar staked
         let expected = expected_cheddar_stake(total_staked_tokens, self.cheddar_rate);
@@ -168,19 +349,28 @@ impl Contract {
             expected - vault.cheddar_staked,
         );
 
+        // weight is fixed at stake time, so later weight changes don't alter existing stakes
+        let weight = self.token_weight(nft_contract_id, &token_id);
+
         // then update the past rewards
         self.ping_all(&mut vault);
         // after that add "token" to staked into vault
         vault.staked[nft_ctr_idx].push(token_id.clone());
+        vault.staked_weights[nft_ctr_idx].push(weight);
+        // more NFTs staked - loyalty boost starts over
+        vault.staked_since_round = self.current_round();
+        vault.last_action_round = vault.staked_since_round;
         // update total staked info about this token
         self.total_stake[nft_ctr_idx] += 1;
 
         self._recompute_stake(&mut vault);
         self.vaults.insert(user, &vault);
+        self.update_storage_usage(user, &mut vault, initial_storage);
         log!(
-            "Staked {}@{}, stake_units: {}",
+            "Staked {}@{} (weight: {}), stake_units: {}",
             nft_contract_id,
             token_id.clone(),
+            weight,
             vault.min_stake
         );
 
@@ -188,6 +378,8 @@ impl Contract {
     }
     /// Returns boost stake operation status.
     /// Stake works only for 1 NFT token coming at the moment.
+    /// If the account already has a boost NFT deposited, it's swapped: the old boost NFT is
+    /// returned to the user and the new one is used for the boost, without a round gap.
     /// Revert transfer if nft_contract (`predecessor_account_id`) not in `Contract.boost_nft_contracts`
     pub(crate) fn _boost_stake(
         &mut self,
@@ -197,28 +389,37 @@ impl Contract {
     ) -> bool {
         // find index for boost token into Contract.boost_nft_contracts
         // Option to refund if `nft_contract_i` not in required for stake NFT contracts
+        let initial_storage = env::storage_usage();
         let nft_ctr_idx = find_acc_idx(&nft_contract_id, &self.boost_nft_contracts);
         let mut vault = self.get_vault(&user);
 
-        if !vault.boost_nft.is_empty() {
-            log!("Account already has boost NFT deposited. You can only deposit one");
-            return false;
-        }
         let contract_token_id: ContractNftTokenId =
-            format!("{}{}{}", nft_ctr_idx, NFT_DELIMETER, token_id);
+            format!("{}{}{}", nft_contract_id, NFT_DELIMETER, token_id);
         log!(
             "Staking {} NFT - you will obtain a special farming boost",
             contract_token_id
         );
 
         self.ping_all(&mut vault);
+        if !vault.boost_nft.is_empty() {
+            self.assert_boost_unlocked(&vault);
+            log!(
+                "Swapping boost NFT {} for {}",
+                vault.boost_nft,
+                contract_token_id
+            );
+            // the old boost NFT is removed from `total_boost` by the transfer
+            self.transfer_boost_nft(user, user, vault.boost_nft.clone());
+        }
         vault.boost_nft = contract_token_id.clone();
+        vault.boost_staked_round = self.current_round();
 
         // update total staked info about this token
         self.total_boost[nft_ctr_idx] += 1;
 
         self._recompute_stake(&mut vault);
         self.vaults.insert(&user, &vault);
+        self.update_storage_usage(user, &mut vault, initial_storage);
         log!(
             "Added boost to user @{} with {}",
             user,
@@ -228,51 +429,99 @@ impl Contract {
     }
 
     /// Returns remaining amount of NFTs from `nft_contract_id` which user has staked after function call.    
+    /// Unstaking the last token closes the account, unless `keep_open` is set.
     /// Panics if `token_id` is not supported or not staked by a user.
     pub(crate) fn _nft_unstake(
         &mut self,
         user: &AccountId,
         nft_contract_id: &NftContractId,
         token_id: TokenId,
-    ) -> Vec<String> {
+        keep_open: bool,
+    ) -> UnstakeResult {
         // getting contract, token and user vault
         let nft_ctr_idx = find_acc_idx(nft_contract_id, &self.stake_nft_tokens);
         let mut vault = self.get_vault(user);
-        let token_idx = find_token_idx(&token_id, &vault.staked[nft_ctr_idx]);
+        // must be checked before any state change: the last token unstake closes the account
+        let token_idx = vault.staked[nft_ctr_idx]
+            .iter()
+            .position(|t| *t == token_id)
+            .expect("token not staked by this account");
+        self.assert_unstake_cooldown(&vault);
 
         // check if we are withdraw last staked token
         // todo - double check for total_stake and total_cheddar_staked
-        if vault.get_number_of_staked_tokens() == 1 {
+        if !keep_open && vault.get_number_of_staked_tokens() == 1 {
             log!("unstaked last staked token - closing account");
-            self.close();
-            return vec![];
+            // settle the rewards to report them, `_close` pings the vault again in the same round
+            self.ping_all(&mut vault);
+            self.vaults.insert(user, &vault);
+            let cheddar_refunded = vault
+                .cheddar_staked
+                .saturating_sub(self.unstake_fee_cheddar);
+            self._close(user.clone());
+            return UnstakeResult {
+                remaining_tokens: vec![],
+                farmed_units: vault.farmed.into(),
+                cheddar_refunded: cheddar_refunded.into(),
+                account_closed: true,
+            };
         }
 
+        let initial_storage = env::storage_usage();
         self.ping_all(&mut vault);
         // remove token from vault
         let removed_token_id = vault.staked[nft_ctr_idx].remove(token_idx);
+        let removed_weight = vault.staked_weights[nft_ctr_idx].remove(token_idx);
         let remaining_tokens = vault.staked[nft_ctr_idx].clone();
 
         self._recompute_stake(&mut vault);
 
         // staked cheddar keeps on vault
         // v.total_cheddar_staked -= self.cheddar_rate;
+        self.charge_unstake_fee(&mut vault);
         self.vaults.insert(user, &vault);
+        self.update_storage_usage(user, &mut vault, initial_storage);
 
-        self.transfer_staked_nft(user.clone(), nft_ctr_idx, removed_token_id);
+        self.transfer_staked_nft(user.clone(), nft_ctr_idx, removed_token_id, removed_weight);
 
         // staked cheddar keeps on vault
         // self.transfer_staked_cheddar(receiver_id.clone(), None);
 
-        return remaining_tokens;
+        UnstakeResult {
+            remaining_tokens,
+            farmed_units: vault.farmed.into(),
+            cheddar_refunded: U128(0),
+            account_closed: false,
+        }
     }
 
-    pub(crate) fn _withdraw_boost_nft(&mut self, user: &AccountId, vault: &mut Vault) {
+    pub(crate) fn _withdraw_boost_nft(
+        &mut self,
+        user: &AccountId,
+        receiver: &AccountId,
+        vault: &mut Vault,
+    ) {
         assert!(!vault.boost_nft.is_empty(), "Sender has no NFT deposit");
         self.ping_all(vault);
 
+        self.transfer_boost_nft(user, receiver, vault.boost_nft.clone());
+
+        vault.boost_nft = "".into();
+        self._recompute_stake(vault);
+        self.vaults.insert(&user, &vault);
+    }
+
+    /// Transfers a boost NFT of the user to the receiver.
+    /// The vault must be updated by the caller. The callback will recover the boost NFT
+    /// to the user vault if the transfer fails.
+    pub(crate) fn transfer_boost_nft(
+        &mut self,
+        user: &AccountId,
+        receiver: &AccountId,
+        contract_and_token_id: ContractNftTokenId,
+    ) {
         let (boost_nft_contract_id, boost_nft_token_id) =
-            extract_contract_token_ids(&vault.boost_nft);
+            extract_contract_token_ids(&contract_and_token_id);
         let nft_ctr_idx = find_acc_idx(&boost_nft_contract_id, &self.boost_nft_contracts);
 
         self.total_boost[nft_ctr_idx] -= 1;
@@ -281,7 +530,7 @@ impl Contract {
             .with_attached_deposit(ONE_YOCTO)
             .with_static_gas(GAS_FOR_NFT_TRANSFER)
             .nft_transfer(
-                user.clone(),
+                receiver.clone(),
                 boost_nft_token_id.clone(),
                 None,
                 Some("Boost withdraw".to_string()),
@@ -291,14 +540,11 @@ impl Contract {
                     .with_static_gas(GAS_FOR_CALLBACK)
                     .withdraw_boost_nft_callback(
                         user.clone(),
-                        vault.boost_nft.clone(),
+                        receiver.clone(),
+                        contract_and_token_id,
                         nft_ctr_idx,
                     ),
             );
-
-        vault.boost_nft = "".into();
-        self._recompute_stake(vault);
-        self.vaults.insert(&user, &vault);
     }
 }
 
@@ -307,7 +553,7 @@ mod tests {
 
     use near_sdk::{base64, borsh::BorshDeserialize, ONE_NEAR};
 
-    use crate::vault::Vault;
+    use crate::migration::LegacyVault;
 
     pub struct VaultStr {
         pub key: String,
@@ -337,7 +583,8 @@ VaultStr {key: "dkAAAAA5MzhjODNmY2Y5MDM1MGU0ZTVhZDdmMWE4MDJkM2RiNzQyNzE4ZTE5NGVm
             let key = base64::decode(v.key).unwrap();
             let key = String::from_utf8(key).unwrap();
             let val = base64::decode(v.val).unwrap();
-            let vault = Vault::try_from_slice(&val).unwrap();
+            // mainnet vaults are stored in the layout before the migration
+            let vault = LegacyVault::try_from_slice(&val).unwrap();
 
             println!("{:?}, {}", key, vault.cheddar_staked / ONE_NEAR);
         }